@@ -0,0 +1,146 @@
+use noria::consensus::Authority;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Number of ids an adapter reserves from the authority at a time.
+const BLOCK_SIZE: u64 = 1000;
+
+/// A range of ids reserved by this adapter that it has not handed out yet.
+struct Block {
+    next: u64,
+    end: u64,
+}
+
+/// Allocator for AUTO_INCREMENT ids, shared by all connections of an adapter.
+///
+/// Ids are reserved in blocks through the deployment's authority, so adapters attached to the
+/// same deployment never hand out the same id, and a restarted adapter never reissues one. Ids
+/// still unused when an adapter exits are skipped, much like MySQL does after a crash.
+///
+/// A table's counter is created past the largest id the table holds (see `seed`), and is moved
+/// past ids that clients insert explicitly. With an in-memory authority, the counters are lost
+/// when the adapter restarts, and are created again from the table's rows.
+pub struct AutoIncrements<A> {
+    authority: Arc<A>,
+    blocks: Mutex<HashMap<String, Block>>,
+}
+
+impl<A: Authority + 'static> AutoIncrements<A> {
+    pub fn new(authority: Arc<A>) -> Self {
+        AutoIncrements {
+            authority,
+            blocks: Mutex::default(),
+        }
+    }
+
+    /// Whether `table` has a counter yet, either in this adapter or in the authority.
+    pub(crate) async fn has_counter(&self, table: &str) -> Result<bool, failure::Error> {
+        if self.blocks.lock().unwrap().contains_key(table) {
+            return Ok(true);
+        }
+
+        let authority = self.authority.clone();
+        let path = Self::path(table);
+        let value = tokio::task::spawn_blocking(move || authority.try_read(&path)).await??;
+        Ok(value.is_some())
+    }
+
+    /// Create the counter for `table` so that it starts past `max`, the largest id the table
+    /// holds. A counter that already exists is only moved forward.
+    pub(crate) async fn seed(&self, table: &str, max: u64) -> Result<(), failure::Error> {
+        let authority = self.authority.clone();
+        let path = Self::path(table);
+
+        trace!(%table, max, "auto-increment::seed");
+        tokio::task::spawn_blocking(move || {
+            authority.read_modify_write(&path, |next: Option<u64>| -> Result<u64, ()> {
+                Ok(cmp::max(next.unwrap_or(1), max.saturating_add(1)))
+            })
+        })
+        .await??
+        .map_err(|()| format_err!("cannot create the id counter of '{}'", table))?;
+        Ok(())
+    }
+
+    /// Hand out `n` fresh ids for `table`.
+    pub(crate) async fn allocate(&self, table: &str, n: usize) -> Result<Vec<u64>, failure::Error> {
+        let mut ids = Vec::with_capacity(n);
+        loop {
+            {
+                let mut blocks = self.blocks.lock().unwrap();
+                if let Some(block) = blocks.get_mut(table) {
+                    while ids.len() < n && block.next < block.end {
+                        ids.push(block.next);
+                        block.next += 1;
+                    }
+                }
+            }
+            if ids.len() == n {
+                return Ok(ids);
+            }
+
+            // if another connection reserves a block concurrently, one of the two is dropped
+            // here; that leaves a gap in the ids, but never hands out an id twice.
+            let block = self.reserve(table, (n - ids.len()) as u64, None).await?;
+            self.blocks.lock().unwrap().insert(table.to_owned(), block);
+        }
+    }
+
+    /// Make sure no id at or below `id`, which a client inserted explicitly, is handed out for
+    /// `table` later, just like MySQL moves its counter past explicitly inserted values.
+    ///
+    /// Other adapters only notice once they reserve their next block.
+    pub(crate) async fn advance(&self, table: &str, id: u64) -> Result<(), failure::Error> {
+        {
+            let mut blocks = self.blocks.lock().unwrap();
+            if let Some(block) = blocks.get_mut(table) {
+                if id < block.end {
+                    block.next = cmp::max(block.next, id.saturating_add(1));
+                    return Ok(());
+                }
+            }
+        }
+
+        // the id is past everything this adapter reserved, so move the shared counter past it
+        let block = self.reserve(table, 1, Some(id)).await?;
+        self.blocks.lock().unwrap().insert(table.to_owned(), block);
+        Ok(())
+    }
+
+    /// Reserve a block of at least `at_least` ids for `table` in the authority, all of them past
+    /// `after` if given.
+    async fn reserve(
+        &self,
+        table: &str,
+        at_least: u64,
+        after: Option<u64>,
+    ) -> Result<Block, failure::Error> {
+        let authority = self.authority.clone();
+        let path = Self::path(table);
+        let size = cmp::max(at_least, BLOCK_SIZE);
+        let first_free = after.map(|id| id.saturating_add(1)).unwrap_or(1);
+
+        trace!(%table, size, "auto-increment::reserve");
+        // the authority is a blocking client
+        let end = tokio::task::spawn_blocking(move || {
+            // the stored value is the first id that nobody has reserved yet
+            authority.read_modify_write(&path, |next: Option<u64>| -> Result<u64, ()> {
+                Ok(cmp::max(next.unwrap_or(1), first_free) + size)
+            })
+        })
+        .await??
+        .map_err(|()| format_err!("cannot reserve ids for '{}'", table))?;
+
+        Ok(Block {
+            next: end - size,
+            end,
+        })
+    }
+
+    /// The authority key of `table`'s counter.
+    fn path(table: &str) -> String {
+        format!("/auto_increment_{}", table)
+    }
+}
@@ -1,158 +1,273 @@
-use noria::{ControllerHandle, DataType, Table, TableOperation, View, ZookeeperAuthority};
+use noria::consensus::Authority;
+use noria::{ControllerHandle, DataType, Modification, Table, TableOperation, View};
 
-use failure;
-use futures_executor::block_on as block_on_buffer;
+use async_trait::async_trait;
+use futures_util::future::{BoxFuture, FutureExt, Shared};
 use msql_srv::{self, *};
 use nom_sql::{
-    self, ColumnConstraint, InsertStatement, Literal, SelectStatement, SqlQuery, UpdateStatement,
+    self, ColumnConstraint, DeleteStatement, InsertStatement, SelectStatement, SqlQuery,
+    UpdateStatement,
 };
 
 use std::borrow::Cow;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fmt;
 use std::io;
 use std::sync::atomic;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time;
+use tokio::io::AsyncWrite;
 use tracing::Level;
+use tracing_futures::Instrument;
 
-use crate::convert::ToDataType;
+use crate::auto_increment::AutoIncrements;
+use crate::convert::{self, ToDataType};
+use crate::errors::Error;
 use crate::referred_tables::ReferredTables;
 use crate::rewrite;
 use crate::schema::{self, schema_for_column, Schema};
 use crate::utils;
 
+/// Reports an error (anything that converts into `Error`) to the client through the given result
+/// writer, and returns from the enclosing handler.
+macro_rules! report {
+    ($results:expr, $e:expr) => {{
+        let e: Error = $e.into();
+        error!(error = %e, "query failed");
+        return $results
+            .error(e.error_kind(), e.to_string().as_bytes());
+    }};
+}
+
+/// Unwraps a `Result`, or `report!`s its error.
+macro_rules! try_or_report {
+    ($results:expr, $e:expr) => {
+        match $e {
+            Ok(v) => v,
+            Err(e) => report!($results, e),
+        }
+    };
+}
+
 #[derive(Clone)]
+#[allow(clippy::large_enum_variant)]
 enum PreparedStatement {
-    /// Query name, Query, result schema, optional parameter rewrite map
+    /// Query name, Query, result schema, collapsed WHERE IN lists, filters on the results
     Select(
         String,
         nom_sql::SelectStatement,
         Vec<msql_srv::Column>,
-        Option<(usize, usize)>,
+        Vec<(usize, Vec<nom_sql::Literal>)>,
+        Vec<rewrite::PostFilter>,
     ),
-    Insert(nom_sql::InsertStatement),
+    /// An INSERT, and whether it is a REPLACE.
+    Insert(nom_sql::InsertStatement, bool),
     Update(nom_sql::UpdateStatement),
+    Delete(nom_sql::DeleteStatement),
 }
 
 impl fmt::Debug for PreparedStatement {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match *self {
-            PreparedStatement::Select(ref qname, ref s, _, _) => write!(f, "{}: {}", qname, s),
-            PreparedStatement::Insert(ref s) => write!(f, "{}", s),
+            PreparedStatement::Select(ref qname, ref s, ..) => write!(f, "{}: {}", qname, s),
+            PreparedStatement::Insert(ref s, _) => write!(f, "{}", s),
             PreparedStatement::Update(ref s) => write!(f, "{}", s),
+            PreparedStatement::Delete(ref s) => write!(f, "{}", s),
         }
     }
 }
 
-struct NoriaBackendInner {
-    executor: tokio::runtime::Handle,
-    noria: ControllerHandle<ZookeeperAuthority>,
-    inputs: BTreeMap<String, Table>,
-    outputs: BTreeMap<String, View>,
+/// Process-wide registry of Noria table and view handles.
+///
+/// Handles are fetched from the controller the first time any connection needs them, and are
+/// cloned into each connection from then on. The handles are `Send` but not `Sync`, so they sit
+/// behind a `Mutex`; it is only held to clone a handle in or out.
+#[derive(Default)]
+pub struct EndpointRegistry {
+    inputs: Mutex<HashMap<String, Table>>,
+    outputs: Mutex<HashMap<String, View>>,
 }
 
-macro_rules! block_on {
-    ($self:expr, $fut:expr) => {{
-        let noria = &mut $self.noria;
-        futures_executor::block_on(futures_util::future::poll_fn(|cx| noria.poll_ready(cx)))
-            .unwrap();
-        futures_executor::block_on($self.executor.spawn($fut)).unwrap()
-    }};
+impl EndpointRegistry {
+    /// Forget any handles for the table or view named `name`, so that they are fetched anew on
+    /// their next use.
+    pub fn invalidate(&self, name: &str) {
+        self.inputs.lock().unwrap().remove(name);
+        self.outputs.lock().unwrap().remove(name);
+    }
 }
 
-impl NoriaBackendInner {
-    async fn new(ex: tokio::runtime::Handle, mut ch: ControllerHandle<ZookeeperAuthority>) -> Self {
-        ch.ready().await.unwrap();
-        let inputs = ch.inputs().await.expect("couldn't get inputs from Noria");
-        let mut i = BTreeMap::new();
-        for (n, _) in inputs {
-            ch.ready().await.unwrap();
-            let t = ch.table(&n).await.unwrap();
-            i.insert(n, t);
-        }
-        ch.ready().await.unwrap();
-        let outputs = ch.outputs().await.expect("couldn't get outputs from Noria");
-        let mut o = BTreeMap::new();
-        for (n, _) in outputs {
-            ch.ready().await.unwrap();
-            let t = ch.view(&n).await.unwrap();
-            o.insert(n, t);
-        }
+/// A view migration that is currently in flight, shared by everyone waiting for it.
+///
+/// Resolves to the name of the new view, or to a description of why the migration failed.
+type PendingView = Shared<BoxFuture<'static, Result<String, String>>>;
+
+#[derive(Clone)]
+enum CachedView {
+    /// the view exists in Noria under this name
+    Ready(String),
+    /// some connection is adding the view to Noria
+    Pending(PendingView),
+}
+
+/// Process-wide cache mapping SELECT queries to the Noria views that answer them.
+///
+/// The first connection to issue a query starts the migration that adds its view; connections
+/// that issue the same query in the meantime wait on that migration rather than starting their
+/// own. The cache is only locked to look up or record a migration, never while one runs.
+#[derive(Default)]
+pub struct QueryCache {
+    views: RwLock<HashMap<SelectStatement, CachedView>>,
+}
+
+struct NoriaBackendInner<A: Authority + 'static> {
+    noria: ControllerHandle<A>,
+    endpoints: Arc<EndpointRegistry>,
+    /// connection-local clones of the handles in `endpoints` (consulted first)
+    inputs: BTreeMap<String, Table>,
+    outputs: BTreeMap<String, View>,
+}
+
+impl<A: Authority + 'static> NoriaBackendInner<A> {
+    fn new(ch: ControllerHandle<A>, endpoints: Arc<EndpointRegistry>) -> Self {
         NoriaBackendInner {
-            executor: ex,
-            inputs: i,
-            outputs: o,
             noria: ch,
+            endpoints,
+            inputs: BTreeMap::new(),
+            outputs: BTreeMap::new(),
         }
     }
 }
 
-impl NoriaBackendInner {
-    fn ensure_mutator<'a, 'b>(&'a mut self, table: &'b str) -> &'a mut Table {
-        self.get_or_make_mutator(table)
-            .expect(&format!("no table named '{}'!", table))
+impl<A: Authority + 'static> NoriaBackendInner<A> {
+    // TODO(malte): the error handling here is lame, as it doesn't differentiate between "no such
+    // table" errors and transport or network errors.
+    async fn ensure_mutator<'a, 'b>(&'a mut self, table: &'b str) -> Result<&'a mut Table, Error> {
+        self.get_or_make_mutator(table).await.map_err(|e| {
+            warn!(error = %e, %table, "no mutator");
+            Error::NoSuchTable(table.to_owned())
+        })
     }
 
-    fn ensure_getter<'a, 'b>(&'a mut self, view: &'b str) -> &'a mut View {
-        self.get_or_make_getter(view)
-            .expect(&format!("no view named '{}'!", view))
+    async fn ensure_getter<'a, 'b>(&'a mut self, view: &'b str) -> Result<&'a mut View, Error> {
+        self.get_or_make_getter(view).await.map_err(|e| {
+            warn!(error = %e, %view, "no getter");
+            Error::NoSuchTable(view.to_owned())
+        })
     }
 
-    fn get_or_make_mutator<'a, 'b>(
+    async fn get_or_make_mutator<'a, 'b>(
         &'a mut self,
         table: &'b str,
     ) -> Result<&'a mut Table, failure::Error> {
         if !self.inputs.contains_key(table) {
-            let t = block_on!(self, self.noria.table(table))?;
+            let shared = self.endpoints.inputs.lock().unwrap().get(table).cloned();
+            let t = match shared {
+                Some(t) => t,
+                None => {
+                    self.noria.ready().await?;
+                    let t = self.noria.table(table).await?;
+                    self.endpoints
+                        .inputs
+                        .lock()
+                        .unwrap()
+                        .insert(table.to_owned(), t.clone());
+                    t
+                }
+            };
             self.inputs.insert(table.to_owned(), t);
         }
         Ok(self.inputs.get_mut(table).unwrap())
     }
 
-    fn get_or_make_getter<'a, 'b>(
+    async fn get_or_make_getter<'a, 'b>(
         &'a mut self,
         view: &'b str,
     ) -> Result<&'a mut View, failure::Error> {
         if !self.outputs.contains_key(view) {
-            let vh = block_on!(self, self.noria.view(view))?;
+            let shared = self.endpoints.outputs.lock().unwrap().get(view).cloned();
+            let vh = match shared {
+                Some(vh) => vh,
+                None => {
+                    self.noria.ready().await?;
+                    let vh = self.noria.view(view).await?;
+                    self.endpoints
+                        .outputs
+                        .lock()
+                        .unwrap()
+                        .insert(view.to_owned(), vh.clone());
+                    vh
+                }
+            };
             self.outputs.insert(view.to_owned(), vh);
         }
         Ok(self.outputs.get_mut(view).unwrap())
     }
+
+    /// Drop the handles for `name`, both here and in the process-wide registry.
+    fn invalidate(&mut self, name: &str) {
+        self.inputs.remove(name);
+        self.outputs.remove(name);
+        self.endpoints.invalidate(name);
+    }
+
+    async fn extend_recipe(&mut self, recipe_addition: &str) -> Result<(), failure::Error> {
+        self.noria.ready().await?;
+        self.noria.extend_recipe(recipe_addition).await?;
+        Ok(())
+    }
 }
 
-pub struct NoriaBackend {
-    inner: NoriaBackendInner,
+pub struct NoriaBackend<A: Authority + 'static> {
+    inner: NoriaBackendInner<A>,
     ops: Arc<atomic::AtomicUsize>,
     trace_every: Option<usize>,
 
-    auto_increments: Arc<RwLock<HashMap<String, atomic::AtomicUsize>>>,
+    auto_increments: Arc<AutoIncrements<A>>,
 
     prepared: HashMap<u32, PreparedStatement>,
     prepared_count: u32,
 
     /// global cache of view endpoints and prepared statements
-    cached: Arc<RwLock<HashMap<SelectStatement, String>>>,
-    /// thread-local version of `cached` (consulted first)
+    cached: Arc<QueryCache>,
+    /// this connection's copy of `cached` (consulted first)
     tl_cached: HashMap<SelectStatement, String>,
+    /// how long to wait for a view that is being created before giving up
+    view_timeout: Option<time::Duration>,
 
     primed: Arc<atomic::AtomicBool>,
     reset: bool,
 
-    parsed: HashMap<String, (SqlQuery, Vec<nom_sql::Literal>)>,
+    /// whether writes outside of an explicit transaction take effect immediately
+    autocommit: bool,
+    /// whether the client has started a transaction with BEGIN or START TRANSACTION
+    in_transaction: bool,
+    /// writes made by the current transaction, held back until it commits
+    pending_writes: BTreeMap<String, Vec<TableOperation>>,
+
+    /// parsed (and rewritten) ad-hoc queries, along with the lookup keys they use and whether
+    /// the results of those keys have to be merged
+    parsed: HashMap<String, (SqlQuery, Vec<Vec<DataType>>, bool)>,
 
     sanitize: bool,
     slowlog: bool,
     static_responses: bool,
+    /// whether UPDATE reports the rows it matched rather than the rows it changed, because the
+    /// client announced the CLIENT_FOUND_ROWS capability when it connected
+    found_rows: bool,
+    /// the warnings of the last statement, for SHOW WARNINGS
+    warnings: Vec<(ErrorKind, String)>,
 }
 
-impl NoriaBackend {
+impl<A: Authority + 'static> NoriaBackend<A> {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
-        ex: tokio::runtime::Handle,
-        ch: ControllerHandle<ZookeeperAuthority>,
-        auto_increments: Arc<RwLock<HashMap<String, atomic::AtomicUsize>>>,
-        query_cache: Arc<RwLock<HashMap<SelectStatement, String>>>,
+        ch: ControllerHandle<A>,
+        endpoints: Arc<EndpointRegistry>,
+        auto_increments: Arc<AutoIncrements<A>>,
+        query_cache: Arc<QueryCache>,
+        view_timeout: Option<time::Duration>,
         (ops, trace_every): (Arc<atomic::AtomicUsize>, Option<usize>),
         primed: Arc<atomic::AtomicBool>,
         slowlog: bool,
@@ -160,7 +275,7 @@ impl NoriaBackend {
         sanitize: bool,
     ) -> Self {
         NoriaBackend {
-            inner: NoriaBackendInner::new(ex, ch).await,
+            inner: NoriaBackendInner::new(ch, endpoints),
             ops,
             trace_every,
 
@@ -171,39 +286,42 @@ impl NoriaBackend {
 
             cached: query_cache,
             tl_cached: HashMap::new(),
+            view_timeout,
 
             parsed: HashMap::new(),
 
             primed,
             reset: false,
 
+            autocommit: true,
+            in_transaction: false,
+            pending_writes: BTreeMap::new(),
+
             sanitize,
             slowlog,
             static_responses,
+            found_rows: false,
+            warnings: Vec::new(),
         }
     }
 
-    fn fetch_endpoints(&mut self, need: Vec<nom_sql::Table>) -> Result<(), failure::Error> {
+    async fn fetch_endpoints(&mut self, need: Vec<nom_sql::Table>) -> Result<(), Error> {
         for t in need {
             //  1. check inner.inputs/inner.outputs
-            if self.inner.inputs.contains_key(&t.name) {
-                // already have mutator, nothing more to do
-                continue;
-            } else if self.inner.outputs.contains_key(&t.name) {
-                // already have getter, nothing more to do
+            if self.inner.inputs.contains_key(&t.name) || self.inner.outputs.contains_key(&t.name) {
+                // already have mutator or getter, nothing more to do
                 continue;
-            } else {
-                //  2. If nothing, RPC for iew
-                match self.inner.get_or_make_getter(&t.name) {
-                    // TODO(malte): the error handling here is lame, as it doesn't differentiate
-                    // between "no such view/table" errors and transport or network errors.
-                    Ok(_) => continue,
-                    Err(_) => {
-                        //  3. If nothing, RPC for table
-                        if self.inner.get_or_make_mutator(&t.name).is_err() {
-                            //  4. If still nothing, panic
-                            bail!("no view or table named '{}'", t.name);
-                        }
+            }
+            //  2. If nothing, RPC for iew
+            match self.inner.get_or_make_getter(&t.name).await {
+                // TODO(malte): the error handling here is lame, as it doesn't differentiate
+                // between "no such view/table" errors and transport or network errors.
+                Ok(_) => continue,
+                Err(_) => {
+                    //  3. If nothing, RPC for table
+                    if self.inner.get_or_make_mutator(&t.name).await.is_err() {
+                        //  4. If still nothing, the client asked for something that isn't there
+                        return Err(Error::NoSuchTable(t.name));
                     }
                 }
             }
@@ -211,207 +329,978 @@ impl NoriaBackend {
         Ok(())
     }
 
-    fn handle_create_table<W: io::Write>(
+    /// Find the primary keys of the rows an UPDATE or DELETE with WHERE-clause `cond` affects,
+    /// along with the current rows, in the order of the table's columns.
+    ///
+    /// `params` are the values of the placeholders in `cond`, in order. If the WHERE-clause names
+    /// the keys, they are all returned, even those without a row: the view the rows are read from
+    /// may not have caught up with recent writes yet. Either way, this takes a single lookup.
+    async fn resolve_rows(
+        &mut self,
+        table: &str,
+        schema: &nom_sql::CreateTableStatement,
+        cond: Option<&nom_sql::ConditionExpression>,
+        params: Vec<DataType>,
+    ) -> Result<(Vec<Vec<DataType>>, Vec<Vec<DataType>>), Error> {
+        let pkey = utils::get_primary_key(schema);
+        let columns: Vec<_> = pkey.iter().map(|&(_, c)| c.clone()).collect();
+        let flattened = cond.map(|cond| {
+            let columns: Vec<_> = columns.iter().collect();
+            utils::flatten_conditional(cond, &columns, &params)
+        });
+        let rows = match flattened {
+            // the WHERE-clause contradicts itself (e.g., `id = 1 AND id = 2`)
+            Some(Ok(None)) => return Ok((vec![], vec![])),
+            // the WHERE-clause names the keys
+            Some(Ok(Some(keys))) if !keys.is_empty() => {
+                let rows = self
+                    .rows_for_keys(table, schema, &columns, keys.clone())
+                    .await?;
+                return Ok((keys, rows));
+            }
+            // otherwise, we have to find the matching rows first. the WHERE-clause is either
+            // missing, always true (e.g., `1 = 1`), or something other than key equalities.
+            Some(Ok(Some(_))) | None => self.matching_rows(table, schema, None, vec![]).await?,
+            Some(Err(e)) => {
+                trace!(reason = %e, "looking up matching rows");
+                self.matching_rows(table, schema, cond.cloned(), params)
+                    .await?
+            }
+        };
+        let keys = rows
+            .iter()
+            .map(|row| pkey.iter().map(|&(i, _)| row[i].clone()).collect())
+            .collect();
+        Ok((keys, rows))
+    }
+
+    /// Find the rows in `table` that match `cond` (or all rows, if there is no condition), in
+    /// the order of the table's columns.
+    ///
+    /// Like any ad-hoc query, the condition's literals are lifted into parameters, so that
+    /// statements of the same shape share the view that `lookup_rows` reads through. Comparisons
+    /// that Noria cannot look up by (e.g., `expires < 5`) are applied to the view's rows instead,
+    /// just like the filters of a prepared SELECT.
+    async fn matching_rows(
+        &mut self,
+        table: &str,
+        schema: &nom_sql::CreateTableStatement,
+        cond: Option<nom_sql::ConditionExpression>,
+        params: Vec<DataType>,
+    ) -> Result<Vec<Vec<DataType>>, Error> {
+        let pkey = utils::get_primary_key(schema);
+        if pkey.is_empty() {
+            return Err(Error::NotSupported(format!(
+                "UPDATE/DELETE on table '{}' without primary key",
+                table
+            )));
+        }
+
+        let mut q = SelectStatement {
+            tables: vec![nom_sql::Table::from(table)],
+            where_clause: cond,
+            ..SelectStatement::default()
+        };
+        let mut values = rewrite::bind_lookup_params(&mut q, params)?;
+        let (filters, _) = rewrite::extract_post_filters(&mut q)?;
+        // the filters' parameters are not part of the lookup key
+        let mut filter_values = Vec::with_capacity(filters.len());
+        for f in filters.iter().rev() {
+            filter_values.push(values.remove(f.param));
+        }
+        let filters: Vec<_> = filters
+            .iter()
+            .zip(filter_values.into_iter().rev())
+            .map(|(f, v)| {
+                rewrite::post_filter_source(&q, f)
+                    .and_then(|c| schema.fields.iter().position(|cs| cs.column.name == c.name))
+                    .map(|i| (i, f.operator.clone(), v))
+                    .ok_or_else(|| Error::BadField(f.column.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+        let mut q = SqlQuery::Select(q);
+        let collapsed = rewrite::collapse_where_in(&mut q, true)?;
+        let keys = utils::where_in_keys(values, &collapsed)?;
+        let cond = match q {
+            SqlQuery::Select(q) => q.where_clause,
+            _ => unreachable!(),
+        };
+        self.lookup_rows(table, schema, cond, keys, filters).await
+    }
+
+    /// Look up the rows of `table` that match the WHERE-clause `cond` for any of `keys`, and pass
+    /// `filters`, each of which compares the column at an index with a value. Rows are returned
+    /// in the order of the table's columns, once per primary key.
+    ///
+    /// This reads through a view that selects the columns of the rows that match `cond`. If
+    /// there is no equality to look up by at all, that means reading every row of the table,
+    /// which is logged as a warning if the rows are filtered afterwards. The rows that the writes
+    /// of the open transaction touch are read by key instead, with those writes applied (see
+    /// `rows_for_keys`), and checked against `cond` by the adapter. For that, `cond` may only AND
+    /// comparisons of columns together (see `rewrite::conjunctive_comparisons`).
+    async fn lookup_rows(
+        &mut self,
+        table: &str,
+        schema: &nom_sql::CreateTableStatement,
+        cond: Option<nom_sql::ConditionExpression>,
+        keys: Vec<Vec<DataType>>,
+        filters: Vec<(usize, nom_sql::Operator, DataType)>,
+    ) -> Result<Vec<Vec<DataType>>, Error> {
+        let pkey = utils::get_primary_key(schema);
+        let pkey_columns: Vec<usize> = pkey.iter().map(|&(i, _)| i).collect();
+        let key_of = |row: &[DataType]| -> Vec<DataType> {
+            pkey_columns.iter().map(|&i| row[i].clone()).collect()
+        };
+        let position = |c: &nom_sql::Column| {
+            schema
+                .fields
+                .iter()
+                .position(|cs| cs.column.name == c.name)
+                .ok_or_else(|| Error::BadField(c.name.clone()))
+        };
+
+        // the keys that the transaction has written to, and how to tell if their rows match
+        let pending = match self.pending_writes.get(table) {
+            None => None,
+            Some(ops) => {
+                let (lookups, comparisons) = match cond {
+                    None => (vec![], vec![]),
+                    Some(ref cond) => rewrite::conjunctive_comparisons(cond).ok_or_else(|| {
+                        Error::NotSupported(format!(
+                            "OR or a nested SELECT in a WHERE-clause on table '{}' after writing \
+                             to it in the same transaction",
+                            table
+                        ))
+                    })?,
+                };
+                let lookups = lookups
+                    .into_iter()
+                    .map(position)
+                    .collect::<Result<Vec<_>, _>>()?;
+                let comparisons = comparisons
+                    .into_iter()
+                    .map(|(c, op, v)| Ok((position(c)?, op, v)))
+                    .collect::<Result<Vec<_>, Error>>()?;
+                Some((
+                    utils::pending_keys(&pkey_columns, ops),
+                    lookups,
+                    comparisons,
+                ))
+            }
+        };
+
+        let q = SelectStatement {
+            tables: vec![nom_sql::Table::from(table)],
+            fields: schema
+                .fields
+                .iter()
+                .map(|cs| {
+                    let mut c = cs.column.clone();
+                    c.table = Some(table.to_owned());
+                    nom_sql::FieldDefinitionExpression::Col(c)
+                })
+                .collect(),
+            where_clause: cond,
+            ..SelectStatement::default()
+        };
+        let qname = self.get_or_create_view(&q, false).await?;
+
+        trace!(%table, %qname, "lookup rows::lookup");
+        let getter = self.inner.ensure_getter(&qname).await?;
+        let positions: Vec<usize> = schema
+            .fields
+            .iter()
+            .map(|cs| {
+                getter
+                    .columns()
+                    .iter()
+                    .position(|f| *f == cs.column.name)
+                    .ok_or_else(|| Error::BadField(cs.column.name.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+        if keys.is_empty() {
+            // the condition has an empty WHERE IN ()
+            return Ok(vec![]);
+        }
+        let scan = keys.iter().all(|k| k.is_empty());
+        let lookup = if scan {
+            vec![vec![DataType::from(0_i32)]]
+        } else {
+            keys.clone()
+        };
+        let rows = match getter.multi_lookup(lookup, true).await {
+            Ok(rows) => rows,
+            Err(e) => {
+                self.inner.invalidate(&qname);
+                return Err(Error::Noria(e.to_string()));
+            }
+        };
+        let rows: Vec<_> = rows.into_iter().flatten().collect();
+        let scanned = rows.len();
+
+        let passes = |row: &[DataType]| {
+            filters
+                .iter()
+                .all(|&(i, ref op, ref v)| utils::compare(&row[i], op, v))
+        };
+        // overlapping lookup keys (e.g., from a WHERE-IN) can return the same row twice
+        let mut seen = HashSet::new();
+        let mut rows: Vec<_> = rows
+            .into_iter()
+            .map(|r| positions.iter().map(|&i| r[i].clone()).collect::<Vec<_>>())
+            .filter(|row| passes(row) && seen.insert(key_of(row)))
+            .collect();
+        if scan && !filters.is_empty() {
+            warn!(
+                %table,
+                scanned,
+                matched = rows.len(),
+                "WHERE-clause has no equality to look up by; read the whole table"
+            );
+        }
+
+        if let Some((touched, lookups, comparisons)) = pending {
+            // Noria's rows for those keys are out of date
+            trace!(%table, keys = touched.len(), "lookup rows::apply transaction");
+            let touched_keys: HashSet<_> = touched.iter().cloned().collect();
+            rows.retain(|row| !touched_keys.contains(&key_of(row)));
+            let pkey: Vec<_> = pkey.iter().map(|&(_, c)| c.clone()).collect();
+            let current = self.rows_for_keys(table, schema, &pkey, touched).await?;
+            rows.extend(current.into_iter().filter(|row| {
+                let found = keys.iter().any(|k| {
+                    lookups
+                        .iter()
+                        .zip(k)
+                        .all(|(&i, v)| utils::compare(&row[i], &nom_sql::Operator::Equal, v))
+                });
+                found
+                    && passes(row)
+                    && comparisons
+                        .iter()
+                        .all(|&(i, ref op, ref v)| utils::compare(&row[i], op, v))
+            }));
+        }
+        Ok(rows)
+    }
+
+    /// Read the rows of the SELECT `q` for `keys` as the open transaction sees them, if it has
+    /// written to a table that `q` reads. The rows have the columns in `schema`, and pass
+    /// `filters`, which were extracted from `q` (see `rewrite::extract_post_filters`).
+    ///
+    /// Noria does not have the transaction's writes yet, so the rows are read by `lookup_rows`
+    /// rather than through the view of `q`. That only works for a query of plain columns of a
+    /// single table. There is no telling how the writes change rows that are joined, aggregated,
+    /// ordered or limited, so reading those fails.
+    async fn read_buffered(
+        &mut self,
+        q: &SelectStatement,
+        keys: &[Vec<DataType>],
+        schema: &[msql_srv::Column],
+        filters: &[(&rewrite::PostFilter, DataType)],
+    ) -> Result<Option<Vec<Vec<DataType>>>, Error> {
+        let table = match utils::select_tables(q)
+            .into_iter()
+            .find(|t| self.pending_writes.contains_key(&t.name))
+        {
+            Some(t) => t.name.clone(),
+            None => return Ok(None),
+        };
+        let unsupported = || {
+            Error::NotSupported(format!(
+                "joins, aggregates, DISTINCT, ORDER BY or LIMIT on table '{}' after writing to it \
+                 in the same transaction",
+                table
+            ))
+        };
+        if q.tables.len() != 1
+            || !q.join.is_empty()
+            || q.group_by.is_some()
+            || q.order.is_some()
+            || q.limit.is_some()
+            || q.distinct
+        {
+            return Err(unsupported());
+        }
+
+        let mutator = self.inner.ensure_mutator(&table).await?;
+        let table_schema = mutator
+            .schema()
+            .cloned()
+            .ok_or_else(|| Error::NoSuchTable(table.clone()))?;
+        if utils::get_primary_key(&table_schema).is_empty() {
+            return Err(Error::NotSupported(format!(
+                "reading table '{}' without primary key after writing to it in the same \
+                 transaction",
+                table
+            )));
+        }
+        let position = |c: &nom_sql::Column| {
+            table_schema
+                .fields
+                .iter()
+                .position(|cs| cs.column.name == c.name)
+                .ok_or_else(|| Error::BadField(c.name.clone()))
+        };
+
+        // the table's column behind each column the query returns
+        let mut selected = Vec::new();
+        for f in &q.fields {
+            match *f {
+                nom_sql::FieldDefinitionExpression::All
+                | nom_sql::FieldDefinitionExpression::AllInTable(_) => selected.extend(
+                    table_schema
+                        .fields
+                        .iter()
+                        .enumerate()
+                        .map(|(i, cs)| (cs.column.name.as_str(), i)),
+                ),
+                nom_sql::FieldDefinitionExpression::Col(ref c) if c.function.is_none() => {
+                    selected.push((c.alias.as_deref().unwrap_or(&c.name), position(c)?))
+                }
+                _ => return Err(unsupported()),
+            }
+        }
+        let projection: Vec<usize> = schema
+            .iter()
+            .map(|cs| {
+                selected
+                    .iter()
+                    .find(|&&(name, _)| name == cs.column)
+                    .map(|&(_, i)| i)
+                    .ok_or_else(|| Error::BadField(cs.column.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+        let filters = filters
+            .iter()
+            .map(|&(f, ref v)| {
+                let c = rewrite::post_filter_source(q, f)
+                    .ok_or_else(|| Error::BadField(f.column.clone()))?;
+                Ok((position(c)?, f.operator.clone(), v.clone()))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        let rows = self
+            .lookup_rows(
+                &table,
+                &table_schema,
+                q.where_clause.clone(),
+                keys.to_vec(),
+                filters,
+            )
+            .await?;
+        Ok(Some(
+            rows.into_iter()
+                .map(|r| projection.iter().map(|&i| r[i].clone()).collect())
+                .collect(),
+        ))
+    }
+
+    /// The columns of the view `qname`, as they are sent to clients.
+    async fn view_schema(&mut self, qname: &str) -> Result<Vec<msql_srv::Column>, Error> {
+        let getter = self.inner.ensure_getter(qname).await?;
+        let schema = getter
+            .schema()
+            .ok_or_else(|| Error::Noria(format!("no schema for view '{}'", qname)))?;
+        Ok(schema::convert_schema(&Schema::View(
+            schema
+                .iter()
+                .cloned()
+                .filter(|c| c.column.name != "bogokey")
+                .collect(),
+        )))
+    }
+
+    /// Look up `keys` in the view `qname` of the query `q`, and return the matching rows that
+    /// pass `filters` with the columns in `schema`. The results of the keys are combined as
+    /// described by `merge`. If the open transaction has written to a table that `q` reads, the
+    /// rows are read as the transaction sees them instead (see `read_buffered`).
+    async fn read_rows(
+        &mut self,
+        qname: &str,
+        q: &SelectStatement,
+        keys: Vec<Vec<DataType>>,
+        schema: &[msql_srv::Column],
+        filters: &[(&rewrite::PostFilter, DataType)],
+        merge: &utils::ResultMerge,
+    ) -> Result<Vec<Vec<DataType>>, Error> {
+        if let Some(rows) = self.read_buffered(q, &keys, schema, filters).await? {
+            return Ok(rows);
+        }
+
+        // create a getter if we don't have one for this query already
+        // TODO(malte): may need to make one anyway if the query has changed w.r.t. an
+        // earlier one of the same name
+        trace!("select::access view");
+        let getter = self.inner.ensure_getter(qname).await?;
+
+        trace!("select::lookup");
+        let cols = Vec::from(getter.columns());
+        let positions: Vec<usize> = schema
+            .iter()
+            .map(|c| {
+                cols.iter()
+                    .position(|f| f == &c.column)
+                    .ok_or_else(|| Error::BadField(c.column.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+        let filters: Vec<_> = filters
+            .iter()
+            .map(|(f, v)| {
+                cols.iter()
+                    .position(|c| *c == f.column)
+                    .map(|i| (i, &f.operator, v))
+                    .ok_or_else(|| Error::BadField(f.column.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+        // an empty WHERE IN () matches nothing, while a query without parameters has a single,
+        // empty key
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+        let bogo = vec![vec![DataType::from(0_i32)]];
+        let is_bogo = keys.iter().all(|k| k.is_empty());
+        let keys = if is_bogo { bogo } else { keys };
+
+        let ops = &self.ops;
+        if self
+            .trace_every
+            .map(|te| ops.fetch_add(1, atomic::Ordering::AcqRel) % te == 0)
+            .unwrap_or(false)
+        {
+            noria::trace_my_next_op();
+        }
+
+        // if first lookup fails, there's no reason to try the others
+        match getter.multi_lookup(keys, true).await {
+            Ok(d) => {
+                trace!("select::complete");
+                // noria aggregates, orders, limits and deduplicates the rows of each key on its own
+                let rows: Vec<Vec<DataType>> = d
+                    .into_iter()
+                    .flatten()
+                    .filter(|r| {
+                        filters
+                            .iter()
+                            .all(|&(coli, op, v)| utils::compare(&r[coli], op, v))
+                    })
+                    .collect();
+                let mut rows = merge.aggregate(rows, &cols)?;
+                merge.sort(&mut rows, &cols)?;
+                let rows = rows
+                    .into_iter()
+                    .map(|r| positions.iter().map(|&coli| r[coli].clone()).collect())
+                    .collect();
+                Ok(merge.truncate(rows))
+            }
+            Err(e) => {
+                self.inner.invalidate(qname);
+                Err(Error::Noria(e.to_string()))
+            }
+        }
+    }
+
+    /// Look up the current rows of `table` with the given primary keys, through a view keyed on
+    /// the primary key. Rows are returned in the order of the table's columns, and keys that
+    /// match no row are skipped. Writes that the open transaction holds back are applied to the
+    /// rows as well.
+    async fn rows_for_keys(
+        &mut self,
+        table: &str,
+        schema: &nom_sql::CreateTableStatement,
+        pkey: &[nom_sql::Column],
+        keys: Vec<Vec<DataType>>,
+    ) -> Result<Vec<Vec<DataType>>, Error> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let column = |c: &nom_sql::Column| {
+            let mut c = c.clone();
+            c.table = Some(table.to_owned());
+            c
+        };
+        let cond = pkey
+            .iter()
+            .map(|c| {
+                nom_sql::ConditionExpression::ComparisonOp(nom_sql::ConditionTree {
+                    operator: nom_sql::Operator::Equal,
+                    left: Box::new(nom_sql::ConditionExpression::Base(
+                        nom_sql::ConditionBase::Field(column(c)),
+                    )),
+                    right: Box::new(nom_sql::ConditionExpression::Base(
+                        nom_sql::ConditionBase::Literal(nom_sql::Literal::Placeholder),
+                    )),
+                })
+            })
+            .fold(None, |acc, eq| match acc {
+                None => Some(eq),
+                Some(acc) => Some(nom_sql::ConditionExpression::LogicalOp(
+                    nom_sql::ConditionTree {
+                        operator: nom_sql::Operator::And,
+                        left: Box::new(acc),
+                        right: Box::new(eq),
+                    },
+                )),
+            });
+        // the columns are selected back to front: Noria would otherwise take a later query that
+        // selects the columns in order without a WHERE-clause (e.g., `SELECT * FROM t`) for this
+        // one with different parameters, and key its view on nothing rather than the bogokey,
+        // so that reading the whole table comes back empty
+        let q = SelectStatement {
+            tables: vec![nom_sql::Table::from(table)],
+            fields: schema
+                .fields
+                .iter()
+                .rev()
+                .map(|cs| nom_sql::FieldDefinitionExpression::Col(column(&cs.column)))
+                .collect(),
+            where_clause: cond,
+            ..SelectStatement::default()
+        };
+        let qname = self.get_or_create_view(&q, true).await?;
+
+        trace!(%table, %qname, "rows for keys::lookup");
+        let getter = self.inner.ensure_getter(&qname).await?;
+        let positions: Vec<usize> = schema
+            .fields
+            .iter()
+            .map(|cs| {
+                getter
+                    .columns()
+                    .iter()
+                    .position(|f| *f == cs.column.name)
+                    .ok_or_else(|| Error::BadField(cs.column.name.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+        let pending = self
+            .pending_writes
+            .get(table)
+            .map(|ops| (ops, keys.clone()));
+        let rows = match getter.multi_lookup(keys, true).await {
+            Ok(rows) => rows,
+            Err(e) => {
+                self.inner.invalidate(&qname);
+                return Err(Error::Noria(e.to_string()));
+            }
+        };
+
+        let rows = rows
+            .into_iter()
+            .flatten()
+            .map(|r| {
+                let r: Vec<DataType> = r;
+                positions.iter().map(|&i| r[i].clone()).collect()
+            })
+            .collect();
+        Ok(match pending {
+            Some((ops, keys)) => {
+                let pkey: Vec<usize> = utils::get_primary_key(schema)
+                    .into_iter()
+                    .map(|(i, _)| i)
+                    .collect();
+                utils::apply_pending_writes(rows, &pkey, &keys, ops)
+            }
+            None => rows,
+        })
+    }
+
+    /// Make sure `table` has an AUTO_INCREMENT counter, and create it past the largest value of
+    /// `column` if not, so that ids the table already holds are not handed out again.
+    ///
+    /// The largest value is read through a view that computes it, which is only needed once per
+    /// table: the counter lives on in the authority after that.
+    async fn seed_auto_increment(
+        &mut self,
+        table: &str,
+        column: &nom_sql::Column,
+    ) -> Result<(), Error> {
+        let has_counter = self
+            .auto_increments
+            .has_counter(table)
+            .await
+            .map_err(|e| Error::AutoIncrement(e.to_string()))?;
+        if has_counter {
+            return Ok(());
+        }
+
+        let mut column = column.clone();
+        column.table = Some(table.to_owned());
+        let max = nom_sql::FunctionExpression::Max(nom_sql::FunctionArguments::Column(column));
+        let q = SelectStatement {
+            tables: vec![nom_sql::Table::from(table)],
+            fields: vec![nom_sql::FieldDefinitionExpression::Col(nom_sql::Column {
+                name: max.to_string(),
+                alias: None,
+                table: None,
+                function: Some(Box::new(max)),
+            })],
+            ..SelectStatement::default()
+        };
+        let qname = self.get_or_create_view(&q, false).await?;
+
+        trace!(%table, %qname, "auto-increment::read max");
+        let getter = self.inner.ensure_getter(&qname).await?;
+        let i = getter
+            .columns()
+            .iter()
+            .position(|c| c != "bogokey")
+            .ok_or_else(|| Error::Noria(format!("view '{}' has no columns", qname)))?;
+        let rows = match getter
+            .multi_lookup(vec![vec![DataType::from(0_i32)]], true)
+            .await
+        {
+            Ok(rows) => rows,
+            Err(e) => {
+                self.inner.invalidate(&qname);
+                return Err(Error::Noria(e.to_string()));
+            }
+        };
+        // an empty table has no rows here, or a NULL maximum
+        let max = rows
+            .into_iter()
+            .flatten()
+            .filter_map(|r| utils::auto_increment_id(&r[i]))
+            .max()
+            .unwrap_or(0);
+
+        self.auto_increments
+            .seed(table, max)
+            .await
+            .map_err(|e| Error::AutoIncrement(e.to_string()))
+    }
+
+    /// Apply `ops` to `table`, or hold them back until COMMIT if a transaction is open.
+    async fn perform(
+        &mut self,
+        table: &str,
+        ops: Vec<TableOperation>,
+    ) -> Result<(), failure::Error> {
+        if self.in_transaction || !self.autocommit {
+            trace!(%table, ops = ops.len(), "transaction::buffer");
+            self.pending_writes
+                .entry(table.to_owned())
+                .or_default()
+                .extend(ops);
+            return Ok(());
+        }
+
+        let mutator = self.inner.get_or_make_mutator(table).await?;
+        mutator.perform_all(ops).await?;
+        Ok(())
+    }
+
+    /// Apply the writes buffered by the current transaction as one batch per table.
+    ///
+    /// Noria does not make batches to different tables atomic with respect to each other, so if
+    /// one fails, the batches applied before it stay applied. The failed batch and the ones after
+    /// it stay buffered, so that the client can retry the COMMIT or ROLLBACK what is left.
+    async fn commit(&mut self) -> Result<(), failure::Error> {
+        while let Some(table) = self.pending_writes.keys().next().cloned() {
+            let ops = self.pending_writes[&table].clone();
+            trace!(%table, ops = ops.len(), "transaction::commit");
+            let mutator = self.inner.get_or_make_mutator(&table).await?;
+            if let Err(e) = mutator.perform_all(ops).await {
+                self.inner.invalidate(&table);
+                return Err(e.into());
+            }
+            self.pending_writes.remove(&table);
+        }
+        Ok(())
+    }
+
+    /// Commit the current transaction, if any, and start a new one if `begin` is set.
+    ///
+    /// If the commit fails, the current transaction stays open (see `commit`).
+    async fn handle_commit<W: AsyncWrite + Unpin + Send>(
+        &mut self,
+        begin: bool,
+        results: QueryResultWriter<'_, W>,
+    ) -> io::Result<()> {
+        try_or_report!(results, self.commit().await);
+        self.in_transaction = begin;
+        results.completed(0, 0)
+    }
+
+    /// List the warnings of the last statement, like MySQL's SHOW WARNINGS.
+    fn handle_show_warnings<W: AsyncWrite + Unpin + Send>(
+        &mut self,
+        results: QueryResultWriter<'_, W>,
+    ) -> io::Result<()> {
+        let cols: Vec<_> = [
+            (
+                "Level",
+                ColumnType::MYSQL_TYPE_VAR_STRING,
+                ColumnFlags::empty(),
+            ),
+            (
+                "Code",
+                ColumnType::MYSQL_TYPE_LONG,
+                ColumnFlags::UNSIGNED_FLAG,
+            ),
+            (
+                "Message",
+                ColumnType::MYSQL_TYPE_VAR_STRING,
+                ColumnFlags::empty(),
+            ),
+        ]
+        .iter()
+        .map(|&(column, coltype, colflags)| Column {
+            table: String::from(""),
+            column: String::from(column),
+            coltype,
+            colflags,
+        })
+        .collect();
+
+        let mut writer = results.start(&cols)?;
+        for &(kind, ref message) in &self.warnings {
+            writer.write_col("Warning")?;
+            writer.write_col(kind as u32)?;
+            writer.write_col(message.as_str())?;
+            writer.end_row()?;
+        }
+        writer.finish()
+    }
+
+    async fn handle_create_table<W: AsyncWrite + Unpin + Send>(
         &mut self,
         q: nom_sql::CreateTableStatement,
-        results: QueryResultWriter<W>,
+        query: &str,
+        results: QueryResultWriter<'_, W>,
     ) -> io::Result<()> {
         // TODO(malte): we should perhaps check our usual caches here, rather than just blindly
         // doing a migration on Noria ever time. On the other hand, CREATE TABLE is rare...
 
+        // the recipe gets the statement as the client wrote it: nom-sql does not print every
+        // column type in a form it can parse again (e.g., ENUM)
         info!(table = %q.table.name, "table::create");
-        match block_on!(
-            self.inner,
-            self.inner.noria.extend_recipe(&format!("{};", q))
-        ) {
-            Ok(_) => {
-                // no rows to return
-                // TODO(malte): potentially eagerly cache the mutator for this table
-                trace!("table::created");
-                results.completed(0, 0)
-            }
-            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.compat())),
-        }
+        let recipe = format!("{};", query.trim().trim_end_matches(';'));
+        try_or_report!(results, self.inner.extend_recipe(&recipe).await);
+
+        // no rows to return
+        // TODO(malte): potentially eagerly cache the mutator for this table
+        self.inner.invalidate(&q.table.name);
+        trace!("table::created");
+        results.completed(0, 0)
     }
 
-    fn handle_create_view<W: io::Write>(
+    async fn handle_create_view<W: AsyncWrite + Unpin + Send>(
         &mut self,
         q: nom_sql::CreateViewStatement,
-        results: QueryResultWriter<W>,
+        results: QueryResultWriter<'_, W>,
     ) -> io::Result<()> {
         // TODO(malte): we should perhaps check our usual caches here, rather than just blindly
         // doing a migration on Noria every time. On the other hand, CREATE VIEW is rare...
 
         info!(%q.definition, %q.name, "view::create");
-        match block_on!(
-            self.inner,
+        try_or_report!(
+            results,
             self.inner
-                .noria
                 .extend_recipe(&format!("VIEW {}: {};", q.name, q.definition))
-        ) {
-            Ok(_) => {
-                // no rows to return
-                trace!("view::created");
-                results.completed(0, 0)
-            }
-            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.compat())),
-        }
+                .await
+        );
+
+        // no rows to return
+        self.inner.invalidate(&q.name);
+        trace!("view::created");
+        results.completed(0, 0)
     }
 
-    fn handle_delete<W: io::Write>(
+    async fn handle_delete<W: AsyncWrite + Unpin + Send>(
         &mut self,
         q: nom_sql::DeleteStatement,
-        results: QueryResultWriter<W>,
+        results: QueryResultWriter<'_, W>,
     ) -> io::Result<()> {
-        let cond = q
-            .where_clause
-            .expect("only supports DELETEs with WHERE-clauses");
-
-        // create a mutator if we don't have one for this table already
-        trace!(table = %q.table.name, "delete::access mutator");
-        let mutator = self.inner.ensure_mutator(&q.table.name);
-
-        trace!("delete::extract schema");
-        let pkey = if let Some(cts) = mutator.schema() {
-            utils::get_primary_key(cts)
-                .into_iter()
-                .map(|(_, c)| c)
-                .collect()
-        } else {
-            // cannot delete from view
-            unimplemented!();
-        };
-
-        trace!("delete::flatten conditionals");
-        match utils::flatten_conditional(&cond, &pkey) {
-            None => results.completed(0, 0),
-            Some(ref flattened) if flattened.len() == 0 => {
-                panic!("DELETE only supports WHERE-clauses on primary keys");
-            }
-            Some(flattened) => {
-                let count = flattened.len() as u64;
-                trace!("delete::execute");
-                for key in flattened {
-                    match block_on_buffer(mutator.delete(key)) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            error!(error = %e, "failed");
-                            return results.error(
-                                msql_srv::ErrorKind::ER_UNKNOWN_ERROR,
-                                format!("{:?}", e).as_bytes(),
-                            );
-                        }
-                    };
-                }
-
-                trace!("delete::done");
-                results.completed(count, 0)
-            }
-        }
+        self.do_delete(Cow::Owned(q), None, results).await
     }
 
-    fn handle_insert<W: io::Write>(
+    async fn handle_insert<W: AsyncWrite + Unpin + Send>(
         &mut self,
         mut q: nom_sql::InsertStatement,
-        results: QueryResultWriter<W>,
+        replace: bool,
+        results: QueryResultWriter<'_, W>,
     ) -> io::Result<()> {
         let table = &q.table.name;
 
         // create a mutator if we don't have one for this table already
         trace!(%table, "query::insert::access mutator");
-        let putter = self.inner.ensure_mutator(table);
+        let putter = try_or_report!(results, self.inner.ensure_mutator(table).await);
         trace!("query::insert::extract schema");
-        let schema = putter
-            .schema()
-            .expect(&format!("no schema for table '{}'", table));
+        let schema = try_or_report!(
+            results,
+            putter
+                .schema()
+                .ok_or_else(|| Error::NotSupported(format!("INSERT into view '{}'", table)))
+        );
 
         // set column names (insert schema) if not set
         if q.fields.is_none() {
             q.fields = Some(schema.fields.iter().map(|cs| cs.column.clone()).collect());
         }
 
-        let data: Vec<Vec<DataType>> = q
-            .data
+        // only an EXECUTE has values for placeholders
+        if q.data
             .iter()
-            .map(|row| row.iter().map(|v| DataType::from(v)).collect())
-            .collect();
+            .flatten()
+            .any(|v| *v == nom_sql::Literal::Placeholder)
+        {
+            report!(
+                results,
+                Error::ParseError("placeholder in a query that was not prepared".into())
+            );
+        }
+        let data: Vec<Vec<DataType>> = try_or_report!(
+            results,
+            q.data
+                .iter()
+                .map(|row| row.iter().map(|v| v.clone().to_datatype()).collect())
+                .collect()
+        );
 
-        self.do_insert(&q, data, results)
+        self.do_insert(&q, data, replace, results).await
     }
 
-    fn handle_select<W: io::Write>(
+    async fn handle_insert_select<W: AsyncWrite + Unpin + Send>(
         &mut self,
-        q: nom_sql::SelectStatement,
-        use_params: Vec<Literal>,
-        results: QueryResultWriter<W>,
+        mut q: nom_sql::InsertStatement,
+        select: &str,
+        replace: bool,
+        results: QueryResultWriter<'_, W>,
     ) -> io::Result<()> {
-        trace!("query::select::access view");
-        let qname = match self.get_or_create_view(&q, false) {
-            Ok(qn) => qn,
-            Err(e) => {
-                error!(error = ?e, "failed to parse");
-                if e.kind() == io::ErrorKind::Other {
-                    // maybe ER_SYNTAX_ERROR ?
-                    // would be good to narrow these down
-                    // TODO: why are the actual error contents never printed?
-                    return results.error(
-                        msql_srv::ErrorKind::ER_UNKNOWN_ERROR,
-                        e.to_string().as_bytes(),
-                    );
-                } else {
-                    return Err(e);
-                }
-            }
+        // set column names (insert schema) if not set
+        if q.fields.is_none() {
+            let table = &q.table.name;
+            trace!(%table, "query::insert-select::access mutator");
+            let putter = try_or_report!(results, self.inner.ensure_mutator(table).await);
+            let schema = try_or_report!(
+                results,
+                putter
+                    .schema()
+                    .ok_or_else(|| Error::NotSupported(format!("INSERT into view '{}'", table)))
+            );
+            q.fields = Some(schema.fields.iter().map(|cs| cs.column.clone()).collect());
+        }
+
+        trace!("query::insert-select::parse select");
+        let mut sq = match nom_sql::parse_query(select) {
+            Ok(sq @ SqlQuery::Select(_)) => sq,
+            Ok(_) => report!(
+                results,
+                Error::NotSupported("INSERT ... SELECT with a compound SELECT".into())
+            ),
+            Err(e) => report!(results, Error::ParseError(e.to_owned())),
+        };
+        try_or_report!(results, self.fetch_endpoints(sq.referred_tables()).await);
+        try_or_report!(results, rewrite::push_down_negations(&mut sq));
+        let (keys, collapsed) = try_or_report!(results, rewrite::parameterize_query(&mut sq));
+        let mut sq = match sq {
+            SqlQuery::Select(sq) => sq,
+            _ => unreachable!(),
+        };
+        let (merge, mut hidden) = if collapsed {
+            let merge = utils::ResultMerge::for_query(&sq);
+            (merge, rewrite::prepare_reaggregation(&mut sq))
+        } else {
+            (utils::ResultMerge::default(), vec![])
         };
+        hidden.extend(rewrite::unselected_lookup_columns(&sq));
+
+        // read the rows to insert through a view, just like a SELECT on its own would
+        trace!("query::insert-select::read");
+        let qname = try_or_report!(results, self.get_or_create_view(&sq, false).await);
+        let mut schema = try_or_report!(results, self.view_schema(&qname).await);
+        schema.retain(|c| !hidden.contains(&c.column));
+        let data = try_or_report!(
+            results,
+            self.read_rows(&qname, &sq, keys, &schema, &[], &merge)
+                .await
+        );
 
-        let keys: Vec<_> = use_params
-            .into_iter()
-            .map(|l| vec![l.to_datatype()])
-            .collect();
+        self.do_insert(&q, data, replace, results).await
+    }
+
+    async fn handle_select<W: AsyncWrite + Unpin + Send>(
+        &mut self,
+        mut q: nom_sql::SelectStatement,
+        keys: Vec<Vec<DataType>>,
+        collapsed: bool,
+        results: QueryResultWriter<'_, W>,
+    ) -> io::Result<()> {
+        // aggregates over several lookups have to be combined by the adapter; the view is the
+        // same however many keys there are, so that all queries of this shape can share it
+        let (merge, mut hidden) = if collapsed {
+            let merge = utils::ResultMerge::for_query(&q);
+            (merge, rewrite::prepare_reaggregation(&mut q))
+        } else {
+            (utils::ResultMerge::default(), vec![])
+        };
+        hidden.extend(rewrite::unselected_lookup_columns(&q));
+
+        trace!("query::select::access view");
+        let qname = try_or_report!(results, self.get_or_create_view(&q, false).await);
 
         // we need the schema for the result writer
         trace!(%qname, "query::select::extract schema");
-        let schema = schema::convert_schema(&Schema::View(
-            self.inner
-                .ensure_getter(&qname)
-                .schema()
-                .expect(&format!("no schema for view '{}'", qname))
-                .iter()
-                .cloned()
-                .filter(|c| c.column.name != "bogokey")
-                .collect(),
-        ));
+        let mut schema = try_or_report!(results, self.view_schema(&qname).await);
+        schema.retain(|c| !hidden.contains(&c.column));
 
         trace!(%qname, "query::select::do");
-        self.do_read(&qname, keys, schema.as_slice(), results)
+        self.do_read(&qname, &q, keys, schema.as_slice(), &[], &merge, results)
+            .await
     }
 
-    fn handle_set<W: io::Write>(
+    async fn handle_set<W: AsyncWrite + Unpin + Send>(
         &mut self,
         q: nom_sql::SetStatement,
-        results: QueryResultWriter<W>,
+        results: QueryResultWriter<'_, W>,
     ) -> io::Result<()> {
         trace!(%q.variable, "set");
 
         if q.variable == "@primed" {
             self.primed.store(true, atomic::Ordering::SeqCst);
         }
+
+        let variable = q.variable.to_lowercase();
+        let variable = variable.trim_start_matches('@');
+        if variable == "autocommit" || variable == "session.autocommit" {
+            let autocommit = match q.value {
+                nom_sql::Literal::Integer(i) => i != 0,
+                nom_sql::Literal::UnsignedInteger(i) => i != 0,
+                nom_sql::Literal::String(ref s) => s.eq_ignore_ascii_case("on") || s == "1",
+                _ => {
+                    return results.error(
+                        msql_srv::ErrorKind::ER_WRONG_VALUE_FOR_VAR,
+                        format!("invalid value for autocommit: {}", q.value.to_string()).as_bytes(),
+                    );
+                }
+            };
+            trace!(autocommit, "transaction::autocommit");
+            if autocommit && !self.autocommit {
+                // switching autocommit on commits any open transaction, and only takes effect
+                // once it has
+                try_or_report!(results, self.commit().await);
+                self.in_transaction = false;
+            }
+            self.autocommit = autocommit;
+        }
+
         // ignore
         results.completed(0, 0)
     }
 
-    fn handle_update<W: io::Write>(
+    async fn handle_update<W: AsyncWrite + Unpin + Send>(
         &mut self,
         q: nom_sql::UpdateStatement,
-        results: QueryResultWriter<W>,
+        results: QueryResultWriter<'_, W>,
     ) -> io::Result<()> {
-        self.do_update(Cow::Owned(q), None, results)
+        self.do_update(Cow::Owned(q), None, results).await
     }
 
-    fn prepare_insert<W: io::Write>(
+    async fn prepare_insert<W: AsyncWrite + Unpin + Send>(
         &mut self,
         mut sql_q: nom_sql::SqlQuery,
-        info: StatementMetaWriter<W>,
+        replace: bool,
+        info: StatementMetaWriter<'_, W>,
     ) -> io::Result<()> {
         let q = if let nom_sql::SqlQuery::Insert(ref q) = sql_q {
             q
@@ -420,45 +1309,49 @@ impl NoriaBackend {
         };
 
         trace!(table = %q.table.name, "insert::access mutator");
-        let mutator = self.inner.ensure_mutator(&q.table.name);
+        let mutator = try_or_report!(info, self.inner.ensure_mutator(&q.table.name).await);
         trace!("insert::extract schema");
-        let schema = schema::convert_schema(&Schema::Table(mutator.schema().unwrap().clone()));
+        let table_schema = try_or_report!(
+            info,
+            mutator
+                .schema()
+                .cloned()
+                .ok_or_else(|| Error::NotSupported(format!("INSERT into view '{}'", q.table.name)))
+        );
+        let schema = schema::convert_schema(&Schema::Table(table_schema.clone()));
 
-        match sql_q {
-            // set column names (insert schema) if not set
-            nom_sql::SqlQuery::Insert(ref mut q) => {
-                if q.fields.is_none() {
-                    q.fields = Some(
-                        mutator
-                            .schema()
-                            .as_ref()
-                            .unwrap()
-                            .fields
-                            .iter()
-                            .map(|cs| cs.column.clone())
-                            .collect(),
-                    );
-                }
+        // set column names (insert schema) if not set
+        if let nom_sql::SqlQuery::Insert(ref mut q) = sql_q {
+            if q.fields.is_none() {
+                q.fields = Some(
+                    table_schema
+                        .fields
+                        .iter()
+                        .map(|cs| cs.column.clone())
+                        .collect(),
+                );
             }
-            _ => (),
         }
 
         let params: Vec<_> = {
             // extract parameter columns -- easy here, since they must all be in the same table
-            let param_cols = utils::get_parameter_columns(&sql_q);
-            param_cols
-                .into_iter()
-                .map(|c| {
-                    //let mut cc = c.clone();
-                    //cc.table = Some(q.table.name.clone());
-                    //schema_for_column(table_schemas, &cc)
-                    schema
-                        .iter()
-                        .cloned()
-                        .find(|mc| c.name == mc.column)
-                        .expect(&format!("column '{}' missing in mutator schema", c))
-                })
-                .collect()
+            let param_cols = try_or_report!(info, utils::get_parameter_columns(&sql_q));
+            try_or_report!(
+                info,
+                param_cols
+                    .into_iter()
+                    .map(|c| {
+                        //let mut cc = c.clone();
+                        //cc.table = Some(q.table.name.clone());
+                        //schema_for_column(table_schemas, &cc)
+                        schema
+                            .iter()
+                            .cloned()
+                            .find(|mc| c.name == mc.column)
+                            .ok_or_else(|| Error::BadField(c.name.clone()))
+                    })
+                    .collect::<Result<_, _>>()
+            )
         };
 
         self.prepared_count += 1;
@@ -473,129 +1366,269 @@ impl NoriaBackend {
             unreachable!()
         };
         self.prepared
-            .insert(self.prepared_count, PreparedStatement::Insert(q));
+            .insert(self.prepared_count, PreparedStatement::Insert(q, replace));
 
         trace!(id = self.prepared_count, "insert::registered");
 
         Ok(())
     }
 
-    fn get_or_create_view(
+    async fn get_or_create_view(
         &mut self,
         q: &nom_sql::SelectStatement,
         prepared: bool,
-    ) -> io::Result<String> {
-        let qname = match self.tl_cached.get(q) {
-            None => {
-                // check global cache
-                let gc = self.cached.read().unwrap();
-                let qname = match gc.get(q) {
-                    Some(qname) => qname.clone(),
-                    None => {
-                        drop(gc);
-                        let mut gc = self.cached.write().unwrap();
-                        if let Some(qname) = gc.get(q) {
-                            qname.clone()
-                        } else {
-                            let qh = utils::hash_select_query(q);
-                            let qname = format!("q_{:x}", qh);
-
-                            // add the query to Noria
-                            if prepared {
-                                info!(query = %q, name = %qname, "adding parameterized query");
-                            } else {
-                                info!(query = %q, name = %qname, "adding ad-hoc query");
-                            }
-                            if let Err(e) = block_on!(
-                                self.inner,
-                                self.inner
-                                    .noria
-                                    .extend_recipe(&format!("QUERY {}: {};", qname, q))
-                            ) {
-                                error!(error = %e, "add query failed");
-                                return Err(io::Error::new(io::ErrorKind::Other, e.compat()));
-                            }
-
-                            gc.insert(q.clone(), qname.clone());
-                            qname
-                        }
-                    }
-                };
+    ) -> Result<String, Error> {
+        if let Some(qname) = self.tl_cached.get(q) {
+            return Ok(qname.to_owned());
+        }
 
-                self.tl_cached.insert(q.clone(), qname.clone());
+        // check global cache, and start the migration ourselves if nobody else has
+        let pending = {
+            let mut gc = self.cached.views.write().unwrap();
+            match gc.get(q) {
+                Some(CachedView::Ready(qname)) => {
+                    let qname = qname.clone();
+                    drop(gc);
+                    self.tl_cached.insert(q.clone(), qname.clone());
+                    return Ok(qname);
+                }
+                Some(CachedView::Pending(pending)) => {
+                    trace!("waiting for in-flight migration");
+                    pending.clone()
+                }
+                None => {
+                    let qh = utils::hash_select_query(q);
+                    let qname = format!("q_{:x}", qh);
 
-                qname
+                    // add the query to Noria
+                    if prepared {
+                        info!(query = %q, name = %qname, "adding parameterized query");
+                    } else {
+                        info!(query = %q, name = %qname, "adding ad-hoc query");
+                    }
+                    let pending = self.start_migration(q.clone(), qname);
+                    gc.insert(q.clone(), CachedView::Pending(pending.clone()));
+                    pending
+                }
             }
-            Some(qname) => qname.to_owned(),
         };
+
+        let res = match self.view_timeout {
+            None => pending.await,
+            Some(t) => match tokio::time::timeout(t, pending).await {
+                Ok(res) => res,
+                Err(_) => return Err(Error::ViewPending),
+            },
+        };
+
+        let qname = res.map_err(Error::Noria)?;
+        self.tl_cached.insert(q.clone(), qname.clone());
         Ok(qname)
     }
 
-    fn prepare_select<W: io::Write>(
+    /// Add a view for `q` to Noria in the background.
+    ///
+    /// The migration runs to completion even if every connection waiting for it gives up, and
+    /// records its outcome in the global query cache when it finishes.
+    fn start_migration(&self, q: nom_sql::SelectStatement, qname: String) -> PendingView {
+        let mut noria = self.inner.noria.clone();
+        let cache = self.cached.clone();
+        let recipe = format!("QUERY {}: {};", qname, q);
+
+        tokio::spawn(
+            async move {
+                let res: Result<(), failure::Error> = async {
+                    noria.ready().await?;
+                    noria.extend_recipe(&recipe).await?;
+                    Ok(())
+                }
+                .await;
+
+                let mut gc = cache.views.write().unwrap();
+                match res {
+                    Ok(_) => {
+                        gc.insert(q, CachedView::Ready(qname.clone()));
+                        Ok(qname)
+                    }
+                    Err(e) => {
+                        error!(error = %e, "add query failed");
+                        // let the next request for this query try again
+                        gc.remove(&q);
+                        Err(e.to_string())
+                    }
+                }
+            }
+            .in_current_span(),
+        )
+        .map(|r| r.unwrap_or_else(|e| Err(format!("migration task failed: {}", e))))
+        .boxed()
+        .shared()
+    }
+
+    async fn prepare_select<W: AsyncWrite + Unpin + Send>(
         &mut self,
         mut sql_q: nom_sql::SqlQuery,
-        info: StatementMetaWriter<W>,
+        info: StatementMetaWriter<'_, W>,
     ) -> io::Result<()> {
         // extract parameter columns
         // note that we have to do this *before* collapsing WHERE IN, otherwise the
         // client will be confused about the number of parameters it's supposed to
         // give.
-        let params: Vec<nom_sql::Column> = utils::get_parameter_columns(&sql_q)
-            .into_iter()
-            .cloned()
-            .collect();
+        let params: Vec<nom_sql::Column> =
+            try_or_report!(info, utils::get_parameter_columns(&sql_q))
+                .into_iter()
+                .cloned()
+                .collect();
+
+        // Noria only looks up by equality, so the adapter filters by the other comparisons
+        trace!("select::extract filters");
+        let (filters, mut hidden) = match sql_q {
+            nom_sql::SqlQuery::Select(ref mut q) => {
+                try_or_report!(info, rewrite::extract_post_filters(q))
+            }
+            _ => unreachable!(),
+        };
 
         trace!("select::collapse where-in clauses");
-        let rewritten = rewrite::collapse_where_in(&mut sql_q, false);
+        let rewritten = try_or_report!(info, rewrite::collapse_where_in(&mut sql_q, false));
         let q = if let nom_sql::SqlQuery::Select(q) = sql_q {
             q
         } else {
             unreachable!();
         };
 
+        // aggregates over several lookups have to be combined by the adapter; the prepared
+        // statement keeps the original query, which says how.
+        let mut view_q = q.clone();
+        if !rewritten.is_empty() {
+            hidden.extend(rewrite::prepare_reaggregation(&mut view_q));
+        }
+        hidden.extend(rewrite::unselected_lookup_columns(&view_q));
+
         // check if we already have this query prepared
         trace!("select::access view");
-        let qname = self.get_or_create_view(&q, true)?;
+        let qname = try_or_report!(info, self.get_or_create_view(&view_q, true).await);
 
         // extract result schema
         trace!(qname = %qname, "select::extract schema");
-        let schema = Schema::View(
-            self.inner
-                .ensure_getter(&qname)
+        let getter = try_or_report!(info, self.inner.ensure_getter(&qname).await);
+        let schema = Schema::View(try_or_report!(
+            info,
+            getter
                 .schema()
-                .expect(&format!("no schema for view '{}'", qname))
-                .to_vec(),
-        );
+                .map(|s| s.to_vec())
+                .ok_or_else(|| Error::Noria(format!("no schema for view '{}'", qname)))
+        ));
 
         // now convert params to msql_srv types; we have to do this here because we don't have
-        // access to the schema yet when we extract them above.
-        let params: Vec<msql_srv::Column> = params
+        // access to the schema yet when we extract them above. the column a filter compares need
+        // not be selected, but its values are in the filter's hidden column. a lookup column may
+        // be selected under another name, so the base table has the last word.
+        let mut param_columns = Vec::with_capacity(params.len());
+        for (i, c) in params.iter().enumerate() {
+            let name = match filters.iter().find(|f| f.param == i) {
+                Some(f) => f.column.clone(),
+                None => c.name.clone(),
+            };
+            let vc = nom_sql::Column {
+                name,
+                table: Some(qname.clone()),
+                ..c.clone()
+            };
+            let column = match (schema_for_column(&schema, &vc), &c.table) {
+                (Ok(column), _) => column,
+                (Err(_), Some(table)) => {
+                    let mutator = try_or_report!(info, self.inner.ensure_mutator(table).await);
+                    let table_schema = try_or_report!(
+                        info,
+                        mutator
+                            .schema()
+                            .cloned()
+                            .ok_or_else(|| Error::BadField(c.name.clone()))
+                    );
+                    try_or_report!(info, schema_for_column(&Schema::Table(table_schema), c))
+                }
+                (Err(e), None) => report!(info, e),
+            };
+            param_columns.push(column);
+        }
+        let params = param_columns;
+        // columns that are only there for the filters or lookups are not part of the result
+        let schema: Vec<_> = schema::convert_schema(&schema)
             .into_iter()
-            .map(|mut c| {
-                c.table = Some(qname.clone());
-                schema_for_column(&schema, &c)
+            .filter(|c| c.column != "bogokey" && !hidden.contains(&c.column))
+            .collect();
+
+        self.prepared_count += 1;
+        info.reply(self.prepared_count, params.as_slice(), schema.as_slice())?;
+
+        // register a new prepared statement
+        self.prepared.insert(
+            self.prepared_count,
+            PreparedStatement::Select(qname, q, schema, rewritten, filters),
+        );
+
+        trace!(id = self.prepared_count, "select::registered");
+
+        Ok(())
+    }
+
+    async fn prepare_delete<W: AsyncWrite + Unpin + Send>(
+        &mut self,
+        sql_q: nom_sql::SqlQuery,
+        info: StatementMetaWriter<'_, W>,
+    ) -> io::Result<()> {
+        let q = if let nom_sql::SqlQuery::Delete(ref q) = sql_q {
+            q
+        } else {
+            unreachable!()
+        };
+
+        trace!(table = %q.table.name, "delete::access mutator");
+        let mutator = try_or_report!(info, self.inner.ensure_mutator(&q.table.name).await);
+        trace!("delete::extract schema");
+        let schema = Schema::Table(try_or_report!(
+            info,
+            mutator
+                .schema()
+                .cloned()
+                .ok_or_else(|| Error::NotSupported(format!("DELETE from view '{}'", q.table.name)))
+        ));
+
+        // extract parameter columns
+        let params: Vec<msql_srv::Column> = try_or_report!(
+            info,
+            utils::get_parameter_columns(&sql_q).and_then(|cols| {
+                cols.into_iter()
+                    .map(|c| {
+                        let mut c = c.clone();
+                        c.table = Some(q.table.name.clone());
+                        schema_for_column(&schema, &c)
+                    })
+                    .collect()
             })
-            .collect();
-        let schema = schema::convert_schema(&schema);
+        );
 
-        self.prepared_count += 1;
-        info.reply(self.prepared_count, params.as_slice(), schema.as_slice())?;
+        let q = if let nom_sql::SqlQuery::Delete(q) = sql_q {
+            q
+        } else {
+            unreachable!();
+        };
 
         // register a new prepared statement
-        self.prepared.insert(
-            self.prepared_count,
-            PreparedStatement::Select(qname, q, schema, rewritten.map(|(a, b)| (a, b.len()))),
-        );
+        self.prepared_count += 1;
+        self.prepared
+            .insert(self.prepared_count, PreparedStatement::Delete(q));
 
-        trace!(id = self.prepared_count, "select::registered");
+        trace!(id = self.prepared_count, "delete::registered");
 
-        Ok(())
+        info.reply(self.prepared_count, &params[..], &[])
     }
 
-    fn prepare_update<W: io::Write>(
+    async fn prepare_update<W: AsyncWrite + Unpin + Send>(
         &mut self,
         sql_q: nom_sql::SqlQuery,
-        info: StatementMetaWriter<W>,
+        info: StatementMetaWriter<'_, W>,
     ) -> io::Result<()> {
         let q = if let nom_sql::SqlQuery::Update(ref q) = sql_q {
             q
@@ -604,17 +1637,25 @@ impl NoriaBackend {
         };
 
         trace!(table = %q.table.name, "update::access mutator");
-        let mutator = self.inner.ensure_mutator(&q.table.name);
+        let mutator = try_or_report!(info, self.inner.ensure_mutator(&q.table.name).await);
         trace!("update::extract schema");
-        let schema = Schema::Table(mutator.schema().unwrap().clone());
+        let schema = Schema::Table(try_or_report!(
+            info,
+            mutator
+                .schema()
+                .cloned()
+                .ok_or_else(|| Error::NotSupported(format!("UPDATE of view '{}'", q.table.name)))
+        ));
 
         // extract parameter columns
-        let params: Vec<msql_srv::Column> = {
-            utils::get_parameter_columns(&sql_q)
-                .into_iter()
-                .map(|c| schema_for_column(&schema, c))
-                .collect()
-        };
+        let params: Vec<msql_srv::Column> = try_or_report!(
+            info,
+            utils::get_parameter_columns(&sql_q).and_then(|cols| {
+                cols.into_iter()
+                    .map(|c| schema_for_column(&schema, c))
+                    .collect()
+            })
+        );
 
         // must have an update query
         let q = if let nom_sql::SqlQuery::Update(q) = sql_q {
@@ -633,51 +1674,81 @@ impl NoriaBackend {
         info.reply(self.prepared_count, &params[..], &[])
     }
 
-    fn execute_insert<W: io::Write>(
+    async fn execute_insert<W: AsyncWrite + Unpin + Send>(
         &mut self,
         q: &InsertStatement,
         data: Vec<DataType>,
-        results: QueryResultWriter<W>,
+        replace: bool,
+        results: QueryResultWriter<'_, W>,
     ) -> io::Result<()> {
-        assert_eq!(q.fields.as_ref().unwrap().len(), data.len());
-        self.do_insert(&q, vec![data], results)
+        // the parameters of all rows arrive as one list, in the order of the placeholders
+        let mut params = data.into_iter();
+        let mut rows = Vec::with_capacity(q.data.len());
+        for row in &q.data {
+            let mut r = Vec::with_capacity(row.len());
+            for v in row {
+                if *v == nom_sql::Literal::Placeholder {
+                    match params.next() {
+                        Some(p) => r.push(p),
+                        None => report!(results, Error::WrongArguments),
+                    }
+                } else {
+                    r.push(try_or_report!(results, v.clone().to_datatype()));
+                }
+            }
+            rows.push(r);
+        }
+        if params.next().is_some() {
+            report!(results, Error::WrongArguments);
+        }
+
+        self.do_insert(q, rows, replace, results).await
     }
 
-    fn execute_select<W: io::Write>(
+    #[allow(clippy::too_many_arguments)]
+    async fn execute_select<W: AsyncWrite + Unpin + Send>(
         &mut self,
         qname: &str,
+        q: &SelectStatement,
         keys: Vec<Vec<DataType>>,
         schema: &[msql_srv::Column],
-        results: QueryResultWriter<W>,
+        filters: &[(&rewrite::PostFilter, DataType)],
+        merge: &utils::ResultMerge,
+        results: QueryResultWriter<'_, W>,
+    ) -> io::Result<()> {
+        self.do_read(qname, q, keys, schema, filters, merge, results)
+            .await
+    }
+
+    async fn execute_delete<W: AsyncWrite + Unpin + Send>(
+        &mut self,
+        q: &DeleteStatement,
+        params: ParamParser<'_>,
+        results: QueryResultWriter<'_, W>,
     ) -> io::Result<()> {
-        self.do_read(qname, keys, schema, results)
+        self.do_delete(Cow::Borrowed(q), Some(params), results)
+            .await
     }
 
-    fn execute_update<W: io::Write>(
+    async fn execute_update<W: AsyncWrite + Unpin + Send>(
         &mut self,
         q: &UpdateStatement,
-        params: ParamParser,
-        results: QueryResultWriter<W>,
+        params: ParamParser<'_>,
+        results: QueryResultWriter<'_, W>,
     ) -> io::Result<()> {
         self.do_update(Cow::Borrowed(q), Some(params), results)
+            .await
     }
 
-    fn do_insert<W: io::Write>(
+    async fn do_insert<W: AsyncWrite + Unpin + Send>(
         &mut self,
         q: &InsertStatement,
         data: Vec<Vec<DataType>>,
-        results: QueryResultWriter<W>,
+        replace: bool,
+        results: QueryResultWriter<'_, W>,
     ) -> io::Result<()> {
         let table = &q.table.name;
 
-        // create a mutator if we don't have one for this table already
-        trace!(%table, "insert::access mutator");
-        let putter = self.inner.ensure_mutator(table);
-        trace!("insert::extract schema");
-        let schema = putter
-            .schema()
-            .expect(&format!("no schema for table '{}'", table));
-
         let columns_specified: Vec<_> = q
             .fields
             .as_ref()
@@ -692,40 +1763,77 @@ impl NoriaBackend {
 
         // handle auto increment
         trace!("insert::auto-increment");
-        let auto_increment_columns: Vec<_> = schema
-            .fields
-            .iter()
-            .filter(|c| c.constraints.contains(&ColumnConstraint::AutoIncrement))
-            .collect();
-        // can only have zero or one AUTO_INCREMENT columns
-        assert!(auto_increment_columns.len() <= 1);
-
-        {
-            let ai_lock = self.auto_increments.read().unwrap();
-            if ai_lock.get(table).is_none() {
-                drop(ai_lock);
-                self.auto_increments
-                    .write()
-                    .unwrap()
-                    .entry(table.to_owned())
-                    .or_insert(atomic::AtomicUsize::new(0));
+        let auto_increment_column = {
+            // create a mutator if we don't have one for this table already
+            trace!(%table, "insert::access mutator");
+            let mutator = try_or_report!(results, self.inner.ensure_mutator(table).await);
+            let schema = try_or_report!(
+                results,
+                mutator
+                    .schema()
+                    .ok_or_else(|| Error::NotSupported(format!("INSERT into view '{}'", table)))
+            );
+            let auto_increment_columns: Vec<_> = schema
+                .fields
+                .iter()
+                .filter(|c| c.constraints.contains(&ColumnConstraint::AutoIncrement))
+                .map(|c| c.column.clone())
+                .collect();
+            // can only have zero or one AUTO_INCREMENT columns
+            if auto_increment_columns.len() > 1 {
+                report!(
+                    results,
+                    Error::NotSupported(format!("several AUTO_INCREMENT columns in '{}'", table))
+                );
             }
+            auto_increment_columns.into_iter().next()
+        };
+        if let Some(ref col) = auto_increment_column {
+            try_or_report!(results, self.seed_auto_increment(table, col).await);
+        }
+        let ids = match auto_increment_column {
+            Some(ref col) => match columns_specified.iter().position(|c| c == col) {
+                // query can specify an explicit AUTO_INCREMENT value, which later ids follow
+                Some(ci) => {
+                    let explicit = data
+                        .iter()
+                        .filter_map(|row| row.get(ci).and_then(utils::auto_increment_id))
+                        .max();
+                    if let Some(id) = explicit {
+                        if let Err(e) = self.auto_increments.advance(table, id).await {
+                            report!(results, Error::AutoIncrement(e.to_string()));
+                        }
+                    }
+                    Vec::new()
+                }
+                None => match self.auto_increments.allocate(table, data.len()).await {
+                    Ok(ids) => ids,
+                    Err(e) => report!(results, Error::AutoIncrement(e.to_string())),
+                },
+            },
+            None => Vec::new(),
         };
-        let ai_lock = self.auto_increments.read().unwrap();
-        let last_insert_id = &ai_lock[table];
+        let first_inserted_id = ids.first().cloned();
+
+        let putter = try_or_report!(results, self.inner.ensure_mutator(table).await);
+        trace!("insert::extract schema");
+        let schema = try_or_report!(
+            results,
+            putter
+                .schema()
+                .cloned()
+                .ok_or_else(|| Error::NotSupported(format!("INSERT into view '{}'", table)))
+        );
 
         // handle default values
         trace!("insert::default values");
-        let mut default_value_columns: Vec<_> = schema
+        let default_value_columns: Vec<_> = schema
             .fields
             .iter()
-            .filter_map(|ref c| {
+            .filter_map(|c| {
                 for cc in &c.constraints {
-                    match *cc {
-                        ColumnConstraint::DefaultValue(ref v) => {
-                            return Some((c.column.clone(), v.clone()))
-                        }
-                        _ => (),
+                    if let ColumnConstraint::DefaultValue(ref v) = *cc {
+                        return Some((c.column.clone(), v.clone()));
                     }
                 }
                 None
@@ -734,44 +1842,116 @@ impl NoriaBackend {
 
         trace!("insert::construct ops");
         let mut buf = vec![vec![DataType::None; schema.fields.len()]; data.len()];
+        let columns: Vec<_> = schema.fields.iter().map(schema::convert_column).collect();
 
-        let mut first_inserted_id = None;
-        for (ri, ref row) in data.iter().enumerate() {
-            if let Some(col) = auto_increment_columns.iter().next() {
-                let idx = schema
-                    .fields
-                    .iter()
-                    .position(|f| f == *col)
-                    .expect(&format!("no column named '{}'", col.column.name));
-                // query can specify an explicit AUTO_INCREMENT value
-                if !columns_specified.contains(&col.column) {
-                    let id = last_insert_id.fetch_add(1, atomic::Ordering::SeqCst) as i64 + 1;
-                    if first_inserted_id.is_none() {
-                        first_inserted_id = Some(id);
-                    }
-                    buf[ri][idx] = DataType::from(id);
-                }
+        for (ri, row) in data.iter().enumerate() {
+            if row.len() != columns_specified.len() {
+                report!(results, Error::WrongValueCount(ri + 1));
             }
 
-            for (c, v) in default_value_columns.drain(..) {
-                let idx = schema
-                    .fields
-                    .iter()
-                    .position(|f| f.column == c)
-                    .expect(&format!("no column named '{}'", c.name));
+            if let (Some(col), Some(&id)) = (&auto_increment_column, ids.get(ri)) {
+                let idx = try_or_report!(
+                    results,
+                    schema
+                        .fields
+                        .iter()
+                        .position(|f| f.column == *col)
+                        .ok_or_else(|| Error::BadField(col.name.clone()))
+                );
+                buf[ri][idx] = DataType::from(id as i64);
+            }
+
+            for (c, v) in &default_value_columns {
+                let idx = try_or_report!(
+                    results,
+                    schema
+                        .fields
+                        .iter()
+                        .position(|f| f.column == *c)
+                        .ok_or_else(|| Error::BadField(c.name.clone()))
+                );
                 // only use default value if query doesn't specify one
-                if !columns_specified.contains(&c) {
-                    buf[ri][idx] = v.into();
+                if !columns_specified.contains(c) {
+                    buf[ri][idx] = try_or_report!(results, v.clone().to_datatype());
                 }
             }
 
             for (ci, c) in columns_specified.iter().enumerate() {
-                let idx = schema
-                    .fields
-                    .iter()
-                    .position(|f| f.column == *c)
-                    .expect(&format!("no column '{:?}' in table '{}'", c, schema.table));
-                buf[ri][idx] = row.get(ci).unwrap().clone();
+                let idx = try_or_report!(
+                    results,
+                    schema
+                        .fields
+                        .iter()
+                        .position(|f| f.column == *c)
+                        .ok_or_else(|| Error::BadField(c.name.clone()))
+                );
+                try_or_report!(results, utils::check_range(&row[ci], &columns[idx]));
+                buf[ri][idx] = row[ci].clone();
+            }
+        }
+
+        let pkey = utils::get_primary_key(&schema);
+        let key_of = |row: &[DataType]| -> Vec<DataType> {
+            pkey.iter().map(|&(i, _)| row[i].clone()).collect()
+        };
+        let key_string = |key: &[DataType]| -> String {
+            key.iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join("-")
+        };
+
+        // Noria does not enforce primary keys, so we look up which keys are already taken: REPLACE
+        // overwrites those rows, INSERT IGNORE skips them, ON DUPLICATE KEY UPDATE updates them,
+        // and a plain INSERT fails. This sees the writes the open transaction holds back, but
+        // may miss a row written just now that the view has not caught up with yet.
+        let mut existing: HashMap<Vec<DataType>, Vec<DataType>> = if !pkey.is_empty() {
+            trace!("insert::read existing keys");
+            let columns: Vec<_> = pkey.iter().map(|&(_, c)| c.clone()).collect();
+            let keys = buf.iter().map(|row| key_of(row)).collect();
+            let rows = try_or_report!(
+                results,
+                self.rows_for_keys(table, &schema, &columns, keys).await
+            );
+            rows.into_iter().map(|row| (key_of(&row), row)).collect()
+        } else {
+            HashMap::new()
+        };
+        let mut seen: HashSet<Vec<DataType>> = existing.keys().cloned().collect();
+
+        let mut affected = buf.len() as u64;
+        if replace && !pkey.is_empty() {
+            // like in MySQL, a row that replaces another one counts twice
+            for row in &buf {
+                if !seen.insert(key_of(row)) {
+                    affected += 1;
+                }
+            }
+        } else if q.ignore && !pkey.is_empty() {
+            // like MySQL, report the skipped rows as warnings rather than as an error
+            let mut kept = Vec::with_capacity(buf.len());
+            for row in buf {
+                let key = key_of(&row);
+                if seen.insert(key.clone()) {
+                    kept.push(row);
+                } else {
+                    trace!(%table, key = %key_string(&key), "insert::ignore duplicate");
+                    self.warnings.push((
+                        ErrorKind::ER_DUP_ENTRY,
+                        format!("Duplicate entry '{}' for key 'PRIMARY'", key_string(&key)),
+                    ));
+                }
+            }
+            buf = kept;
+            affected = buf.len() as u64;
+        } else if q.on_duplicate.is_none() && !pkey.is_empty() {
+            // like MySQL, reject the whole statement, whether a key is taken by a row in the table
+            // or by an earlier row of the statement
+            for row in &buf {
+                let key = key_of(row);
+                if !seen.insert(key.clone()) {
+                    report!(results, Error::DupEntry(key_string(&key)));
+                }
             }
         }
 
@@ -784,10 +1964,8 @@ impl NoriaBackend {
             noria::trace_my_next_op();
         }
 
-        let result = if let Some(ref update_fields) = q.on_duplicate {
+        let ops: Vec<_> = if let Some(ref update_fields) = q.on_duplicate {
             trace!("insert::complex");
-            assert_eq!(buf.len(), 1);
-
             let updates = {
                 // fake out an update query
                 let mut uq = UpdateStatement {
@@ -795,55 +1973,100 @@ impl NoriaBackend {
                     fields: update_fields.clone(),
                     where_clause: None,
                 };
-                utils::extract_update_params_and_fields(&mut uq, &mut None, schema)
+                try_or_report!(
+                    results,
+                    utils::extract_update_params_and_fields(&mut uq, &mut None, &schema)
+                )
             };
 
-            // TODO(malte): why can't I consume buf here?
-            let r = block_on_buffer(putter.insert_or_update(buf[0].clone(), updates));
-            trace!("insert::complex::complete");
-            r
+            let update = utils::dense_modifications(schema.fields.len(), updates);
+            if !pkey.is_empty() {
+                // like MySQL, an inserted row counts once, and an updated row twice. a row that
+                // the update leaves as it was only counts if the client asked for found rows.
+                affected = 0;
+                for row in &buf {
+                    match existing.get_mut(&key_of(row)) {
+                        Some(current) if utils::changes_row(current, &update) => {
+                            utils::apply_modifications(current, &update);
+                            affected += 2;
+                        }
+                        Some(_) => affected += u64::from(self.found_rows),
+                        None => {
+                            existing.insert(key_of(row), row.clone());
+                            affected += 1;
+                        }
+                    }
+                }
+            }
+            buf.into_iter()
+                .map(|row| TableOperation::InsertOrUpdate {
+                    row,
+                    update: update.clone(),
+                })
+                .collect()
+        } else if replace && !pkey.is_empty() {
+            trace!("insert::replace");
+            // overwriting every column of an existing row is the same as deleting and
+            // re-inserting it
+            buf.into_iter()
+                .map(|row| {
+                    let update = row.iter().cloned().map(Modification::Set).collect();
+                    TableOperation::InsertOrUpdate { row, update }
+                })
+                .collect()
+        } else if q.ignore && !pkey.is_empty() {
+            trace!("insert::ignore");
+            // the rows with keys that were taken are gone already, but another client may have
+            // taken one since. an update that changes nothing keeps its row as it is.
+            let update = utils::dense_modifications(schema.fields.len(), vec![]);
+            buf.into_iter()
+                .map(|row| TableOperation::InsertOrUpdate {
+                    row,
+                    update: update.clone(),
+                })
+                .collect()
         } else {
             trace!("insert::simple");
-            let buf: Vec<_> = buf
-                .into_iter()
-                .map(|r| TableOperation::Insert(r.into()))
-                .collect();
-            let r = block_on_buffer(putter.perform_all(buf));
-            trace!("insert::simple::complete");
-            r
+            buf.into_iter().map(TableOperation::Insert).collect()
         };
+        let result = self.perform(table, ops).await;
+        trace!("insert::complete");
 
-        match result {
-            Ok(_) => results.completed(data.len() as u64, first_inserted_id.unwrap_or(0) as u64),
-            Err(e) => {
-                error!(error = %e, "failed");
-                results.error(
-                    msql_srv::ErrorKind::ER_UNKNOWN_ERROR,
-                    format!("{:?}", e).as_bytes(),
-                )
-            }
+        if let Err(e) = result {
+            self.inner.invalidate(table);
+            report!(results, e);
         }
+        results.completed_with_warnings(
+            affected,
+            first_inserted_id.unwrap_or(0),
+            u16::try_from(self.warnings.len()).unwrap_or(u16::max_value()),
+        )
     }
 
-    fn do_read<W: io::Write>(
+    #[allow(clippy::too_many_arguments)]
+    async fn do_read<W: AsyncWrite + Unpin + Send>(
         &mut self,
         qname: &str,
+        q: &SelectStatement,
         keys: Vec<Vec<DataType>>,
         schema: &[msql_srv::Column],
-        results: QueryResultWriter<W>,
+        filters: &[(&rewrite::PostFilter, DataType)],
+        merge: &utils::ResultMerge,
+        results: QueryResultWriter<'_, W>,
     ) -> io::Result<()> {
-        // create a getter if we don't have one for this query already
-        // TODO(malte): may need to make one anyway if the query has changed w.r.t. an
-        // earlier one of the same name
-        trace!("select::access view");
-        let getter = self.inner.ensure_getter(&qname);
+        let rows = try_or_report!(
+            results,
+            self.read_rows(qname, q, keys, schema, filters, merge).await
+        );
 
         let write_column = |rw: &mut RowWriter<W>, c: &DataType, cs: &msql_srv::Column| {
-            let written = match *c {
+            match *c {
                 DataType::None => rw.write_col(None::<i32>),
                 // NOTE(malte): the code repetition here is unfortunate, but it's hard to factor
                 // this out into a helper since i has a different time depending on the DataType
-                // variant.
+                // variant. the casts are lossless, since values are checked against their
+                // columns' ranges when they are written. msql-srv converts `isize` and `usize` to
+                // whatever integer type the column has.
                 DataType::Int(i) => {
                     if cs.colflags.contains(ColumnFlags::UNSIGNED_FLAG) {
                         rw.write_col(i as usize)
@@ -872,8 +2095,9 @@ impl NoriaBackend {
                         rw.write_col(i as isize)
                     }
                 }
-                DataType::Text(ref t) => rw.write_col(t.to_str().unwrap()),
-                ref dt @ DataType::TinyText(_) => rw.write_col(dt.to_string()),
+                DataType::Text(_) | DataType::TinyText(_) => {
+                    rw.write_col(convert::text_bytes(c).unwrap())
+                }
                 ref dt @ DataType::Real(_, _) => match cs.coltype {
                     msql_srv::ColumnType::MYSQL_TYPE_DECIMAL => {
                         let f = dt.to_string();
@@ -883,88 +2107,120 @@ impl NoriaBackend {
                         let f: f64 = dt.into();
                         rw.write_col(f)
                     }
-                    _ => unreachable!(),
+                    _ => rw.write_col(dt.to_string()),
+                },
+                DataType::Timestamp(ts) => match cs.coltype {
+                    msql_srv::ColumnType::MYSQL_TYPE_DATE => rw.write_col(ts.date()),
+                    _ => rw.write_col(ts),
                 },
-                DataType::Timestamp(ts) => rw.write_col(ts),
-            };
-            match written {
-                Ok(_) => (),
-                Err(e) => panic!("failed to write column: {:?}", e),
             }
         };
 
-        trace!("select::lookup");
-        let cols = Vec::from(getter.columns());
-        let bogo = vec![vec![DataType::from(0 as i32)]];
-        let is_bogo = keys.is_empty() || keys.iter().all(|k| k.is_empty());
-        let keys = if is_bogo { bogo } else { keys };
-
-        let ops = &self.ops;
-        if self
-            .trace_every
-            .map(|te| ops.fetch_add(1, atomic::Ordering::AcqRel) % te == 0)
-            .unwrap_or(false)
-        {
-            noria::trace_my_next_op();
+        let mut rw = results.start(schema)?;
+        for r in rows {
+            for (v, c) in r.iter().zip(schema) {
+                write_column(&mut rw, v, c)?;
+            }
+            rw.end_row()?;
         }
+        rw.finish()
+    }
 
-        // if first lookup fails, there's no reason to try the others
-        match block_on_buffer(getter.multi_lookup(keys, true)) {
-            Ok(d) => {
-                trace!("select::complete");
-                let mut rw = results.start(schema).unwrap();
-                for resultsets in d {
-                    for r in resultsets {
-                        let mut r: Vec<_> = r.into();
-                        if is_bogo {
-                            // drop bogokey
-                            r.pop();
-                        }
-
-                        for c in schema {
-                            let coli =
-                                cols.iter().position(|f| f == &c.column).unwrap_or_else(|| {
-                                    panic!(
-                                    "tried to emit column {:?} not in getter for {:?} with schema {:?}",
-                                    c.column, qname, cols
-                                );
-                                });
-                            write_column(&mut rw, &r[coli], c);
-                        }
-                        rw.end_row()?;
-                    }
-                }
-                rw.finish()
-            }
-            Err(e) => {
-                error!(error = %e, "failed");
-                results.error(
-                    msql_srv::ErrorKind::ER_UNKNOWN_ERROR,
-                    "Noria returned an error".as_bytes(),
-                )
+    async fn do_delete<W: AsyncWrite + Unpin + Send>(
+        &mut self,
+        q: Cow<'_, DeleteStatement>,
+        params: Option<ParamParser<'_>>,
+        results: QueryResultWriter<'_, W>,
+    ) -> io::Result<()> {
+        let table = q.table.name.clone();
+        let table = &table;
+        let cond = q.into_owned().where_clause;
+        let params = match cond {
+            Some(ref cond) => {
+                let mut params = params.map(|p| p.into_iter());
+                try_or_report!(results, utils::where_params(cond, &mut params))
             }
+            None => Vec::new(),
+        };
+
+        // create a mutator if we don't have one for this table already
+        trace!(%table, "delete::access mutator");
+        let mutator = try_or_report!(results, self.inner.ensure_mutator(table).await);
+
+        trace!("delete::extract schema");
+        // cannot delete from view
+        let schema = try_or_report!(
+            results,
+            mutator
+                .schema()
+                .cloned()
+                .ok_or_else(|| Error::NotSupported(format!("DELETE from view '{}'", table)))
+        );
+
+        // only count the rows that actually exist, but delete every key (see `resolve_rows`)
+        trace!("delete::resolve rows");
+        let (keys, rows) = try_or_report!(
+            results,
+            self.resolve_rows(table, &schema, cond.as_ref(), params)
+                .await
+        );
+
+        let count = rows.len() as u64;
+        let ops = keys
+            .into_iter()
+            .map(|key| TableOperation::Delete { key })
+            .collect();
+
+        trace!("delete::execute");
+        if let Err(e) = self.perform(table, ops).await {
+            self.inner.invalidate(table);
+            report!(results, e);
         }
+
+        trace!("delete::done");
+        results.completed(count, 0)
     }
 
-    fn do_update<W: io::Write>(
+    async fn do_update<W: AsyncWrite + Unpin + Send>(
         &mut self,
-        q: Cow<UpdateStatement>,
-        params: Option<ParamParser>,
-        results: QueryResultWriter<W>,
+        q: Cow<'_, UpdateStatement>,
+        params: Option<ParamParser<'_>>,
+        results: QueryResultWriter<'_, W>,
     ) -> io::Result<()> {
-        trace!(table = %q.table.name, "update::access mutator");
-        let mutator = self.inner.ensure_mutator(&q.table.name);
+        let table = q.table.name.clone();
+        trace!(%table, "update::access mutator");
+        let mutator = try_or_report!(results, self.inner.ensure_mutator(&table).await);
 
         let q = q.into_owned();
-        let (key, updates) = {
-            trace!("update::extract schema");
-            let schema = if let Some(cts) = mutator.schema() {
-                cts
-            } else {
-                // no update on views
-                unimplemented!();
-            };
-            utils::extract_update(q, params, schema)
+        let ncols = mutator.columns().len();
+        trace!("update::extract schema");
+        // no update on views
+        let schema = try_or_report!(
+            results,
+            mutator
+                .schema()
+                .cloned()
+                .ok_or_else(|| Error::NotSupported(format!("UPDATE of view '{}'", table)))
+        );
+        let (cond, params, updates) =
+            try_or_report!(results, utils::extract_update(q, params, &schema));
+        let set = utils::dense_modifications(ncols, updates);
+
+        // MySQL counts the rows an UPDATE matched and the rows it actually changed separately,
+        // so we need the current rows to tell the two apart. they only go into the count, though:
+        // every key is updated (see `resolve_rows`).
+        trace!("update::resolve rows");
+        let (keys, rows) = try_or_report!(
+            results,
+            self.resolve_rows(&table, &schema, cond.as_ref(), params)
+                .await
+        );
+        let count = if self.found_rows {
+            rows.len() as u64
+        } else {
+            rows.iter()
+                .filter(|row| utils::changes_row(row, &set))
+                .count() as u64
         };
 
         let ops = &self.ops;
@@ -977,29 +2233,32 @@ impl NoriaBackend {
         }
 
         trace!("update::update");
-        match block_on_buffer(mutator.update(key, updates)) {
+        let ops = keys
+            .into_iter()
+            .map(|key| TableOperation::Update {
+                key,
+                set: set.clone(),
+            })
+            .collect();
+        match self.perform(&table, ops).await {
             Ok(..) => {
                 trace!("update::complete");
-                results.completed(1 /* TODO */, 0)
+                results.completed(count, 0)
             }
             Err(e) => {
-                error!(error = %e, "failed");
-                results.error(
-                    msql_srv::ErrorKind::ER_UNKNOWN_ERROR,
-                    format!("{:?}", e).as_bytes(),
-                )
+                self.inner.invalidate(&table);
+                report!(results, e);
             }
         }
     }
 }
 
-impl<W: io::Write> MysqlShim<W> for &mut NoriaBackend {
-    type Error = io::Error;
-
-    fn on_prepare(&mut self, query: &str, info: StatementMetaWriter<W>) -> io::Result<()> {
-        let span = span!(Level::DEBUG, "prepare", query);
-        let _g = span.enter();
-
+impl<A: Authority + 'static> NoriaBackend<A> {
+    async fn prepare<W: AsyncWrite + Unpin + Send>(
+        &mut self,
+        query: &str,
+        info: StatementMetaWriter<'_, W>,
+    ) -> io::Result<()> {
         if !self.reset && self.primed.load(atomic::Ordering::Acquire) {
             self.reset = true;
         }
@@ -1011,118 +2270,125 @@ impl<W: io::Write> MysqlShim<W> for &mut NoriaBackend {
             query.to_owned()
         };
 
-        trace!("parse");
-        let sql_q = match self.parsed.get(&query) {
-            None => match nom_sql::parse_query(&query) {
-                Ok(sql_q) => {
-                    // ensure that we have schemas and endpoints for the query
-                    let endpoints_needed = sql_q.referred_tables();
-                    self.fetch_endpoints(endpoints_needed).unwrap();
-
-                    self.parsed
-                        .insert(query.to_owned(), (sql_q.clone(), vec![]));
+        // nom-sql cannot parse REPLACE, so we treat it as the INSERT it looks like
+        let (query, replace) = match utils::replace_as_insert(&query) {
+            Some(q) => (q, true),
+            None => (query, false),
+        };
 
-                    sql_q
-                }
-                Err(e) => {
-                    // if nom-sql rejects the query, there is no chance Noria will like it
-                    error!(%query, "query can't be parsed");
-                    return info.error(msql_srv::ErrorKind::ER_PARSE_ERROR, e.as_bytes());
-                }
-            },
-            Some((q, _)) => q.clone(),
+        // `parsed` holds ad-hoc queries with their literals lifted, so prepared statements are
+        // parsed anew
+        trace!("parse");
+        let sql_q = match nom_sql::parse_query(&query) {
+            Ok(mut sql_q) => {
+                // ensure that we have schemas and endpoints for the query
+                let endpoints_needed = sql_q.referred_tables();
+                try_or_report!(info, self.fetch_endpoints(endpoints_needed).await);
+
+                try_or_report!(info, rewrite::push_down_negations(&mut sql_q));
+                sql_q
+            }
+            Err(e) => {
+                // if nom-sql rejects the query, there is no chance Noria will like it
+                error!(%query, "query can't be parsed");
+                report!(info, Error::ParseError(e.to_owned()));
+            }
         };
 
         trace!("delegate");
         match sql_q {
-            nom_sql::SqlQuery::Select(_) => self.prepare_select(sql_q, info),
-            nom_sql::SqlQuery::Insert(_) => self.prepare_insert(sql_q, info),
-            nom_sql::SqlQuery::Update(_) => self.prepare_update(sql_q, info),
+            nom_sql::SqlQuery::Select(_) => self.prepare_select(sql_q, info).await,
+            nom_sql::SqlQuery::Insert(_) => self.prepare_insert(sql_q, replace, info).await,
+            nom_sql::SqlQuery::Update(_) => self.prepare_update(sql_q, info).await,
+            nom_sql::SqlQuery::Delete(_) => self.prepare_delete(sql_q, info).await,
+            // SELECT, INSERT, UPDATE and DELETE can be prepared; anything else has to be sent as
+            // a plain query
             _ => {
-                // Noria only supports prepared SELECT statements at the moment
-                error!(%query, "unsupported query for prepared statement");
-                return info.error(
-                    msql_srv::ErrorKind::ER_NOT_SUPPORTED_YET,
-                    "unsupported query".as_bytes(),
+                let kind = match sql_q {
+                    nom_sql::SqlQuery::CreateTable(_) => "CREATE TABLE",
+                    nom_sql::SqlQuery::CreateView(_) => "CREATE VIEW",
+                    nom_sql::SqlQuery::CompoundSelect(_) => "compound SELECT",
+                    nom_sql::SqlQuery::DropTable(_) => "DROP TABLE",
+                    nom_sql::SqlQuery::Set(_) => "SET",
+                    _ => unreachable!(),
+                };
+                error!(%query, kind, "unsupported query for prepared statement");
+                report!(
+                    info,
+                    Error::NotSupported(format!("preparing a {} statement", kind))
                 );
             }
         }
     }
 
-    fn on_execute(
+    async fn execute<W: AsyncWrite + Unpin + Send>(
         &mut self,
         id: u32,
-        params: ParamParser,
-        results: QueryResultWriter<W>,
+        params: ParamParser<'_>,
+        results: QueryResultWriter<'_, W>,
     ) -> io::Result<()> {
-        let span = span!(Level::TRACE, "execute", id);
-        let _g = span.enter();
-
         if !self.reset && self.primed.load(atomic::Ordering::Acquire) {
             self.reset = true;
         }
 
         let start = time::Instant::now();
+        self.warnings.clear();
 
         // TODO(malte): unfortunate clone here, but we can't call execute_select(&mut self) if we
         // have self.prepared borrowed
         let prep: PreparedStatement = {
             match self.prepared.get(&id) {
                 Some(e) => e.clone(),
-                None => {
-                    return results.error(
-                        msql_srv::ErrorKind::ER_UNKNOWN_ERROR,
-                        "non-existent statement".as_bytes(),
-                    )
-                }
+                None => report!(results, Error::UnknownStatement(id)),
             }
         };
 
         trace!("delegate");
         let res = match prep {
-            PreparedStatement::Select(ref qname, ref _q, ref schema, ref rewritten) => {
-                trace!("apply where-in rewrites");
-                let key = match rewritten {
-                    Some((first_rewritten, nrewritten)) => {
-                        // this is a little tricky
-                        // the user is giving us some params [a, b, c, d]
-                        // for the query WHERE x = ? AND y IN (?, ?) AND z = ?
-                        // that we rewrote to WHERE x = ? AND y = ? AND z = ?
-                        // so we need to turn that into the keys:
-                        // [[a, b, d], [a, c, d]]
-                        let params: Vec<_> = params
-                            .into_iter()
-                            .map(|pv| pv.value.to_datatype())
-                            .collect::<Vec<_>>();
-                        (0..*nrewritten)
-                            .map(|poffset| {
-                                params
-                                    .iter()
-                                    .take(*first_rewritten)
-                                    .chain(params.iter().skip(first_rewritten + poffset).take(1))
-                                    .chain(params.iter().skip(first_rewritten + nrewritten))
-                                    .cloned()
-                                    .collect()
-                            })
-                            .collect()
-                    }
-                    None => vec![params
+            PreparedStatement::Select(ref qname, ref q, ref schema, ref rewritten, ref post) => {
+                let mut params: Vec<_> = try_or_report!(
+                    results,
+                    params
                         .into_iter()
                         .map(|pv| pv.value.to_datatype())
-                        .collect::<Vec<_>>()],
+                        .collect()
+                );
+
+                // the filters' parameters are not part of the lookup key
+                trace!("bind filters");
+                let mut filters = Vec::with_capacity(post.len());
+                for f in post.iter().rev() {
+                    if f.param >= params.len() {
+                        report!(results, Error::WrongArguments);
+                    }
+                    filters.push((f, params.remove(f.param)));
+                }
+
+                trace!("apply where-in rewrites");
+                let key = try_or_report!(results, utils::where_in_keys(params, rewritten));
+                // the view of a query with a collapsed WHERE IN leaves merging to the adapter
+                let merge = if rewritten.is_empty() {
+                    utils::ResultMerge::default()
+                } else {
+                    utils::ResultMerge::for_query(q)
                 };
 
-                self.execute_select(&qname, key, schema, results)
+                self.execute_select(qname, q, key, schema, &filters, &merge, results)
+                    .await
             }
-            PreparedStatement::Insert(ref q) => {
-                let values: Vec<DataType> = params
-                    .into_iter()
-                    .map(|pv| pv.value.to_datatype())
-                    .collect();
+            PreparedStatement::Insert(ref q, replace) => {
+                let values: Vec<DataType> = try_or_report!(
+                    results,
+                    params
+                        .into_iter()
+                        .map(|pv| pv.value.to_datatype())
+                        .collect()
+                );
 
-                self.execute_insert(&q, values, results)
+                self.execute_insert(q, values, replace, results).await
             }
-            PreparedStatement::Update(ref q) => self.execute_update(&q, params, results),
+            PreparedStatement::Update(ref q) => self.execute_update(q, params, results).await,
+            PreparedStatement::Delete(ref q) => self.execute_delete(q, params, results).await,
         };
 
         if self.slowlog {
@@ -1130,8 +2396,9 @@ impl<W: io::Write> MysqlShim<W> for &mut NoriaBackend {
             if took.as_secs() > 0 || took.subsec_nanos() > 5_000_000 {
                 let query: &dyn std::fmt::Display = match prep {
                     PreparedStatement::Select(_, ref q, ..) => q,
-                    PreparedStatement::Insert(ref q) => q,
+                    PreparedStatement::Insert(ref q, _) => q,
                     PreparedStatement::Update(ref q) => q,
+                    PreparedStatement::Delete(ref q) => q,
                 };
                 warn!(
                     %query,
@@ -1144,12 +2411,11 @@ impl<W: io::Write> MysqlShim<W> for &mut NoriaBackend {
         res
     }
 
-    fn on_close(&mut self, _: u32) {}
-
-    fn on_query(&mut self, query: &str, results: QueryResultWriter<W>) -> io::Result<()> {
-        let span = span!(Level::TRACE, "query", query);
-        let _g = span.enter();
-
+    async fn query<W: AsyncWrite + Unpin + Send>(
+        &mut self,
+        query: &str,
+        results: QueryResultWriter<'_, W>,
+    ) -> io::Result<()> {
         if !self.reset && self.primed.load(atomic::Ordering::Acquire) {
             self.reset = true;
         }
@@ -1165,16 +2431,47 @@ impl<W: io::Write> MysqlShim<W> for &mut NoriaBackend {
 
         let query_lc = query.to_lowercase();
 
-        if query_lc.starts_with("begin")
-            || query_lc.starts_with("start transaction")
-            || query_lc.starts_with("commit")
+        let statement = query_lc
+            .trim()
+            .trim_end_matches(';')
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        if statement == "show warnings" {
+            return self.handle_show_warnings(results);
+        }
+        self.warnings.clear();
+
+        if statement == "begin"
+            || statement == "begin work"
+            || statement == "start transaction"
+            || statement.starts_with("start transaction ")
         {
-            trace!("ignoring transaction bits");
+            // like MySQL, starting a transaction implicitly commits the current one
+            trace!("transaction::begin");
+            return self.handle_commit(true, results).await;
+        }
+
+        if statement == "commit" || statement == "commit work" {
+            return self.handle_commit(false, results).await;
+        }
+
+        if statement == "rollback" || statement == "rollback work" {
+            trace!("transaction::rollback");
+            self.pending_writes.clear();
+            self.in_transaction = false;
             return results.completed(0, 0);
         }
 
+        // savepoints would need a log of the buffered writes, which we don't keep
+        if statement.starts_with("rollback ")
+            || statement.starts_with("savepoint ")
+            || statement.starts_with("release savepoint ")
+        {
+            report!(results, Error::NotSupported(format!("'{}'", statement)));
+        }
+
         if query_lc.starts_with("show databases")
-            || query_lc.starts_with("rollback")
             || query_lc.starts_with("alter table")
             || query_lc.starts_with("create index")
             || query_lc.starts_with("create unique index")
@@ -1211,16 +2508,28 @@ impl<W: io::Write> MysqlShim<W> for &mut NoriaBackend {
                         })
                         .collect();
                     let mut writer = results.start(&cols[..])?;
-                    for &(_, ref r) in columns {
-                        writer.write_col(String::from(*r))?;
+                    for &(_, r) in columns {
+                        writer.write_col(String::from(r))?;
                     }
-                    return writer.end_row();
+                    writer.end_row()?;
+                    return writer.finish();
                 }
             }
         }
 
+        // nom-sql cannot parse REPLACE, so we treat it as the INSERT it looks like
+        let (query, replace) = match utils::replace_as_insert(&query) {
+            Some(q) => (q, true),
+            None => (query, false),
+        };
+
+        // nom-sql cannot parse INSERT ... SELECT, so we take it apart ourselves
+        if let Some((q, select)) = utils::split_insert_select(&query) {
+            return self.handle_insert_select(q, select, replace, results).await;
+        }
+
         trace!("analyzing query");
-        let (q, use_params) = match self.parsed.get(&query) {
+        let (q, keys, collapsed) = match self.parsed.get(&query) {
             None => {
                 trace!("parsing query");
                 match nom_sql::parse_query(&query) {
@@ -1238,41 +2547,42 @@ impl<W: io::Write> MysqlShim<W> for &mut NoriaBackend {
                             }
                             _ => {
                                 let endpoints_needed = q.referred_tables();
-                                self.fetch_endpoints(endpoints_needed).unwrap();
+                                try_or_report!(
+                                    results,
+                                    self.fetch_endpoints(endpoints_needed).await
+                                );
                             }
                         }
 
-                        trace!("collapsing where-in clauses");
-                        let mut use_params = Vec::new();
-                        if let Some((_, p)) = rewrite::collapse_where_in(&mut q, true) {
-                            use_params = p;
-                        }
+                        trace!("parameterizing query");
+                        try_or_report!(results, rewrite::push_down_negations(&mut q));
+                        let (keys, collapsed) =
+                            try_or_report!(results, rewrite::parameterize_query(&mut q));
 
                         self.parsed
-                            .insert(query.to_owned(), (q.clone(), use_params.clone()));
+                            .insert(query.to_owned(), (q.clone(), keys.clone(), collapsed));
 
-                        (q, use_params)
+                        (q, keys, collapsed)
                     }
-                    Err(_e) => {
+                    Err(e) => {
                         // if nom-sql rejects the query, there is no chance Noria will like it
                         error!("query can't be parsed");
-                        return results.completed(0, 0);
-                        //return results.error(msql_srv::ErrorKind::ER_PARSE_ERROR, e.as_bytes());
+                        report!(results, Error::ParseError(e.to_owned()));
                     }
                 }
             }
-            Some((q, use_params)) => (q.clone(), use_params.clone()),
+            Some((q, keys, collapsed)) => (q.clone(), keys.clone(), *collapsed),
         };
 
         trace!("delegate");
         let res = match q {
-            nom_sql::SqlQuery::CreateTable(q) => self.handle_create_table(q, results),
-            nom_sql::SqlQuery::CreateView(q) => self.handle_create_view(q, results),
-            nom_sql::SqlQuery::Insert(q) => self.handle_insert(q, results),
-            nom_sql::SqlQuery::Select(q) => self.handle_select(q, use_params, results),
-            nom_sql::SqlQuery::Set(q) => self.handle_set(q, results),
-            nom_sql::SqlQuery::Update(q) => self.handle_update(q, results),
-            nom_sql::SqlQuery::Delete(q) => self.handle_delete(q, results),
+            nom_sql::SqlQuery::CreateTable(q) => self.handle_create_table(q, &query, results).await,
+            nom_sql::SqlQuery::CreateView(q) => self.handle_create_view(q, results).await,
+            nom_sql::SqlQuery::Insert(q) => self.handle_insert(q, replace, results).await,
+            nom_sql::SqlQuery::Select(q) => self.handle_select(q, keys, collapsed, results).await,
+            nom_sql::SqlQuery::Set(q) => self.handle_set(q, results).await,
+            nom_sql::SqlQuery::Update(q) => self.handle_update(q, results).await,
+            nom_sql::SqlQuery::Delete(q) => self.handle_delete(q, results).await,
             nom_sql::SqlQuery::DropTable(_) => {
                 warn!("ignoring drop table");
                 return results.completed(0, 0);
@@ -1300,3 +2610,42 @@ impl<W: io::Write> MysqlShim<W> for &mut NoriaBackend {
         res
     }
 }
+
+#[async_trait]
+impl<A, W> MysqlShim<W> for NoriaBackend<A>
+where
+    A: Authority + 'static,
+    W: AsyncWrite + Unpin + Send,
+{
+    type Error = io::Error;
+
+    async fn on_prepare(
+        &mut self,
+        query: &str,
+        info: StatementMetaWriter<'_, W>,
+    ) -> io::Result<()> {
+        let span = span!(Level::DEBUG, "prepare", query);
+        self.prepare(query, info).instrument(span).await
+    }
+
+    async fn on_execute(
+        &mut self,
+        id: u32,
+        params: ParamParser<'_>,
+        results: QueryResultWriter<'_, W>,
+    ) -> io::Result<()> {
+        let span = span!(Level::TRACE, "execute", id);
+        self.execute(id, params, results).instrument(span).await
+    }
+
+    fn on_close(&mut self, _: u32) {}
+
+    fn on_handshake(&mut self, capabilities: CapabilityFlags) {
+        self.found_rows = capabilities.contains(CapabilityFlags::CLIENT_FOUND_ROWS);
+    }
+
+    async fn on_query(&mut self, query: &str, results: QueryResultWriter<'_, W>) -> io::Result<()> {
+        let span = span!(Level::TRACE, "query", query);
+        self.query(query, results).instrument(span).await
+    }
+}
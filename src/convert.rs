@@ -1,35 +1,60 @@
-use chrono;
 use msql_srv::{Value, ValueInner};
 use nom_sql::{Literal, Real};
 use noria::DataType;
 
-use arccstr::ArcCStr;
 use std::convert::TryFrom;
+use std::fmt::Write;
+use std::str;
 
+use crate::errors::Error;
+
+/// Turns client values into Noria's. This fails for byte strings that Noria cannot store (see
+/// `bytes_to_datatype`).
 pub(crate) trait ToDataType {
-    fn to_datatype(self) -> DataType;
+    fn to_datatype(self) -> Result<DataType, Error>;
 }
 
-const TINYTEXT_WIDTH: usize = 15;
-
-impl ToDataType for Literal {
-    fn to_datatype(self) -> DataType {
-        match self {
-            Literal::Null => DataType::None,
-            Literal::String(b) => b.into(),
-            Literal::Blob(b) => {
-                let len = b.len();
-                if len <= TINYTEXT_WIDTH {
-                    let mut bytes = [0; TINYTEXT_WIDTH];
-                    if len != 0 {
-                        let bts = &mut bytes[0..len];
-                        bts.copy_from_slice(&b);
-                    }
-                    DataType::TinyText(bytes)
+/// Turns a byte string into a text value.
+///
+/// Noria's text values are NUL-terminated UTF-8, and it has no type for other byte strings, so
+/// those are rejected rather than stored in a form that other text could be mistaken for.
+pub(crate) fn bytes_to_datatype(b: &[u8]) -> Result<DataType, Error> {
+    match str::from_utf8(b) {
+        Ok(s) if !b.contains(&0) => Ok(String::from(s).into()),
+        _ => {
+            // like MySQL, show the bytes that are not printable as hex escapes
+            let mut shown = String::new();
+            for &byte in b {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    shown.push(char::from(byte));
                 } else {
-                    DataType::Text(ArcCStr::try_from(&b[..]).unwrap())
+                    write!(shown, "\\x{:02X}", byte).unwrap();
                 }
             }
+            Err(Error::IncorrectString(shown))
+        }
+    }
+}
+
+/// The bytes of a text value.
+pub(crate) fn text_bytes(v: &DataType) -> Option<&[u8]> {
+    match *v {
+        DataType::Text(ref t) => Some(t.to_bytes()),
+        DataType::TinyText(ref t) => {
+            // short texts are padded with NULs
+            let len = t.iter().position(|&b| b == 0).unwrap_or(t.len());
+            Some(&t[..len])
+        }
+        _ => None,
+    }
+}
+
+impl ToDataType for Literal {
+    fn to_datatype(self) -> Result<DataType, Error> {
+        Ok(match self {
+            Literal::Null => DataType::None,
+            Literal::String(b) => bytes_to_datatype(b.as_bytes())?,
+            Literal::Blob(b) => bytes_to_datatype(&b)?,
             Literal::Integer(i) => i.into(),
             Literal::UnsignedInteger(i) => i.into(),
             Literal::FixedPoint(Real {
@@ -43,20 +68,83 @@ impl ToDataType for Literal {
                 DataType::Timestamp(chrono::Local::now().naive_local())
             }
             Literal::Placeholder => unreachable!(),
-        }
+        })
     }
 }
 
 impl<'a> ToDataType for Value<'a> {
-    fn to_datatype(self) -> DataType {
-        match self.into_inner() {
+    fn to_datatype(self) -> Result<DataType, Error> {
+        Ok(match self.into_inner() {
             ValueInner::NULL => DataType::None,
-            ValueInner::Bytes(b) => DataType::Text(ArcCStr::try_from(b).unwrap()),
+            ValueInner::Bytes(b) => bytes_to_datatype(b)?,
             ValueInner::Int(i) => i.into(),
-            ValueInner::UInt(i) => (i as i32).into(),
+            ValueInner::UInt(i) => i.into(),
             ValueInner::Double(f) => f.into(),
+            ValueInner::Date(_) => {
+                let d: chrono::NaiveDate = self.into();
+                DataType::Timestamp(d.and_hms(0, 0, 0))
+            }
             ValueInner::Datetime(_) => DataType::Timestamp(self.into()),
-            _ => unimplemented!(),
-        }
+            // Noria has no type for times, so they are kept the way MySQL writes them
+            ValueInner::Time(t) => format_time(t).into(),
+        })
+    }
+}
+
+/// Formats a TIME parameter in the binary protocol as `[-]HHH:MM:SS[.ffffff]`.
+fn format_time(t: &[u8]) -> String {
+    if t.len() < 8 {
+        return String::from("00:00:00");
+    }
+    let u32_at = |i: usize| u32::from_le_bytes([t[i], t[i + 1], t[i + 2], t[i + 3]]);
+    let sign = if t[0] == 1 { "-" } else { "" };
+    let hours = u64::from(u32_at(1)) * 24 + u64::from(t[5]);
+    let mut time = format!("{}{:02}:{:02}:{:02}", sign, hours, t[6], t[7]);
+    if t.len() >= 12 && u32_at(8) != 0 {
+        time.push_str(&format!(".{:06}", u32_at(8)));
+    }
+    time
+}
+
+/// Turns parameter values into literals, for the comparisons that Noria has to evaluate itself
+/// rather than take as parameters (see `rewrite::bind_lookup_params`). `ToDataType` turns the
+/// literals into the same values again.
+pub(crate) trait ToLiteral {
+    fn to_literal(self) -> Result<Literal, Error>;
+}
+
+impl ToLiteral for DataType {
+    fn to_literal(self) -> Result<Literal, Error> {
+        Ok(match self {
+            DataType::None => Literal::Null,
+            DataType::Int(i) => Literal::Integer(i as i64),
+            DataType::BigInt(i) => Literal::Integer(i),
+            DataType::UnsignedInt(i) => Literal::UnsignedInteger(i as u64),
+            DataType::UnsignedBigInt(i) => Literal::UnsignedInteger(i),
+            DataType::Real(integral, fractional) => {
+                // nom-sql's fixed-point literals have a narrower integral part than Noria's
+                let integral = i32::try_from(integral).map_err(|_| {
+                    Error::OutOfRange(format!("{}", DataType::Real(integral, fractional)))
+                })?;
+                Literal::FixedPoint(Real {
+                    integral,
+                    fractional,
+                })
+            }
+            // nom-sql has no timestamp literals, and a string would not compare equal to one
+            DataType::Timestamp(ts) => {
+                return Err(Error::NotSupported(format!(
+                    "comparing with timestamp parameter '{}' outside of a lookup",
+                    ts
+                )))
+            }
+            dt @ DataType::Text(_) | dt @ DataType::TinyText(_) => {
+                // like nom-sql, keep what is not UTF-8 as a blob
+                match String::from_utf8(text_bytes(&dt).unwrap().to_vec()) {
+                    Ok(s) => Literal::String(s),
+                    Err(e) => Literal::Blob(e.into_bytes()),
+                }
+            }
+        })
     }
 }
@@ -0,0 +1,62 @@
+use msql_srv::ErrorKind;
+
+/// An error that fails a single query. It is reported to the client as the corresponding MySQL
+/// error, and leaves the connection usable.
+#[derive(Debug, Fail)]
+#[allow(clippy::enum_variant_names)]
+pub(crate) enum Error {
+    #[fail(display = "Table '{}' doesn't exist", _0)]
+    NoSuchTable(String),
+    #[fail(display = "Unknown column '{}'", _0)]
+    BadField(String),
+    #[fail(display = "Column count doesn't match value count at row {}", _0)]
+    WrongValueCount(usize),
+    #[fail(display = "Duplicate entry '{}' for key 'PRIMARY'", _0)]
+    DupEntry(String),
+    #[fail(display = "Incorrect string value: '{}'", _0)]
+    IncorrectString(String),
+    #[fail(display = "Out of range value for column '{}'", _0)]
+    OutOfRange(String),
+    #[fail(display = "You have an error in your SQL syntax: {}", _0)]
+    ParseError(String),
+    #[fail(display = "{} is not supported", _0)]
+    NotSupported(String),
+    #[fail(display = "Unknown prepared statement handler ({})", _0)]
+    UnknownStatement(u32),
+    #[fail(display = "Incorrect arguments to EXECUTE")]
+    WrongArguments,
+    #[fail(display = "The view for this query is still being created; try again later")]
+    ViewPending,
+    #[fail(display = "Failed to allocate AUTO_INCREMENT ids: {}", _0)]
+    AutoIncrement(String),
+    #[fail(display = "{}", _0)]
+    Noria(String),
+}
+
+impl Error {
+    /// The MySQL error code to report this error with.
+    pub(crate) fn error_kind(&self) -> ErrorKind {
+        match *self {
+            Error::NoSuchTable(_) => ErrorKind::ER_NO_SUCH_TABLE,
+            Error::BadField(_) => ErrorKind::ER_BAD_FIELD_ERROR,
+            Error::WrongValueCount(_) => ErrorKind::ER_WRONG_VALUE_COUNT_ON_ROW,
+            Error::DupEntry(_) => ErrorKind::ER_DUP_ENTRY,
+            Error::IncorrectString(_) => ErrorKind::ER_TRUNCATED_WRONG_VALUE_FOR_FIELD,
+            Error::OutOfRange(_) => ErrorKind::ER_WARN_DATA_OUT_OF_RANGE,
+            Error::ParseError(_) => ErrorKind::ER_PARSE_ERROR,
+            Error::NotSupported(_) => ErrorKind::ER_NOT_SUPPORTED_YET,
+            Error::UnknownStatement(_) => ErrorKind::ER_UNKNOWN_STMT_HANDLER,
+            Error::WrongArguments => ErrorKind::ER_WRONG_ARGUMENTS,
+            // what MySQL reports when a statement runs past max_execution_time
+            Error::ViewPending => ErrorKind::ER_QUERY_TIMEOUT,
+            Error::AutoIncrement(_) => ErrorKind::ER_AUTOINC_READ_FAILED,
+            Error::Noria(_) => ErrorKind::ER_UNKNOWN_ERROR,
+        }
+    }
+}
+
+impl From<failure::Error> for Error {
+    fn from(e: failure::Error) -> Self {
+        Error::Noria(e.to_string())
+    }
+}
@@ -9,12 +9,15 @@ extern crate lazy_static;
 #[macro_use]
 extern crate tracing;
 
+mod auto_increment;
 mod backend;
 mod convert;
+mod errors;
 mod referred_tables;
 mod rewrite;
 mod schema;
 mod utils;
 
-pub use crate::backend::NoriaBackend;
+pub use crate::auto_increment::AutoIncrements;
+pub use crate::backend::{EndpointRegistry, NoriaBackend, QueryCache};
 pub use crate::schema::Schema;
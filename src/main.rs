@@ -5,31 +5,20 @@
 #[macro_use]
 extern crate clap;
 #[macro_use]
-extern crate failure;
-#[macro_use]
-extern crate lazy_static;
-#[macro_use]
 extern crate tracing;
 
-mod backend;
-mod convert;
-mod referred_tables;
-mod rewrite;
-mod schema;
-mod utils;
-
-use crate::backend::NoriaBackend;
 use futures_util::future::FutureExt;
 use futures_util::stream::StreamExt;
 use msql_srv::MysqlIntermediary;
-use nom_sql::SelectStatement;
-use noria::{ControllerHandle, ZookeeperAuthority};
-use std::collections::HashMap;
-use std::io::{self, BufReader, BufWriter};
-use std::sync::atomic::{self, AtomicUsize};
-use std::sync::{Arc, RwLock};
-use std::thread;
+use noria::consensus::{Authority, LocalAuthority};
+use noria::{ControllerDescriptor, ControllerHandle, ZookeeperAuthority};
+use noria_mysql::{AutoIncrements, EndpointRegistry, NoriaBackend, QueryCache};
+use std::io;
+use std::sync::atomic;
+use std::sync::Arc;
+use std::time;
 use tracing::Level;
+use tracing_futures::Instrument;
 
 // Just give me a damn terminal logger
 // Duplicated from distributary, as the API subcrate doesn't export it.
@@ -59,7 +48,7 @@ fn main() {
             Arg::with_name("deployment")
                 .long("deployment")
                 .takes_value(true)
-                .required(true)
+                .required_unless_one(&["controller", "local"])
                 .help("Noria deployment ID to attach to."),
         )
         .arg(
@@ -69,6 +58,23 @@ fn main() {
                 .default_value("127.0.0.1:2181")
                 .help("IP:PORT for Zookeeper."),
         )
+        .arg(
+            Arg::with_name("controller")
+                .long("controller")
+                .takes_value(true)
+                .conflicts_with_all(&["deployment", "local"])
+                .help(
+                    "IP:PORT of a Noria controller to connect to directly, without Zookeeper. \
+                     AUTO_INCREMENT ids are then only coordinated within this adapter, so only \
+                     one adapter should use the controller.",
+                ),
+        )
+        .arg(
+            Arg::with_name("local")
+                .long("local")
+                .conflicts_with_all(&["deployment", "controller"])
+                .help("Run an in-process Noria instead of attaching to a deployment."),
+        )
         .arg(
             Arg::with_name("slowlog")
                 .long("log-slow")
@@ -85,6 +91,15 @@ fn main() {
                 .long("time")
                 .help("Instead of logging trace events, time them and output metrics on exit"),
         )
+        .arg(
+            Arg::with_name("view-timeout")
+                .long("view-timeout")
+                .takes_value(true)
+                .help(
+                    "Give up on a query after waiting this many milliseconds for its view to be \
+                     created. 0 fails immediately; waits indefinitely if not given.",
+                ),
+        )
         .arg(
             Arg::with_name("no-static-responses")
                 .long("no-static-responses")
@@ -101,8 +116,6 @@ fn main() {
         .get_matches();
 
     let listen_addr = value_t_or_exit!(matches, "address", String);
-    let deployment = matches.value_of("deployment").unwrap().to_owned();
-    assert!(!deployment.contains("-"));
 
     let histograms = matches.is_present("time");
     let trace_every = if matches.is_present("trace") {
@@ -110,10 +123,22 @@ fn main() {
     } else {
         None
     };
-    let slowlog = matches.is_present("slowlog");
-    let zk_addr = matches.value_of("zk_addr").unwrap().to_owned();
-    let sanitize = !matches.is_present("no-sanitize");
-    let static_responses = !matches.is_present("no-static-responses");
+    let view_timeout = if matches.is_present("view-timeout") {
+        Some(time::Duration::from_millis(value_t_or_exit!(
+            matches,
+            "view-timeout",
+            u64
+        )))
+    } else {
+        None
+    };
+    let options = BackendOptions {
+        trace_every,
+        view_timeout,
+        slowlog: matches.is_present("slowlog"),
+        static_responses: !matches.is_present("no-static-responses"),
+        sanitize: !matches.is_present("no-sanitize"),
+    };
 
     use tracing_subscriber::Layer;
     let filter = tracing_subscriber::EnvFilter::from_default_env();
@@ -133,112 +158,56 @@ fn main() {
     let mut rt = tracing::dispatcher::with_default(&tracer, tokio::runtime::Runtime::new).unwrap();
     let listen_socket: std::net::SocketAddr = listen_addr.parse().unwrap();
 
-    let mut listener = rt
+    let listener = rt
         .block_on(tokio::net::TcpListener::bind(&listen_socket))
         .unwrap();
 
     let log = logger_pls();
     slog::info!(log, "listening on address {}", listen_addr);
 
-    let auto_increments: Arc<RwLock<HashMap<String, AtomicUsize>>> = Arc::default();
-    let query_cache: Arc<RwLock<HashMap<SelectStatement, String>>> = Arc::default();
-
-    let mut zk_auth = ZookeeperAuthority::new(&format!("{}/{}", zk_addr, deployment)).unwrap();
-    zk_auth.log_with(log.clone());
-
-    slog::debug!(log, "Connecting to Noria...",);
-    let ch = rt.block_on(ControllerHandle::new(zk_auth)).unwrap();
-    slog::debug!(log, "Connected!");
-
-    let ctrlc = tokio::signal::ctrl_c();
-    let mut listener = Box::pin(futures_util::stream::select(
-        listener.incoming(),
-        ctrlc
-            .map(|r| {
-                if let Err(e) = r {
-                    Err(e)
-                } else {
-                    Err(io::Error::new(io::ErrorKind::Interrupted, "got ctrl-c"))
-                }
-            })
-            .into_stream(),
-    ));
-    let primed = Arc::new(atomic::AtomicBool::new(false));
-    let ops = Arc::new(atomic::AtomicUsize::new(0));
-
-    let mut threads = Vec::new();
-    let mut i = 0;
-    while let Some(Ok(s)) = rt.block_on(listener.next()) {
-        // one day, when msql-srv is async, this won't be necessary
-        let s = {
-            use std::os::unix::io::AsRawFd;
-            use std::os::unix::io::FromRawFd;
-            let s2 = unsafe { std::net::TcpStream::from_raw_fd(s.as_raw_fd()) };
-            std::mem::forget(s); // don't drop, which would close
-            s2.set_nonblocking(false).unwrap();
-            s2
+    if matches.is_present("controller") {
+        let addr = value_t_or_exit!(matches, "controller", std::net::SocketAddr);
+        // a local authority that knows of nothing but the given controller
+        let authority = Arc::new(LocalAuthority::new());
+        let descriptor = ControllerDescriptor {
+            external_addr: addr,
+            worker_addr: addr,
+            domain_addr: addr,
+            nonce: 0,
         };
-        s.set_nodelay(true).unwrap();
-
-        let connection = span!(Level::DEBUG, "connection", addr = ?s.peer_addr().unwrap());
-        connection.in_scope(|| debug!("accepted"));
-
-        let builder = thread::Builder::new().name(format!("conn-{}", i));
-
-        let (auto_increments, query_cache, primed) =
-            (auto_increments.clone(), query_cache.clone(), primed.clone());
-
-        let ex = rt.handle().clone();
-        let ch = ch.clone();
-        let ops = ops.clone();
-
-        let jh = builder
-            .spawn(move || {
-                let (tx, rx) = tokio::sync::oneshot::channel();
-                let _g = connection.enter();
-                let b = NoriaBackend::new(
-                    ex.clone(),
-                    ch,
-                    auto_increments,
-                    query_cache,
-                    (ops, trace_every),
-                    primed,
-                    slowlog,
-                    static_responses,
-                    sanitize,
-                );
-                ex.spawn(async move {
-                    let _ = tx.send(b.await);
-                });
-                let mut b = futures_executor::block_on(rx).unwrap();
-
-                let rs = s.try_clone().unwrap();
-                if let Err(e) =
-                    MysqlIntermediary::run_on(&mut b, BufReader::new(rs), BufWriter::new(s))
-                {
-                    match e.kind() {
-                        io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe => {}
-                        _ => {
-                            error!(err = ?e, "connection lost");
-                            return;
-                        }
-                    }
-                }
-
-                debug!("disconnected");
-            })
+        authority
+            .become_leader(serde_json::to_vec(&descriptor).unwrap())
             .unwrap();
-        threads.push(jh);
-        i += 1;
-    }
-
-    drop(ch);
-    slog::info!(log, "Exiting...");
+        slog::warn!(
+            log,
+            "AUTO_INCREMENT ids are not coordinated with other adapters of this controller"
+        );
+        rt.block_on(serve(listener, authority, options, log));
+    } else if matches.is_present("local") {
+        #[cfg(feature = "local")]
+        {
+            let authority = Arc::new(LocalAuthority::new());
+            let mut builder = noria_server::Builder::default();
+            builder.log_with(log.clone());
+            slog::debug!(log, "Starting in-process Noria...");
+            let server = rt.block_on(builder.start(authority.clone())).unwrap();
+            rt.block_on(serve(listener, authority, options, log));
+            drop(server);
+        }
+        #[cfg(not(feature = "local"))]
+        {
+            drop(listener);
+            eprintln!("--local requires building with the `local` feature");
+            std::process::exit(1);
+        }
+    } else {
+        let deployment = matches.value_of("deployment").unwrap();
+        assert!(!deployment.contains('-'));
+        let zk_addr = matches.value_of("zk_addr").unwrap();
 
-    for t in threads.drain(..) {
-        t.join()
-            .map_err(|e| e.downcast::<io::Error>().unwrap())
-            .unwrap();
+        let mut zk_auth = ZookeeperAuthority::new(&format!("{}/{}", zk_addr, deployment)).unwrap();
+        zk_auth.log_with(log.clone());
+        rt.block_on(serve(listener, Arc::new(zk_auth), options, log));
     }
 
     drop(rt);
@@ -310,6 +279,101 @@ fn main() {
     }
 }
 
+/// Settings that every connection's backend is created with.
+#[derive(Clone, Copy)]
+struct BackendOptions {
+    trace_every: Option<usize>,
+    view_timeout: Option<time::Duration>,
+    slowlog: bool,
+    static_responses: bool,
+    sanitize: bool,
+}
+
+/// Serve MySQL clients that connect to `listener` from the Noria deployment that `authority`
+/// points to, until ctrl-c is pressed.
+async fn serve<A: Authority + 'static>(
+    mut listener: tokio::net::TcpListener,
+    authority: Arc<A>,
+    options: BackendOptions,
+    log: slog::Logger,
+) {
+    let endpoints: Arc<EndpointRegistry> = Arc::default();
+    let query_cache: Arc<QueryCache> = Arc::default();
+    let auto_increments = Arc::new(AutoIncrements::new(authority.clone()));
+
+    slog::debug!(log, "Connecting to Noria...",);
+    let ch = ControllerHandle::make(authority).await.unwrap();
+    slog::debug!(log, "Connected!");
+
+    let ctrlc = tokio::signal::ctrl_c();
+    let mut listener = Box::pin(futures_util::stream::select(
+        listener.incoming(),
+        ctrlc
+            .map(|r| {
+                if let Err(e) = r {
+                    Err(e)
+                } else {
+                    Err(io::Error::new(io::ErrorKind::Interrupted, "got ctrl-c"))
+                }
+            })
+            .into_stream(),
+    ));
+    let primed = Arc::new(atomic::AtomicBool::new(false));
+    let ops = Arc::new(atomic::AtomicUsize::new(0));
+
+    while let Some(Ok(s)) = listener.next().await {
+        s.set_nodelay(true).unwrap();
+
+        let connection = span!(Level::DEBUG, "connection", addr = ?s.peer_addr().unwrap());
+        connection.in_scope(|| debug!("accepted"));
+
+        let (endpoints, auto_increments, query_cache, primed) = (
+            endpoints.clone(),
+            auto_increments.clone(),
+            query_cache.clone(),
+            primed.clone(),
+        );
+
+        let ch = ch.clone();
+        let ops = ops.clone();
+
+        // the task is detached; it ends on its own when the client disconnects
+        tokio::spawn(
+            async move {
+                let b = NoriaBackend::new(
+                    ch,
+                    endpoints,
+                    auto_increments,
+                    query_cache,
+                    options.view_timeout,
+                    (ops, options.trace_every),
+                    primed,
+                    options.slowlog,
+                    options.static_responses,
+                    options.sanitize,
+                )
+                .await;
+
+                if let Err(e) = MysqlIntermediary::run_on_tcp(b, s).await {
+                    match e.kind() {
+                        io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe => {}
+                        _ => {
+                            error!(err = ?e, "connection lost");
+                            return;
+                        }
+                    }
+                }
+
+                debug!("disconnected");
+            }
+            .instrument(connection),
+        );
+    }
+
+    drop(ch);
+    slog::info!(log, "Exiting...");
+}
+
 // until we have https://github.com/rust-lang/rust/issues/62208
 fn break_once<I, F>(it: I, mut f: F) -> impl Iterator<Item = I::Item>
 where
@@ -1,6 +1,6 @@
 use nom_sql::{
-    CompoundSelectStatement, ConditionBase, ConditionExpression, SelectSpecification, SqlQuery,
-    Table,
+    ArithmeticBase, CompoundSelectStatement, ConditionBase, ConditionExpression,
+    SelectSpecification, SqlQuery, Table,
 };
 
 pub trait ReferredTables {
@@ -13,7 +13,7 @@ impl ReferredTables for SqlQuery {
             csq.selects
                 .iter()
                 .fold(Vec::new(), |mut acc, &(_, ref sq)| {
-                    acc.extend(sq.tables.iter().cloned().collect::<Vec<_>>());
+                    acc.extend(sq.tables.to_vec());
                     acc
                 })
         };
@@ -22,13 +22,13 @@ impl ReferredTables for SqlQuery {
             SqlQuery::Set(_) => vec![],
             SqlQuery::CreateTable(ref ctq) => vec![ctq.table.clone()],
             SqlQuery::CreateView(ref cvq) => match *cvq.definition {
-                SelectSpecification::Simple(ref sq) => sq.tables.iter().cloned().collect(),
+                SelectSpecification::Simple(ref sq) => sq.tables.to_vec(),
                 SelectSpecification::Compound(ref csq) => handle_compound_select(csq),
             },
             SqlQuery::Insert(ref iq) => vec![iq.table.clone()],
-            SqlQuery::Select(ref sq) => sq.tables.iter().cloned().collect(),
+            SqlQuery::Select(ref sq) => sq.tables.to_vec(),
             SqlQuery::CompoundSelect(ref csq) => handle_compound_select(csq),
-            SqlQuery::DropTable(ref dtq) => dtq.tables.iter().cloned().collect(),
+            SqlQuery::DropTable(ref dtq) => dtq.tables.to_vec(),
             // TODO(malte): this ignores the possibility of nested selections in the WHERE clause;
             // that's okay since we only support primary key deletes at this point.
             SqlQuery::Delete(ref dq) => vec![dq.table.clone()],
@@ -40,6 +40,11 @@ impl ReferredTables for SqlQuery {
 impl ReferredTables for ConditionExpression {
     fn referred_tables(&self) -> Vec<Table> {
         let mut tables = Vec::new();
+        let mut add = |t: Table| {
+            if !tables.contains(&t) {
+                tables.push(t);
+            }
+        };
         match *self {
             ConditionExpression::LogicalOp(ref ct) | ConditionExpression::ComparisonOp(ref ct) => {
                 for t in ct
@@ -48,21 +53,35 @@ impl ReferredTables for ConditionExpression {
                     .into_iter()
                     .chain(ct.right.referred_tables().into_iter())
                 {
-                    if !tables.contains(&t) {
-                        tables.push(t);
-                    }
+                    add(t);
                 }
             }
-            ConditionExpression::Base(ConditionBase::Field(ref f)) => match f.table {
-                Some(ref t) => {
-                    let t = Table::from(t.as_ref());
-                    if !tables.contains(&t) {
-                        tables.push(t);
+            ConditionExpression::NegationOp(ref ce) | ConditionExpression::Bracketed(ref ce) => {
+                for t in ce.referred_tables() {
+                    add(t);
+                }
+            }
+            ConditionExpression::Arithmetic(ref ae) => {
+                for b in &[&ae.left, &ae.right] {
+                    if let ArithmeticBase::Column(ref c) = **b {
+                        if let Some(ref t) = c.table {
+                            add(Table::from(t.as_ref()));
+                        }
                     }
                 }
-                None => (),
-            },
-            _ => unimplemented!(),
+            }
+            ConditionExpression::Base(ConditionBase::Field(ref f)) => {
+                if let Some(ref t) = f.table {
+                    add(Table::from(t.as_ref()))
+                }
+            }
+            ConditionExpression::Base(ConditionBase::NestedSelect(ref sq)) => {
+                for t in sq.tables.iter().cloned() {
+                    add(t);
+                }
+            }
+            ConditionExpression::Base(ConditionBase::Literal(_))
+            | ConditionExpression::Base(ConditionBase::LiteralList(_)) => (),
         }
         tables
     }
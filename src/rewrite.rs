@@ -1,56 +1,73 @@
-use nom_sql::{ConditionBase, ConditionExpression, ConditionTree, Literal, Operator, SqlQuery};
+use nom_sql::{
+    ArithmeticBase, Column, ConditionBase, ConditionExpression, ConditionTree,
+    FieldDefinitionExpression, FunctionExpression, Literal, Operator, SelectStatement, SqlQuery,
+};
+use noria::DataType;
 
-use std::mem;
+use std::{mem, vec};
+
+use crate::convert::{ToDataType, ToLiteral};
+use crate::errors::Error;
+use crate::utils;
 
 fn collapse_where_in_recursive(
     leftmost_param_index: &mut usize,
     expr: &mut ConditionExpression,
     rewrite_literals: bool,
-) -> Option<(usize, Vec<Literal>)> {
+    rewritten: &mut Vec<(usize, Vec<Literal>)>,
+) -> Result<(), Error> {
     match *expr {
-        ConditionExpression::Arithmetic(_) => unimplemented!(),
+        ConditionExpression::Arithmetic(ref ae) => Err(Error::NotSupported(format!(
+            "arithmetic expression '{}' in WHERE-clause",
+            ae
+        ))),
         ConditionExpression::Base(ConditionBase::Literal(Literal::Placeholder)) => {
             *leftmost_param_index += 1;
-            None
+            Ok(())
         }
         ConditionExpression::Base(ConditionBase::NestedSelect(ref mut sq)) => {
             if let Some(ref mut w) = sq.where_clause {
-                collapse_where_in_recursive(leftmost_param_index, w, rewrite_literals)
+                collapse_where_in_recursive(leftmost_param_index, w, rewrite_literals, rewritten)
             } else {
-                None
+                Ok(())
             }
         }
         ConditionExpression::Base(ConditionBase::LiteralList(ref list)) => {
             *leftmost_param_index += list.iter().filter(|&l| *l == Literal::Placeholder).count();
-            None
+            Ok(())
         }
-        ConditionExpression::Base(_) => None,
+        ConditionExpression::Base(_) => Ok(()),
         ConditionExpression::NegationOp(ref mut ce)
         | ConditionExpression::Bracketed(ref mut ce) => {
-            collapse_where_in_recursive(leftmost_param_index, ce, rewrite_literals)
+            collapse_where_in_recursive(leftmost_param_index, ce, rewrite_literals, rewritten)
         }
         ConditionExpression::LogicalOp(ref mut ct) => {
-            collapse_where_in_recursive(leftmost_param_index, &mut *ct.left, rewrite_literals)
-                .or_else(|| {
-                    // we can't also try rewriting ct.right, as it'd make it hard to recover
-                    // literals: if we rewrote WHERE x IN (a, b) in left and WHERE y IN (1, 2) in
-                    // right into WHERE x = ? ... y = ?, then what param values should we use?
-                    collapse_where_in_recursive(
-                        leftmost_param_index,
-                        &mut *ct.right,
-                        rewrite_literals,
-                    )
-                })
+            collapse_where_in_recursive(
+                leftmost_param_index,
+                &mut *ct.left,
+                rewrite_literals,
+                rewritten,
+            )?;
+            collapse_where_in_recursive(
+                leftmost_param_index,
+                &mut *ct.right,
+                rewrite_literals,
+                rewritten,
+            )
         }
         ConditionExpression::ComparisonOp(ref mut ct) if ct.operator != Operator::In => {
-            collapse_where_in_recursive(leftmost_param_index, &mut *ct.left, rewrite_literals)
-                .or_else(|| {
-                    collapse_where_in_recursive(
-                        leftmost_param_index,
-                        &mut *ct.right,
-                        rewrite_literals,
-                    )
-                })
+            collapse_where_in_recursive(
+                leftmost_param_index,
+                &mut *ct.left,
+                rewrite_literals,
+                rewritten,
+            )?;
+            collapse_where_in_recursive(
+                leftmost_param_index,
+                &mut *ct.right,
+                rewrite_literals,
+                rewritten,
+            )
         }
         ConditionExpression::ComparisonOp(ref mut ct) => {
             let mut do_it = false;
@@ -58,9 +75,9 @@ fn collapse_where_in_recursive(
                 if let ConditionExpression::Base(ConditionBase::LiteralList(ref mut list)) =
                     *ct.right
                 {
-                    if rewrite_literals || list.iter().all(|l| *l == Literal::Placeholder) {
+                    if rewrite_literals || list.contains(&Literal::Placeholder) {
                         do_it = true;
-                        mem::replace(list, Vec::new())
+                        mem::take(list)
                     } else {
                         Vec::new()
                     }
@@ -69,23 +86,26 @@ fn collapse_where_in_recursive(
                 };
 
             if !do_it {
-                return collapse_where_in_recursive(
+                collapse_where_in_recursive(
                     leftmost_param_index,
                     &mut *ct.left,
                     rewrite_literals,
-                )
-                .or_else(|| {
-                    collapse_where_in_recursive(
-                        leftmost_param_index,
-                        &mut *ct.right,
-                        rewrite_literals,
-                    )
-                });
+                    rewritten,
+                )?;
+                return collapse_where_in_recursive(
+                    leftmost_param_index,
+                    &mut *ct.right,
+                    rewrite_literals,
+                    rewritten,
+                );
             }
 
             if let ConditionExpression::Base(ConditionBase::Field(_)) = *ct.left {
             } else {
-                unimplemented!();
+                return Err(Error::NotSupported(format!(
+                    "IN on non-column expression '{}'",
+                    ct.left
+                )));
             }
 
             let c = mem::replace(
@@ -95,48 +115,861 @@ fn collapse_where_in_recursive(
                 ))),
             );
 
-            mem::replace(
-                ct,
-                ConditionTree {
-                    operator: Operator::Equal,
-                    left: c,
-                    right: Box::new(ConditionExpression::Base(ConditionBase::Literal(
-                        Literal::Placeholder,
-                    ))),
-                },
-            );
+            *ct = ConditionTree {
+                operator: Operator::Equal,
+                left: c,
+                right: Box::new(ConditionExpression::Base(ConditionBase::Literal(
+                    Literal::Placeholder,
+                ))),
+            };
+
+            // every value of the list is a parameter in its own right
+            let first = *leftmost_param_index;
+            *leftmost_param_index += literals.len();
+            rewritten.push((first, literals));
+            Ok(())
+        }
+    }
+}
+
+// The column and value of a `column = value` comparison.
+fn equality(expr: &ConditionExpression) -> Option<(&Column, &Literal)> {
+    match *expr {
+        ConditionExpression::ComparisonOp(ConditionTree {
+            operator: Operator::Equal,
+            left: box ConditionExpression::Base(ConditionBase::Field(ref c)),
+            right: box ConditionExpression::Base(ConditionBase::Literal(ref l)),
+        })
+        | ConditionExpression::ComparisonOp(ConditionTree {
+            operator: Operator::Equal,
+            left: box ConditionExpression::Base(ConditionBase::Literal(ref l)),
+            right: box ConditionExpression::Base(ConditionBase::Field(ref c)),
+        }) if c.function.is_none() => Some((c, l)),
+        ConditionExpression::Bracketed(ref ce) => equality(ce),
+        _ => None,
+    }
+}
+
+// Collects the column and the values of a disjunction of equalities on one column, in order.
+// Returns None if `expr` is anything else.
+fn or_equalities(expr: &ConditionExpression) -> Option<(&Column, Vec<Literal>)> {
+    match *expr {
+        ConditionExpression::LogicalOp(ConditionTree {
+            operator: Operator::Or,
+            ref left,
+            ref right,
+        }) => {
+            let (column, mut values) = or_equalities(left)?;
+            let (other, more) = or_equalities(right)?;
+            if other != column {
+                return None;
+            }
+            values.extend(more);
+            Some((column, values))
+        }
+        ConditionExpression::Bracketed(ref ce) => or_equalities(ce),
+        _ => match equality(expr)? {
+            // NULL never compares equal, and the current time changes from lookup to lookup
+            (_, &Literal::Null)
+            | (_, &Literal::CurrentDate)
+            | (_, &Literal::CurrentTime)
+            | (_, &Literal::CurrentTimestamp) => None,
+            (c, l) => Some((c, vec![l.clone()])),
+        },
+    }
+}
 
-            if literals.is_empty() {
-                eprintln!("spotted empty WHERE IN ()");
+fn or_to_in_recursive(expr: &mut ConditionExpression, rewrite_literals: bool) {
+    match *expr {
+        ConditionExpression::LogicalOp(ConditionTree {
+            operator: Operator::Or,
+            ..
+        }) => {
+            let (column, values) = match or_equalities(expr) {
+                Some((column, values)) => (column.clone(), values),
+                None => return,
+            };
+            // only rewrite what collapse_where_in will turn into lookups
+            if !rewrite_literals && !values.contains(&Literal::Placeholder) {
+                return;
             }
+            *expr = ConditionExpression::ComparisonOp(ConditionTree {
+                operator: Operator::In,
+                left: Box::new(ConditionExpression::Base(ConditionBase::Field(column))),
+                right: Box::new(ConditionExpression::Base(ConditionBase::LiteralList(
+                    values,
+                ))),
+            });
+        }
+        ConditionExpression::LogicalOp(ConditionTree {
+            operator: Operator::And,
+            ref mut left,
+            ref mut right,
+        }) => {
+            or_to_in_recursive(left, rewrite_literals);
+            or_to_in_recursive(right, rewrite_literals);
+        }
+        ConditionExpression::Bracketed(ref mut ce) => or_to_in_recursive(ce, rewrite_literals),
+        _ => {}
+    }
+}
 
-            Some((*leftmost_param_index, literals))
+fn auto_parameterize_recursive(expr: &mut ConditionExpression, lifted: &mut Vec<Literal>) {
+    match *expr {
+        ConditionExpression::ComparisonOp(ConditionTree {
+            operator: Operator::Equal,
+            left: box ConditionExpression::Base(ConditionBase::Field(ref c)),
+            right: box ConditionExpression::Base(ConditionBase::Literal(ref mut l)),
+        }) if c.function.is_none() => match *l {
+            // placeholders are already parameters, NULL never compares equal, and lifting the
+            // current time would freeze it at the first lookup
+            Literal::Placeholder
+            | Literal::Null
+            | Literal::CurrentDate
+            | Literal::CurrentTime
+            | Literal::CurrentTimestamp => {}
+            _ => lifted.push(mem::replace(l, Literal::Placeholder)),
+        },
+        ConditionExpression::LogicalOp(ConditionTree {
+            operator: Operator::And,
+            ref mut left,
+            ref mut right,
+        }) => {
+            // Noria only supports parameters in conjunctions, so we don't descend into ORs
+            auto_parameterize_recursive(left, lifted);
+            auto_parameterize_recursive(right, lifted);
         }
+        ConditionExpression::Bracketed(ref mut ce) => auto_parameterize_recursive(ce, lifted),
+        _ => {}
     }
 }
 
+/// Replaces the literals in `column = literal` comparisons of a SELECT's WHERE clause with
+/// placeholders, so that queries that differ only in those literals share one view.
+///
+/// Returns the lifted literals in placeholder order.
+pub(crate) fn auto_parameterize_query(query: &mut SqlQuery) -> Vec<Literal> {
+    let mut lifted = Vec::new();
+    if let SqlQuery::Select(ref mut sq) = *query {
+        if let Some(ref mut w) = sq.where_clause {
+            auto_parameterize_recursive(w, &mut lifted);
+        }
+    }
+    lifted
+}
+
+/// Rewrites every `column IN (...)` in a SELECT's WHERE clause into `column = ?`, so that the
+/// query can be served by one lookup per combination of the listed values. Disjunctions of
+/// equalities on one column, like `column = 1 OR column = ?`, are treated as IN lists too.
+///
+/// Unless `rewrite_literals` is set, only lists with placeholders in them are rewritten.
+///
+/// Returns, for each rewritten list in order, the position of its first value among the query's
+/// parameters (counting every value of the lists before it), and the values that were listed.
 pub(crate) fn collapse_where_in(
     query: &mut SqlQuery,
     rewrite_literals: bool,
-) -> Option<(usize, Vec<Literal>)> {
+) -> Result<Vec<(usize, Vec<Literal>)>, Error> {
+    let mut rewritten = Vec::new();
     if let SqlQuery::Select(ref mut sq) = *query {
         if let Some(ref mut w) = sq.where_clause {
+            or_to_in_recursive(w, rewrite_literals);
             let mut left_edge = 0;
-            return collapse_where_in_recursive(&mut left_edge, w, rewrite_literals);
+            collapse_where_in_recursive(&mut left_edge, w, rewrite_literals, &mut rewritten)?;
+        }
+    }
+    Ok(rewritten)
+}
+
+/// A `column <op> ?` comparison in a prepared SELECT that Noria cannot look up by (e.g., `x > ?`
+/// or `name LIKE ?`). The adapter applies it to the rows of the query's view instead.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct PostFilter {
+    /// The position of the comparison's placeholder among the query's parameters.
+    pub(crate) param: usize,
+    /// The hidden column of the view that holds the compared values (see `post_filter_column`).
+    pub(crate) column: String,
+    pub(crate) operator: Operator,
+}
+
+/// The name of the hidden column that `extract_post_filters` adds for the `i`th filter.
+fn post_filter_column(i: usize) -> String {
+    format!("__filter_{}", i)
+}
+
+/// The number of placeholders in `expr`, including those in lists and nested SELECTs.
+pub(crate) fn count_placeholders(expr: &ConditionExpression) -> usize {
+    match *expr {
+        ConditionExpression::Base(ConditionBase::Literal(Literal::Placeholder)) => 1,
+        ConditionExpression::Base(ConditionBase::LiteralList(ref list)) => {
+            list.iter().filter(|&l| *l == Literal::Placeholder).count()
+        }
+        ConditionExpression::Base(ConditionBase::NestedSelect(ref sq)) => sq
+            .where_clause
+            .as_ref()
+            .map(count_placeholders)
+            .unwrap_or(0),
+        ConditionExpression::Base(_) | ConditionExpression::Arithmetic(_) => 0,
+        ConditionExpression::NegationOp(ref ce) | ConditionExpression::Bracketed(ref ce) => {
+            count_placeholders(ce)
+        }
+        ConditionExpression::LogicalOp(ref ct) | ConditionExpression::ComparisonOp(ref ct) => {
+            count_placeholders(&ct.left) + count_placeholders(&ct.right)
+        }
+    }
+}
+
+// The operator to filter by for `column <op> ?`, or for `? <op> column` if `flipped`.
+fn post_filter_operator(operator: &Operator, flipped: bool) -> Option<Operator> {
+    Some(match (operator, flipped) {
+        (Operator::Greater, false) | (Operator::Less, true) => Operator::Greater,
+        (Operator::GreaterOrEqual, false) | (Operator::LessOrEqual, true) => {
+            Operator::GreaterOrEqual
+        }
+        (Operator::Less, false) | (Operator::Greater, true) => Operator::Less,
+        (Operator::LessOrEqual, false) | (Operator::GreaterOrEqual, true) => Operator::LessOrEqual,
+        (Operator::NotEqual, _) => Operator::NotEqual,
+        (Operator::Like, false) => Operator::Like,
+        (Operator::NotLike, false) => Operator::NotLike,
+        _ => return None,
+    })
+}
+
+// Whether `expr` compares a column with a placeholder in a way that Noria cannot look up by.
+fn has_post_filter(expr: &ConditionExpression) -> bool {
+    match *expr {
+        ConditionExpression::ComparisonOp(ConditionTree {
+            ref operator,
+            left: box ConditionExpression::Base(ConditionBase::Field(_)),
+            right: box ConditionExpression::Base(ConditionBase::Literal(Literal::Placeholder)),
+        })
+        | ConditionExpression::ComparisonOp(ConditionTree {
+            ref operator,
+            left: box ConditionExpression::Base(ConditionBase::Literal(Literal::Placeholder)),
+            right: box ConditionExpression::Base(ConditionBase::Field(_)),
+        }) => post_filter_operator(operator, false).is_some(),
+        ConditionExpression::NegationOp(ref ce) | ConditionExpression::Bracketed(ref ce) => {
+            has_post_filter(ce)
+        }
+        ConditionExpression::LogicalOp(ref ct) => {
+            has_post_filter(&ct.left) || has_post_filter(&ct.right)
+        }
+        _ => false,
+    }
+}
+
+// Removes the post-filter comparisons from `expr`, and returns what is left of it. The filters
+// are returned as their parameter, the column they compare, and the operator to filter by.
+fn extract_post_filters_recursive(
+    expr: ConditionExpression,
+    next_param: &mut usize,
+    filters: &mut Vec<(usize, Column, Operator)>,
+) -> Result<Option<ConditionExpression>, Error> {
+    Ok(match expr {
+        ConditionExpression::LogicalOp(ConditionTree {
+            operator: Operator::And,
+            left,
+            right,
+        }) => {
+            // only conjuncts can be checked separately from the rest of the WHERE-clause
+            let left = extract_post_filters_recursive(*left, next_param, filters)?;
+            let right = extract_post_filters_recursive(*right, next_param, filters)?;
+            match (left, right) {
+                (Some(left), Some(right)) => Some(ConditionExpression::LogicalOp(ConditionTree {
+                    operator: Operator::And,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                })),
+                (left, None) => left,
+                (None, right) => right,
+            }
         }
+        ConditionExpression::Bracketed(ce) => {
+            extract_post_filters_recursive(*ce, next_param, filters)?
+                .map(|ce| ConditionExpression::Bracketed(Box::new(ce)))
+        }
+        ConditionExpression::ComparisonOp(ConditionTree {
+            ref operator,
+            left: box ConditionExpression::Base(ConditionBase::Field(ref c)),
+            right: box ConditionExpression::Base(ConditionBase::Literal(Literal::Placeholder)),
+        })
+        | ConditionExpression::ComparisonOp(ConditionTree {
+            ref operator,
+            left: box ConditionExpression::Base(ConditionBase::Literal(Literal::Placeholder)),
+            right: box ConditionExpression::Base(ConditionBase::Field(ref c)),
+        }) if c.function.is_none() => {
+            let flipped = matches!(
+                expr,
+                ConditionExpression::ComparisonOp(ConditionTree {
+                    left: box ConditionExpression::Base(ConditionBase::Literal(_)),
+                    ..
+                })
+            );
+            let param = *next_param;
+            *next_param += 1;
+            match post_filter_operator(operator, flipped) {
+                Some(operator) => {
+                    filters.push((param, c.clone(), operator));
+                    None
+                }
+                None => Some(expr),
+            }
+        }
+        expr => {
+            // Noria would get the comparison, and cannot look up by it either
+            if has_post_filter(&expr) {
+                return Err(Error::NotSupported(format!(
+                    "range or LIKE parameters under OR or NOT in '{}'",
+                    expr
+                )));
+            }
+            *next_param += count_placeholders(&expr);
+            Some(expr)
+        }
+    })
+}
+
+// Binds the placeholder or lifts the literal `l` of a comparison (see `bind_lookup_params`).
+fn bind_literal(
+    l: &mut Literal,
+    lift: bool,
+    params: &mut vec::IntoIter<DataType>,
+    values: &mut Vec<DataType>,
+) -> Result<(), Error> {
+    if *l == Literal::Placeholder {
+        let v = params.next().ok_or(Error::WrongArguments)?;
+        if lift {
+            values.push(v);
+        } else {
+            *l = v.to_literal()?;
+        }
+    } else if lift {
+        values.push(mem::replace(l, Literal::Placeholder).to_datatype()?);
     }
-    None
+    Ok(())
+}
+
+// `conjunct` says whether `expr` is one of the conjuncts of the WHERE clause, since only those
+// can be looked up by or filtered.
+fn bind_lookup_params_recursive(
+    expr: &mut ConditionExpression,
+    conjunct: bool,
+    params: &mut vec::IntoIter<DataType>,
+    values: &mut Vec<DataType>,
+) -> Result<(), Error> {
+    match *expr {
+        ConditionExpression::ComparisonOp(ConditionTree {
+            ref operator,
+            left: box ConditionExpression::Base(ConditionBase::Field(ref c)),
+            right: box ConditionExpression::Base(ConditionBase::Literal(ref mut l)),
+        }) if c.function.is_none() => {
+            // like in `auto_parameterize_query`, NULL and the current time stay in the query
+            let lookup = *operator == Operator::Equal
+                && !matches!(
+                    *l,
+                    Literal::Null
+                        | Literal::CurrentDate
+                        | Literal::CurrentTime
+                        | Literal::CurrentTimestamp
+                );
+            let lift = conjunct && (lookup || post_filter_operator(operator, false).is_some());
+            bind_literal(l, lift, params, values)
+        }
+        ConditionExpression::ComparisonOp(ConditionTree {
+            ref operator,
+            left: box ConditionExpression::Base(ConditionBase::Literal(ref mut l)),
+            right: box ConditionExpression::Base(ConditionBase::Field(ref c)),
+        }) if c.function.is_none() => {
+            let lift = conjunct && post_filter_operator(operator, true).is_some();
+            bind_literal(l, lift, params, values)
+        }
+        ConditionExpression::LogicalOp(ConditionTree {
+            operator: Operator::And,
+            ref mut left,
+            ref mut right,
+        }) => {
+            bind_lookup_params_recursive(left, conjunct, params, values)?;
+            bind_lookup_params_recursive(right, conjunct, params, values)
+        }
+        ConditionExpression::Bracketed(ref mut ce) => {
+            bind_lookup_params_recursive(ce, conjunct, params, values)
+        }
+        ConditionExpression::LogicalOp(ref mut ct)
+        | ConditionExpression::ComparisonOp(ref mut ct) => {
+            bind_lookup_params_recursive(&mut ct.left, false, params, values)?;
+            bind_lookup_params_recursive(&mut ct.right, false, params, values)
+        }
+        ConditionExpression::NegationOp(ref mut ce) => {
+            bind_lookup_params_recursive(ce, false, params, values)
+        }
+        ConditionExpression::Base(ConditionBase::Literal(ref mut l)) => {
+            bind_literal(l, false, params, values)
+        }
+        // `collapse_where_in` turns the values of a list into lookup keys, wherever it is
+        ConditionExpression::Base(ConditionBase::LiteralList(ref list)) => {
+            for _ in list.iter().filter(|&l| *l == Literal::Placeholder) {
+                values.push(params.next().ok_or(Error::WrongArguments)?);
+            }
+            Ok(())
+        }
+        ConditionExpression::Base(ConditionBase::NestedSelect(ref mut sq)) => {
+            match sq.where_clause {
+                Some(ref mut w) => bind_lookup_params_recursive(w, false, params, values),
+                None => Ok(()),
+            }
+        }
+        ConditionExpression::Base(ConditionBase::Field(_)) => Ok(()),
+        ConditionExpression::Arithmetic(ref mut ae) => {
+            for b in vec![&mut ae.left, &mut ae.right] {
+                if let ArithmeticBase::Scalar(ref mut l) = *b {
+                    bind_literal(l, false, params, values)?;
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Prepares a SELECT for a lookup of the rows that match its WHERE clause, which may have the
+/// placeholders of an EXECUTE in it (e.g., when it comes from a prepared UPDATE or DELETE).
+///
+/// The literals of the comparisons that the lookup takes as parameters (equalities, and the
+/// comparisons that `extract_post_filters` takes out) are lifted into placeholders, so that
+/// statements of the same shape share a view. Placeholders anywhere else are replaced with their
+/// value from `params`, since Noria has to compare those itself.
+///
+/// Returns the values of the placeholders left in the query, in order.
+pub(crate) fn bind_lookup_params(
+    query: &mut SelectStatement,
+    params: Vec<DataType>,
+) -> Result<Vec<DataType>, Error> {
+    let mut params = params.into_iter();
+    let mut values = Vec::new();
+    if let Some(ref mut w) = query.where_clause {
+        // disjunctions of equalities are looked up like IN lists, so they keep their parameters
+        or_to_in_recursive(w, true);
+        bind_lookup_params_recursive(w, true, &mut params, &mut values)?;
+    }
+    Ok(values)
+}
+
+// Whether `fields` select `c` itself, rather than just a column of the same name.
+fn selects_column(fields: &[FieldDefinitionExpression], c: &Column) -> bool {
+    fields.iter().any(|f| match *f {
+        FieldDefinitionExpression::All => true,
+        FieldDefinitionExpression::AllInTable(ref t) => c.table.as_ref() == Some(t),
+        FieldDefinitionExpression::Col(ref fc) => {
+            fc.function.is_none() && fc.name == c.name && fc.table == c.table
+        }
+        _ => false,
+    })
+}
+
+fn lookup_columns_recursive<'a>(expr: &'a ConditionExpression, columns: &mut Vec<&'a Column>) {
+    match *expr {
+        ConditionExpression::ComparisonOp(ConditionTree {
+            operator: Operator::Equal,
+            left: box ConditionExpression::Base(ConditionBase::Field(ref c)),
+            right: box ConditionExpression::Base(ConditionBase::Literal(Literal::Placeholder)),
+        })
+        | ConditionExpression::ComparisonOp(ConditionTree {
+            operator: Operator::Equal,
+            left: box ConditionExpression::Base(ConditionBase::Literal(Literal::Placeholder)),
+            right: box ConditionExpression::Base(ConditionBase::Field(ref c)),
+        }) => columns.push(c),
+        ConditionExpression::NegationOp(ref ce) | ConditionExpression::Bracketed(ref ce) => {
+            lookup_columns_recursive(ce, columns)
+        }
+        ConditionExpression::LogicalOp(ref ct) => {
+            lookup_columns_recursive(&ct.left, columns);
+            lookup_columns_recursive(&ct.right, columns);
+        }
+        _ => {}
+    }
+}
+
+/// The names of the columns that a SELECT's view is looked up by, but that the query does not
+/// select. Noria adds those to the view's columns, so they have to be kept from the client just
+/// like the hidden columns the adapter adds.
+pub(crate) fn unselected_lookup_columns(query: &SelectStatement) -> Vec<String> {
+    let mut columns = Vec::new();
+    if let Some(ref w) = query.where_clause {
+        lookup_columns_recursive(w, &mut columns);
+    }
+
+    let mut hidden: Vec<String> = Vec::new();
+    for c in columns {
+        // the adapter does not resolve tables, so a column without one may be either
+        let selected = query.fields.iter().any(|f| match *f {
+            FieldDefinitionExpression::All => true,
+            FieldDefinitionExpression::AllInTable(ref t) => {
+                c.table.is_none() || c.table.as_ref() == Some(t)
+            }
+            FieldDefinitionExpression::Col(ref fc) => {
+                fc.function.is_none()
+                    && fc.name == c.name
+                    && (fc.table.is_none() || c.table.is_none() || fc.table == c.table)
+            }
+            _ => false,
+        });
+        if !selected && !hidden.contains(&c.name) {
+            hidden.push(c.name.clone());
+        }
+    }
+    hidden
+}
+
+fn conjunctive_comparisons_recursive<'a>(
+    expr: &'a ConditionExpression,
+    lookups: &mut Vec<&'a Column>,
+    comparisons: &mut Vec<(&'a Column, Operator, DataType)>,
+) -> bool {
+    match *expr {
+        ConditionExpression::LogicalOp(ConditionTree {
+            operator: Operator::And,
+            ref left,
+            ref right,
+        }) => {
+            conjunctive_comparisons_recursive(left, lookups, comparisons)
+                && conjunctive_comparisons_recursive(right, lookups, comparisons)
+        }
+        ConditionExpression::Bracketed(ref ce) => {
+            conjunctive_comparisons_recursive(ce, lookups, comparisons)
+        }
+        ConditionExpression::ComparisonOp(ConditionTree {
+            ref operator,
+            ref left,
+            ref right,
+        }) => {
+            let (c, l, flipped) = match (&**left, &**right) {
+                (
+                    ConditionExpression::Base(ConditionBase::Field(ref c)),
+                    ConditionExpression::Base(ConditionBase::Literal(ref l)),
+                ) => (c, l, false),
+                (
+                    ConditionExpression::Base(ConditionBase::Literal(ref l)),
+                    ConditionExpression::Base(ConditionBase::Field(ref c)),
+                ) => (c, l, true),
+                _ => return false,
+            };
+            match (operator, l) {
+                (Operator::Equal, Literal::Placeholder) => lookups.push(c),
+                (_, Literal::Placeholder) => return false,
+                (Operator::Equal, _) => match l.clone().to_datatype() {
+                    Ok(v) => comparisons.push((c, Operator::Equal, v)),
+                    Err(_) => return false,
+                },
+                _ => match (
+                    post_filter_operator(operator, flipped),
+                    l.clone().to_datatype(),
+                ) {
+                    (Some(op), Ok(v)) => comparisons.push((c, op, v)),
+                    _ => return false,
+                },
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Splits a WHERE-clause that only ANDs comparisons of columns together into the columns that
+/// are compared to placeholders, in the order of the placeholders, and the comparisons of
+/// columns with literals. A row matches the clause for a lookup key if the former equal the
+/// key's values, and it passes all of the latter (see `utils::compare`).
+///
+/// Any other clause, e.g. with an OR, a NOT or a nested SELECT, gives `None`.
+#[allow(clippy::type_complexity)]
+pub(crate) fn conjunctive_comparisons(
+    expr: &ConditionExpression,
+) -> Option<(Vec<&Column>, Vec<(&Column, Operator, DataType)>)> {
+    let mut lookups = Vec::new();
+    let mut comparisons = Vec::new();
+    if conjunctive_comparisons_recursive(expr, &mut lookups, &mut comparisons) {
+        Some((lookups, comparisons))
+    } else {
+        None
+    }
+}
+
+/// The column that the filter `f`, which was extracted from `query`, compares.
+pub(crate) fn post_filter_source<'a>(
+    query: &'a SelectStatement,
+    f: &PostFilter,
+) -> Option<&'a Column> {
+    query.fields.iter().find_map(|fd| match *fd {
+        FieldDefinitionExpression::Col(ref c) if c.alias.as_ref() == Some(&f.column) => Some(c),
+        _ => None,
+    })
+}
+
+/// Takes the comparisons of columns with placeholders that Noria cannot look up by out of a
+/// prepared SELECT, so that the adapter can filter the view's rows by them instead.
+///
+/// Each filter gets a hidden column in the query's fields that holds the values it compares, so
+/// that the filters do not depend on what the query selects, or under which names. The names of
+/// those columns are returned alongside the filters, so that they can be kept from the client.
+pub(crate) fn extract_post_filters(
+    query: &mut SelectStatement,
+) -> Result<(Vec<PostFilter>, Vec<String>), Error> {
+    let mut found = Vec::new();
+    if let Some(w) = query.where_clause.take() {
+        let mut next_param = 0;
+        query.where_clause = extract_post_filters_recursive(w, &mut next_param, &mut found)?;
+    }
+    if found.is_empty() {
+        return Ok((vec![], vec![]));
+    }
+
+    // filtering after the fact is only correct if the view's rows are the rows that match
+    let aggregates = query.fields.iter().any(|f| match *f {
+        FieldDefinitionExpression::Col(ref c) => c.function.is_some(),
+        _ => false,
+    });
+    if aggregates || query.group_by.is_some() || query.limit.is_some() {
+        return Err(Error::NotSupported(
+            "range or LIKE parameters in a query with aggregates, GROUP BY or LIMIT".into(),
+        ));
+    }
+
+    let mut filters = Vec::with_capacity(found.len());
+    let mut hidden = Vec::with_capacity(found.len());
+    for (i, (param, column, operator)) in found.into_iter().enumerate() {
+        // an extra column would make rows that only differ in it distinct
+        if query.distinct && !selects_column(&query.fields, &column) {
+            return Err(Error::NotSupported(
+                "range or LIKE parameters on unselected columns with DISTINCT".into(),
+            ));
+        }
+        let name = post_filter_column(i);
+        query.fields.push(FieldDefinitionExpression::Col(Column {
+            alias: Some(name.clone()),
+            ..column
+        }));
+        hidden.push(name.clone());
+        filters.push(PostFilter {
+            param,
+            column: name,
+            operator,
+        });
+    }
+    Ok((filters, hidden))
+}
+
+/// The name of the hidden column that `prepare_reaggregation` adds to count the values of the
+/// average `avg`.
+pub(crate) fn average_count_column(avg: &str) -> String {
+    // the name ends up in the recipe, so it has to be a valid identifier
+    let avg: String = avg
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("__count_{}", avg)
+}
+
+/// Rewrites an aggregate query whose WHERE-IN was collapsed, so that the adapter can combine the
+/// rows Noria returns for each key (see `utils::ResultMerge`).
+///
+/// Each AVG gets a COUNT of the same values, named by `average_count_column`, so that averages
+/// can be weighted by how many values they are over. Grouping columns that the query does not
+/// select are added to its fields as well. The names of the added columns are returned, so that
+/// they can be kept from the client.
+///
+/// An OFFSET is folded into the LIMIT, since the rows to skip are only known once the rows of all
+/// keys have been merged.
+pub(crate) fn prepare_reaggregation(query: &mut SelectStatement) -> Vec<String> {
+    if let Some(ref mut limit) = query.limit {
+        limit.limit = limit.limit.saturating_add(limit.offset);
+        limit.offset = 0;
+    }
+
+    let mut hidden = Vec::new();
+    let mut counts = Vec::new();
+    for f in &query.fields {
+        if let FieldDefinitionExpression::Col(ref c) = *f {
+            if let Some(&FunctionExpression::Avg(ref args, false)) = c.function.as_deref() {
+                let count = average_count_column(&c.name);
+                hidden.push(count.clone());
+                counts.push(FieldDefinitionExpression::Col(Column {
+                    name: count.clone(),
+                    alias: Some(count),
+                    table: None,
+                    function: Some(Box::new(FunctionExpression::Count(args.clone(), false))),
+                }));
+            }
+        }
+    }
+    query.fields.extend(counts);
+
+    if let Some(ref group_by) = query.group_by {
+        for c in &group_by.columns {
+            let selected = query.fields.iter().any(|f| match *f {
+                FieldDefinitionExpression::All => true,
+                FieldDefinitionExpression::AllInTable(ref t) => c.table.as_ref() == Some(t),
+                FieldDefinitionExpression::Col(ref fc) => {
+                    fc.function.is_none() && fc.name == c.name
+                }
+                _ => false,
+            });
+            if !selected && !hidden.contains(&c.name) {
+                hidden.push(c.name.clone());
+                query.fields.push(FieldDefinitionExpression::Col(c.clone()));
+            }
+        }
+    }
+    hidden
+}
+
+// The comparison that holds exactly where `op` does not, for values that are not NULL.
+fn opposite_operator(op: &Operator) -> Option<Operator> {
+    Some(match *op {
+        Operator::Equal => Operator::NotEqual,
+        Operator::NotEqual => Operator::Equal,
+        Operator::Less => Operator::GreaterOrEqual,
+        Operator::GreaterOrEqual => Operator::Less,
+        Operator::Greater => Operator::LessOrEqual,
+        Operator::LessOrEqual => Operator::Greater,
+        // nom-sql prints NOT LIKE in a form it cannot parse again
+        _ => return None,
+    })
+}
+
+// Puts `expr` in brackets if it is an OR, which would otherwise come apart under an AND.
+fn bracketed_if_or(expr: ConditionExpression) -> ConditionExpression {
+    match expr {
+        ConditionExpression::LogicalOp(ConditionTree {
+            operator: Operator::Or,
+            ..
+        }) => ConditionExpression::Bracketed(Box::new(expr)),
+        _ => expr,
+    }
+}
+
+// The condition that holds exactly where `expr` is false, without a NOT in front, if there is
+// one. A comparison with NULL is neither true nor false either way, like under NOT.
+fn negated(expr: &ConditionExpression) -> Option<ConditionExpression> {
+    match *expr {
+        ConditionExpression::Bracketed(ref ce) => negated(ce),
+        ConditionExpression::NegationOp(ref ce) => Some((**ce).clone()),
+        ConditionExpression::ComparisonOp(ref ct) => {
+            opposite_operator(&ct.operator).map(|operator| {
+                ConditionExpression::ComparisonOp(ConditionTree {
+                    operator,
+                    left: ct.left.clone(),
+                    right: ct.right.clone(),
+                })
+            })
+        }
+        ConditionExpression::LogicalOp(ref ct) => {
+            let (left, right) = (negated(&ct.left)?, negated(&ct.right)?);
+            let tree = match ct.operator {
+                Operator::And => ConditionTree {
+                    operator: Operator::Or,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                },
+                Operator::Or => ConditionTree {
+                    operator: Operator::And,
+                    left: Box::new(bracketed_if_or(left)),
+                    right: Box::new(bracketed_if_or(right)),
+                },
+                _ => return None,
+            };
+            Some(ConditionExpression::LogicalOp(tree))
+        }
+        _ => None,
+    }
+}
+
+// `under_and` is set if `expr` is a side of an AND.
+fn push_down_negations_recursive(
+    expr: &mut ConditionExpression,
+    under_and: bool,
+) -> Result<(), Error> {
+    match *expr {
+        ConditionExpression::NegationOp(ref mut ce) => {
+            push_down_negations_recursive(ce, false)?;
+            match negated(ce) {
+                Some(ne) if under_and => *expr = bracketed_if_or(ne),
+                Some(ne) => *expr = ne,
+                None => return Err(Error::NotSupported(format!("negated condition '{}'", expr))),
+            }
+        }
+        ConditionExpression::Bracketed(ref mut ce) => push_down_negations_recursive(ce, false)?,
+        ConditionExpression::LogicalOp(ref mut ct) => {
+            let and = ct.operator == Operator::And;
+            push_down_negations_recursive(&mut ct.left, and)?;
+            push_down_negations_recursive(&mut ct.right, and)?;
+        }
+        ConditionExpression::ComparisonOp(ref mut ct) => {
+            push_down_negations_recursive(&mut ct.left, false)?;
+            push_down_negations_recursive(&mut ct.right, false)?;
+        }
+        ConditionExpression::Base(ConditionBase::NestedSelect(ref mut sq)) => {
+            if let Some(ref mut w) = sq.where_clause {
+                push_down_negations_recursive(w, false)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Rewrites negated conditions in the WHERE-clause of `query` into the opposite comparisons
+/// (e.g., `NOT (t.a < 5)` into `t.a >= 5`), and returns an error for those without one.
+///
+/// noria-server removes negations itself, but keeps the brackets after a NOT, and its query
+/// graph drops the comparisons inside brackets, so that `NOT (t.a < 5)` would match every row.
+/// It cannot remove a NOT in front of IN or LIKE at all. The opposite comparison also lets the
+/// adapter apply it to the rows of a view when Noria cannot look up by it (see
+/// `extract_post_filters`).
+pub(crate) fn push_down_negations(query: &mut SqlQuery) -> Result<(), Error> {
+    let where_clause = match *query {
+        SqlQuery::Select(ref mut q) => q.where_clause.as_mut(),
+        SqlQuery::Update(ref mut q) => q.where_clause.as_mut(),
+        SqlQuery::Delete(ref mut q) => q.where_clause.as_mut(),
+        _ => None,
+    };
+    match where_clause {
+        Some(w) => push_down_negations_recursive(w, false),
+        None => Ok(()),
+    }
+}
+
+/// Turns an ad-hoc query into the parameterized query that serves it, and returns the keys to
+/// look that query's view up with.
+///
+/// Literals are lifted into parameters so that ad-hoc queries of the same shape share a view,
+/// and WHERE-IN clauses are collapsed into one lookup per combination of listed values. Whether
+/// any were is returned as well, since the results of such a query's keys have to be merged
+/// (see `prepare_reaggregation`), however many keys the listed values make.
+pub(crate) fn parameterize_query(
+    query: &mut SqlQuery,
+) -> Result<(Vec<Vec<DataType>>, bool), Error> {
+    let lifted = auto_parameterize_query(query);
+    let collapsed = collapse_where_in(query, true)?;
+    let params = lifted
+        .into_iter()
+        .map(|l| l.to_datatype())
+        .collect::<Result<_, _>>()?;
+    let keys = utils::where_in_keys(params, &collapsed)?;
+    Ok((keys, !collapsed.is_empty()))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use nom_sql;
+
+    fn collapse_one(q: &mut SqlQuery, rewrite_literals: bool) -> (usize, Vec<Literal>) {
+        let mut rewritten = collapse_where_in(q, rewrite_literals).unwrap();
+        assert_eq!(rewritten.len(), 1);
+        rewritten.remove(0)
+    }
 
     #[test]
     fn collapsed_where_placeholders() {
         let mut q = nom_sql::parse_query("SELECT * FROM x WHERE x.y IN (?, ?, ?)").unwrap();
-        let rewritten = collapse_where_in(&mut q, false).unwrap();
+        let rewritten = collapse_one(&mut q, false);
         assert_eq!(rewritten.0, 0);
         assert_eq!(rewritten.1.len(), 3);
         assert_eq!(
@@ -145,7 +978,7 @@ mod tests {
         );
 
         let mut q = nom_sql::parse_query("SELECT * FROM x WHERE y IN (?, ?, ?)").unwrap();
-        let rewritten = collapse_where_in(&mut q, false).unwrap();
+        let rewritten = collapse_one(&mut q, false);
         assert_eq!(rewritten.0, 0);
         assert_eq!(rewritten.1.len(), 3);
         assert_eq!(
@@ -154,7 +987,7 @@ mod tests {
         );
 
         let mut q = nom_sql::parse_query("SELECT * FROM x WHERE AVG(y) IN (?, ?, ?)").unwrap();
-        let rewritten = collapse_where_in(&mut q, false).unwrap();
+        let rewritten = collapse_one(&mut q, false);
         assert_eq!(rewritten.0, 0);
         assert_eq!(rewritten.1.len(), 3);
         assert_eq!(
@@ -164,7 +997,7 @@ mod tests {
 
         let mut q = nom_sql::parse_query("SELECT * FROM t WHERE x = ? AND y IN (?, ?, ?) OR z = ?")
             .unwrap();
-        let rewritten = collapse_where_in(&mut q, false).unwrap();
+        let rewritten = collapse_one(&mut q, false);
         assert_eq!(rewritten.0, 1);
         assert_eq!(rewritten.1.len(), 3);
         assert_eq!(
@@ -176,7 +1009,7 @@ mod tests {
             "SELECT * FROM t WHERE x IN (SELECT * FROM z WHERE a = ?) AND y IN (?, ?) OR z = ?",
         )
         .unwrap();
-        let rewritten = collapse_where_in(&mut q, false).unwrap();
+        let rewritten = collapse_one(&mut q, false);
         assert_eq!(rewritten.0, 1);
         assert_eq!(rewritten.1.len(), 2);
         assert_eq!(
@@ -191,7 +1024,7 @@ mod tests {
             "SELECT * FROM t WHERE x IN (SELECT * FROM z WHERE b = ? AND a IN (?, ?)) OR z = ?",
         )
         .unwrap();
-        let rewritten = collapse_where_in(&mut q, false).unwrap();
+        let rewritten = collapse_one(&mut q, false);
         assert_eq!(rewritten.0, 1);
         assert_eq!(rewritten.1.len(), 2);
         assert_eq!(
@@ -203,17 +1036,116 @@ mod tests {
         );
     }
 
+    #[test]
+    fn collapsed_where_multiple() {
+        let mut q = nom_sql::parse_query(
+            "SELECT * FROM t WHERE t.a IN (?, ?) AND t.b = ? AND t.c IN (?, ?, ?)",
+        )
+        .unwrap();
+        let rewritten = collapse_where_in(&mut q, false).unwrap();
+        assert_eq!(rewritten.len(), 2);
+        assert_eq!((rewritten[0].0, rewritten[0].1.len()), (0, 2));
+        assert_eq!((rewritten[1].0, rewritten[1].1.len()), (3, 3));
+        assert_eq!(
+            q,
+            nom_sql::parse_query("SELECT * FROM t WHERE t.a = ? AND t.b = ? AND t.c = ?").unwrap()
+        );
+
+        let mut q = nom_sql::parse_query(
+            "SELECT * FROM t WHERE t.a IN (1, 2) AND t.b = 3 AND t.c IN (4, 5)",
+        )
+        .unwrap();
+        let expected: Vec<Vec<DataType>> = vec![[1, 3, 4], [1, 3, 5], [2, 3, 4], [2, 3, 5]]
+            .iter()
+            .map(|k| {
+                k.iter()
+                    .map(|&i| Literal::Integer(i).to_datatype().unwrap())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(parameterize_query(&mut q).unwrap(), (expected, true));
+        assert_eq!(
+            q,
+            nom_sql::parse_query("SELECT * FROM t WHERE t.a = ? AND t.b = ? AND t.c = ?").unwrap()
+        );
+
+        // a single listed value still needs its result merged, like several would
+        let mut q = nom_sql::parse_query("SELECT * FROM t WHERE t.a IN (1)").unwrap();
+        let expected: Vec<Vec<DataType>> = vec![vec![Literal::Integer(1).to_datatype().unwrap()]];
+        assert_eq!(parameterize_query(&mut q).unwrap(), (expected, true));
+        let mut q = nom_sql::parse_query("SELECT * FROM t WHERE t.a = 1").unwrap();
+        let expected: Vec<Vec<DataType>> = vec![vec![Literal::Integer(1).to_datatype().unwrap()]];
+        assert_eq!(parameterize_query(&mut q).unwrap(), (expected, false));
+    }
+
+    #[test]
+    fn collapsed_or_equalities() {
+        let mut q =
+            nom_sql::parse_query("SELECT * FROM t WHERE t.id = ? OR t.id = 5 OR ? = t.id").unwrap();
+        let rewritten = collapse_one(&mut q, false);
+        assert_eq!(
+            rewritten,
+            (
+                0,
+                vec![
+                    Literal::Placeholder,
+                    Literal::Integer(5),
+                    Literal::Placeholder
+                ]
+            )
+        );
+        assert_eq!(
+            q,
+            nom_sql::parse_query("SELECT * FROM t WHERE t.id = ?").unwrap()
+        );
+
+        let mut q =
+            nom_sql::parse_query("SELECT * FROM t WHERE t.x = ? AND (t.id = 1 OR t.id = 2)")
+                .unwrap();
+        let rewritten = collapse_one(&mut q, true);
+        assert_eq!(
+            rewritten,
+            (1, vec![Literal::Integer(1), Literal::Integer(2)])
+        );
+        assert_eq!(
+            q,
+            nom_sql::parse_query("SELECT * FROM t WHERE t.x = ? AND (t.id = ?)").unwrap()
+        );
+
+        // without placeholders, a prepared query keeps its disjunction
+        let mut q = nom_sql::parse_query("SELECT * FROM t WHERE t.id = 1 OR t.id = 2").unwrap();
+        assert!(collapse_where_in(&mut q, false).unwrap().is_empty());
+        assert_eq!(
+            q,
+            nom_sql::parse_query("SELECT * FROM t WHERE t.id = 1 OR t.id = 2").unwrap()
+        );
+
+        // and so do disjunctions over different columns
+        let mut q = nom_sql::parse_query("SELECT * FROM t WHERE t.id = ? OR t.x = ?").unwrap();
+        assert!(collapse_where_in(&mut q, false).unwrap().is_empty());
+        assert_eq!(
+            q,
+            nom_sql::parse_query("SELECT * FROM t WHERE t.id = ? OR t.x = ?").unwrap()
+        );
+
+        // equal values of a disjunction give a single lookup
+        let mut q = nom_sql::parse_query("SELECT * FROM t WHERE t.id = ? OR t.id = ?").unwrap();
+        let rewritten = collapse_where_in(&mut q, false).unwrap();
+        let keys = utils::where_in_keys(vec![7.into(), 7.into()], &rewritten).unwrap();
+        assert_eq!(keys, vec![vec![DataType::from(7)]]);
+    }
+
     #[test]
     fn collapsed_where_literals() {
         let mut q = nom_sql::parse_query("SELECT * FROM x WHERE x.y IN (1, 2, 3)").unwrap();
-        assert_eq!(collapse_where_in(&mut q, false), None);
+        assert!(collapse_where_in(&mut q, false).unwrap().is_empty());
         assert_eq!(
             q,
             nom_sql::parse_query("SELECT * FROM x WHERE x.y IN (1, 2, 3)").unwrap()
         );
 
         let mut q = nom_sql::parse_query("SELECT * FROM x WHERE x.y IN (1, 2, 3)").unwrap();
-        let rewritten = collapse_where_in(&mut q, true).unwrap();
+        let rewritten = collapse_one(&mut q, true);
         assert_eq!(rewritten.0, 0);
         assert_eq!(rewritten.1.len(), 3);
         assert_eq!(
@@ -222,7 +1154,7 @@ mod tests {
         );
 
         let mut q = nom_sql::parse_query("SELECT * FROM x WHERE y IN (1, 2, 3)").unwrap();
-        let rewritten = collapse_where_in(&mut q, true).unwrap();
+        let rewritten = collapse_one(&mut q, true);
         assert_eq!(rewritten.0, 0);
         assert_eq!(rewritten.1.len(), 3);
         assert_eq!(
@@ -231,7 +1163,7 @@ mod tests {
         );
 
         let mut q = nom_sql::parse_query("SELECT * FROM x WHERE AVG(y) IN (1, 2, 3)").unwrap();
-        let rewritten = collapse_where_in(&mut q, true).unwrap();
+        let rewritten = collapse_one(&mut q, true);
         assert_eq!(rewritten.0, 0);
         assert_eq!(rewritten.1.len(), 3);
         assert_eq!(
@@ -240,10 +1172,312 @@ mod tests {
         );
     }
 
+    #[test]
+    fn auto_parameterized_equalities() {
+        let mut q = nom_sql::parse_query("SELECT * FROM x WHERE x.y = 1").unwrap();
+        assert_eq!(auto_parameterize_query(&mut q), vec![Literal::Integer(1)]);
+        assert_eq!(
+            q,
+            nom_sql::parse_query("SELECT * FROM x WHERE x.y = ?").unwrap()
+        );
+
+        let mut q =
+            nom_sql::parse_query("SELECT * FROM t WHERE t.x = 'foo' AND t.y > 3 AND t.z = 4")
+                .unwrap();
+        assert_eq!(
+            auto_parameterize_query(&mut q),
+            vec![Literal::String("foo".into()), Literal::Integer(4)]
+        );
+        assert_eq!(
+            q,
+            nom_sql::parse_query("SELECT * FROM t WHERE t.x = ? AND t.y > 3 AND t.z = ?").unwrap()
+        );
+
+        // literals in disjunctions stay put
+        let mut q = nom_sql::parse_query("SELECT * FROM t WHERE t.x = 1 OR t.y = 2").unwrap();
+        assert!(auto_parameterize_query(&mut q).is_empty());
+        assert_eq!(
+            q,
+            nom_sql::parse_query("SELECT * FROM t WHERE t.x = 1 OR t.y = 2").unwrap()
+        );
+
+        // lifted literals and collapsed IN lists share one parameter numbering
+        let mut q =
+            nom_sql::parse_query("SELECT * FROM t WHERE t.x = 1 AND t.y IN (2, 3) AND t.z = 4")
+                .unwrap();
+        assert_eq!(
+            auto_parameterize_query(&mut q),
+            vec![Literal::Integer(1), Literal::Integer(4)]
+        );
+        let rewritten = collapse_one(&mut q, true);
+        assert_eq!(rewritten.0, 1);
+        assert_eq!(rewritten.1, vec![Literal::Integer(2), Literal::Integer(3)]);
+        assert_eq!(
+            q,
+            nom_sql::parse_query("SELECT * FROM t WHERE t.x = ? AND t.y = ? AND t.z = ?").unwrap()
+        );
+    }
+
+    #[test]
+    fn post_filters() {
+        let select = |q: &str| match nom_sql::parse_query(q).unwrap() {
+            SqlQuery::Select(q) => q,
+            _ => unreachable!(),
+        };
+
+        let mut q = select("SELECT t.a FROM t WHERE t.x = ? AND t.y > ? AND ? >= t.z AND t.b = ?");
+        let (filters, hidden) = extract_post_filters(&mut q).unwrap();
+        assert_eq!(
+            filters,
+            vec![
+                PostFilter {
+                    param: 1,
+                    column: "__filter_0".into(),
+                    operator: Operator::Greater,
+                },
+                PostFilter {
+                    param: 2,
+                    column: "__filter_1".into(),
+                    operator: Operator::LessOrEqual,
+                },
+            ]
+        );
+        assert_eq!(
+            hidden,
+            vec![String::from("__filter_0"), String::from("__filter_1")]
+        );
+        assert_eq!(
+            q,
+            select(
+                "SELECT t.a, t.y AS __filter_0, t.z AS __filter_1 FROM t \
+                 WHERE t.x = ? AND t.b = ?"
+            )
+        );
+
+        // equalities stay in the query
+        let mut q = select("SELECT * FROM t WHERE t.name LIKE ?");
+        let (filters, _) = extract_post_filters(&mut q).unwrap();
+        assert_eq!(filters.len(), 1);
+        assert_eq!(q.where_clause, None);
+
+        let mut q = select("SELECT t.a FROM t WHERE t.x = ?");
+        assert_eq!(extract_post_filters(&mut q).unwrap(), (vec![], vec![]));
+        assert_eq!(q, select("SELECT t.a FROM t WHERE t.x = ?"));
+
+        // filters compare the column they name, whatever is selected under that name
+        let mut q = select(
+            "SELECT u.name AS age, v.age FROM u, v WHERE u.id = v.uid AND u.age > ? AND v.age < ?",
+        );
+        extract_post_filters(&mut q).unwrap();
+        assert_eq!(
+            q,
+            select(
+                "SELECT u.name AS age, v.age, u.age AS __filter_0, v.age AS __filter_1 \
+                 FROM u, v WHERE u.id = v.uid"
+            )
+        );
+
+        // filtering would change the result of aggregates and limits
+        let mut q = select("SELECT COUNT(t.a) FROM t WHERE t.y > ?");
+        assert!(extract_post_filters(&mut q).is_err());
+        let mut q = select("SELECT t.a FROM t WHERE t.y > ? LIMIT 3");
+        assert!(extract_post_filters(&mut q).is_err());
+
+        // and rows cannot be filtered by one side of an OR
+        let mut q = select("SELECT t.a FROM t WHERE t.x = ? OR t.y > ?");
+        assert!(extract_post_filters(&mut q).is_err());
+        let mut q = select("SELECT t.a FROM t WHERE t.x = ? AND NOT (t.name LIKE ?)");
+        assert!(extract_post_filters(&mut q).is_err());
+
+        // an extra column changes which rows are distinct
+        let mut q = select("SELECT DISTINCT t.a FROM t WHERE t.y > ?");
+        assert!(extract_post_filters(&mut q).is_err());
+        let mut q = select("SELECT DISTINCT t.a, t.y FROM t WHERE t.y > ?");
+        assert!(extract_post_filters(&mut q).is_ok());
+    }
+
+    #[test]
+    fn conjunctive_where_clauses() {
+        let where_clause = |q: &str| match nom_sql::parse_query(q).unwrap() {
+            SqlQuery::Select(q) => q.where_clause.unwrap(),
+            _ => unreachable!(),
+        };
+
+        let w = where_clause(
+            "SELECT * FROM t WHERE t.x = ? AND (3 < t.y AND t.z LIKE 'a%') AND ? = t.w",
+        );
+        let (lookups, comparisons) = conjunctive_comparisons(&w).unwrap();
+        assert_eq!(lookups, vec![&Column::from("t.x"), &Column::from("t.w")]);
+        assert_eq!(
+            comparisons,
+            vec![
+                (&Column::from("t.y"), Operator::Greater, DataType::from(3)),
+                (&Column::from("t.z"), Operator::Like, DataType::from("a%")),
+            ]
+        );
+
+        // the adapter cannot tell which rows match anything else
+        let w = where_clause("SELECT * FROM t WHERE t.x = ? OR t.y = 3");
+        assert!(conjunctive_comparisons(&w).is_none());
+        let w = where_clause("SELECT * FROM t WHERE NOT (t.x = 3)");
+        assert!(conjunctive_comparisons(&w).is_none());
+        let w = where_clause("SELECT * FROM t WHERE t.x > ?");
+        assert!(conjunctive_comparisons(&w).is_none());
+        let w = where_clause("SELECT * FROM t WHERE t.x = t.y");
+        assert!(conjunctive_comparisons(&w).is_none());
+    }
+
+    #[test]
+    fn pushed_down_negations() {
+        let pushed_down = |q: &str| {
+            let mut q = nom_sql::parse_query(q).unwrap();
+            push_down_negations(&mut q).unwrap();
+            match q {
+                SqlQuery::Select(q) => q.where_clause.unwrap().to_string(),
+                SqlQuery::Delete(q) => q.where_clause.unwrap().to_string(),
+                _ => unreachable!(),
+            }
+        };
+
+        assert_eq!(
+            pushed_down("SELECT t.a FROM t WHERE t.x = ? AND NOT (t.y < ?)"),
+            "t.x = ? AND t.y >= ?"
+        );
+        assert_eq!(
+            pushed_down("DELETE FROM t WHERE NOT (t.x = 'a')"),
+            "t.x != 'a'"
+        );
+        // De Morgan's laws only add brackets where an OR would otherwise come apart
+        assert_eq!(
+            pushed_down("SELECT t.a FROM t WHERE t.x = 1 AND NOT (t.y > 2 OR t.z = 3)"),
+            "t.x = 1 AND t.y <= 2 AND t.z != 3"
+        );
+        assert_eq!(
+            pushed_down("DELETE FROM t WHERE NOT (t.y > 2 AND t.z = 3)"),
+            "t.y <= 2 OR t.z != 3"
+        );
+        assert_eq!(
+            pushed_down("SELECT t.a FROM t WHERE t.x = 1 AND NOT (t.y > 2 AND t.z = 3)"),
+            "t.x = 1 AND (t.y <= 2 OR t.z != 3)"
+        );
+        assert_eq!(
+            pushed_down("SELECT t.a FROM t WHERE NOT ((t.x = 1 AND t.y > 2) OR t.z = 3)"),
+            "(t.x != 1 OR t.y <= 2) AND t.z != 3"
+        );
+        assert_eq!(
+            pushed_down("SELECT t.a FROM t WHERE NOT (NOT (t.y > 2))"),
+            "t.y > 2"
+        );
+
+        // Noria cannot remove a NOT in front of IN or LIKE
+        let not_supported = |q: &str| {
+            let mut q = nom_sql::parse_query(q).unwrap();
+            matches!(push_down_negations(&mut q), Err(Error::NotSupported(_)))
+        };
+        assert!(not_supported("SELECT t.a FROM t WHERE NOT (t.y IN (1, 2))"));
+        assert!(not_supported(
+            "DELETE FROM t WHERE t.x = 1 AND NOT (t.name LIKE 'a%')"
+        ));
+        assert!(not_supported(
+            "SELECT t.a FROM t WHERE NOT (t.x = 1 OR t.y IN (1, 2))"
+        ));
+    }
+
+    #[test]
+    fn bound_lookup_params() {
+        let select = |q: &str| match nom_sql::parse_query(q).unwrap() {
+            SqlQuery::Select(q) => q,
+            _ => unreachable!(),
+        };
+        let ts = chrono::NaiveDate::from_ymd(2020, 1, 2).and_hms(3, 4, 5);
+
+        let mut q = select("SELECT t.id FROM t WHERE t.x = 1 AND 25 > t.expires AND t.name LIKE ?");
+        let values = bind_lookup_params(&mut q, vec![DataType::Timestamp(ts)]).unwrap();
+        // parameters keep their values as they are, rather than going through a literal
+        assert_eq!(values, vec![1.into(), 25.into(), DataType::Timestamp(ts)]);
+        let (filters, _) = extract_post_filters(&mut q).unwrap();
+        assert_eq!(
+            filters.iter().map(|f| f.param).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(
+            q.where_clause.map(|w| w.to_string()),
+            Some(String::from("t.x = ?"))
+        );
+
+        // comparisons under OR or NOT stay in the query, with their parameters bound
+        let mut q = select(
+            "SELECT t.id FROM t WHERE (t.x = 1 OR t.expires < ?) AND NOT (t.y > 3) AND t.z < ?",
+        );
+        let values = bind_lookup_params(&mut q, vec![5.into(), 4.into()]).unwrap();
+        assert_eq!(values, vec![4.into()]);
+        assert_eq!(
+            q.where_clause.as_ref().map(|w| w.to_string()),
+            Some(String::from(
+                "(t.x = 1 OR t.expires < 5) AND NOT (t.y > 3) AND t.z < ?"
+            ))
+        );
+        let (filters, _) = extract_post_filters(&mut q).unwrap();
+        assert_eq!(filters.iter().map(|f| f.param).collect::<Vec<_>>(), vec![0]);
+
+        // disjunctions of equalities are lookups too
+        let mut q = select("SELECT t.id FROM t WHERE t.x = ? OR t.x = 2");
+        let values = bind_lookup_params(&mut q, vec![1.into()]).unwrap();
+        assert_eq!(values, vec![1.into()]);
+        let mut q = SqlQuery::Select(q);
+        let collapsed = collapse_where_in(&mut q, true).unwrap();
+        assert_eq!(
+            utils::where_in_keys(values, &collapsed).unwrap(),
+            vec![vec![1.into()], vec![2.into()]]
+        );
+
+        // Noria cannot compare with timestamps that it is not looked up by
+        let mut q = select("SELECT t.id FROM t WHERE t.x = 1 OR t.y < ?");
+        assert!(bind_lookup_params(&mut q, vec![DataType::Timestamp(ts)]).is_err());
+        let mut q = select("SELECT t.id FROM t WHERE t.x = ?");
+        assert!(bind_lookup_params(&mut q, vec![]).is_err());
+    }
+
+    #[test]
+    fn reaggregation() {
+        let select = |q: &str| match nom_sql::parse_query(q).unwrap() {
+            SqlQuery::Select(q) => q,
+            _ => unreachable!(),
+        };
+
+        let mut q = select("SELECT AVG(t.x) AS a, COUNT(*) FROM t WHERE t.y = ? GROUP BY t.g");
+        let hidden = prepare_reaggregation(&mut q);
+        assert_eq!(hidden, vec![String::from("__count_a"), String::from("g")]);
+        assert_eq!(
+            q,
+            select(
+                "SELECT AVG(t.x) AS a, COUNT(*), COUNT(t.x) AS __count_a, t.g FROM t \
+                 WHERE t.y = ? GROUP BY t.g"
+            )
+        );
+        assert_eq!(average_count_column("avg(t.x)"), "__count_avg_t_x_");
+
+        // queries without averages or unselected grouping columns stay as they are
+        let mut q = select("SELECT t.g, MAX(t.x) FROM t WHERE t.y = ? GROUP BY t.g");
+        assert!(prepare_reaggregation(&mut q).is_empty());
+        assert_eq!(
+            q,
+            select("SELECT t.g, MAX(t.x) FROM t WHERE t.y = ? GROUP BY t.g")
+        );
+
+        // each key has to return the rows that the merged result skips
+        let mut q = select("SELECT t.x FROM t WHERE t.y = ? ORDER BY t.x LIMIT 2 OFFSET 3");
+        assert!(prepare_reaggregation(&mut q).is_empty());
+        assert_eq!(
+            q,
+            select("SELECT t.x FROM t WHERE t.y = ? ORDER BY t.x LIMIT 5")
+        );
+    }
+
     #[test]
     fn noninterference() {
         let mut q = nom_sql::parse_query("SELECT * FROM x WHERE x.y = 'foo'").unwrap();
-        assert_eq!(collapse_where_in(&mut q, true), None);
+        assert!(collapse_where_in(&mut q, true).unwrap().is_empty());
         assert_eq!(
             q,
             nom_sql::parse_query("SELECT * FROM x WHERE x.y = 'foo'").unwrap()
@@ -1,6 +1,7 @@
-use msql_srv;
 use nom_sql::{self, ColumnConstraint, ColumnSpecification, CreateTableStatement, SqlType};
 
+use crate::errors::Error;
+
 #[derive(Debug)]
 pub enum Schema {
     Table(CreateTableStatement),
@@ -9,93 +10,107 @@ pub enum Schema {
 
 pub(crate) fn convert_column(cs: &ColumnSpecification) -> msql_srv::Column {
     let mut flags = msql_srv::ColumnFlags::empty();
+    let coltype = match cs.sql_type {
+        SqlType::Tinytext => msql_srv::ColumnType::MYSQL_TYPE_VAR_STRING,
+        SqlType::Mediumtext => msql_srv::ColumnType::MYSQL_TYPE_VAR_STRING,
+        SqlType::Longtext => msql_srv::ColumnType::MYSQL_TYPE_BLOB,
+        SqlType::Text => msql_srv::ColumnType::MYSQL_TYPE_STRING,
+        SqlType::Char(_) => msql_srv::ColumnType::MYSQL_TYPE_STRING,
+        SqlType::Varchar(_) => msql_srv::ColumnType::MYSQL_TYPE_VAR_STRING,
+        SqlType::Enum(_) => {
+            flags |= msql_srv::ColumnFlags::ENUM_FLAG;
+            msql_srv::ColumnType::MYSQL_TYPE_STRING
+        }
+        SqlType::Binary(_) => {
+            flags |= msql_srv::ColumnFlags::BINARY_FLAG;
+            msql_srv::ColumnType::MYSQL_TYPE_STRING
+        }
+        SqlType::Varbinary(_) => {
+            flags |= msql_srv::ColumnFlags::BINARY_FLAG;
+            msql_srv::ColumnType::MYSQL_TYPE_VAR_STRING
+        }
+        SqlType::Tinyblob | SqlType::Blob | SqlType::Mediumblob | SqlType::Longblob => {
+            flags |= msql_srv::ColumnFlags::BLOB_FLAG | msql_srv::ColumnFlags::BINARY_FLAG;
+            msql_srv::ColumnType::MYSQL_TYPE_BLOB
+        }
+        SqlType::Int(_) => msql_srv::ColumnType::MYSQL_TYPE_LONG,
+        SqlType::UnsignedInt(_) => {
+            flags |= msql_srv::ColumnFlags::UNSIGNED_FLAG;
+            msql_srv::ColumnType::MYSQL_TYPE_LONG
+        }
+        SqlType::Bigint(_) => msql_srv::ColumnType::MYSQL_TYPE_LONGLONG,
+        SqlType::UnsignedBigint(_) => {
+            flags |= msql_srv::ColumnFlags::UNSIGNED_FLAG;
+            msql_srv::ColumnType::MYSQL_TYPE_LONGLONG
+        }
+        SqlType::Tinyint(_) => msql_srv::ColumnType::MYSQL_TYPE_TINY,
+        SqlType::UnsignedTinyint(_) => {
+            flags |= msql_srv::ColumnFlags::UNSIGNED_FLAG;
+            msql_srv::ColumnType::MYSQL_TYPE_TINY
+        }
+        SqlType::Bool => msql_srv::ColumnType::MYSQL_TYPE_BIT,
+        SqlType::Date => msql_srv::ColumnType::MYSQL_TYPE_DATE,
+        SqlType::DateTime(_) => msql_srv::ColumnType::MYSQL_TYPE_DATETIME,
+        SqlType::Timestamp => msql_srv::ColumnType::MYSQL_TYPE_TIMESTAMP,
+        // reals are sent to the client as doubles
+        SqlType::Float | SqlType::Double | SqlType::Real => msql_srv::ColumnType::MYSQL_TYPE_DOUBLE,
+        SqlType::Decimal(_, _) => msql_srv::ColumnType::MYSQL_TYPE_DECIMAL,
+    };
+    for c in &cs.constraints {
+        match *c {
+            ColumnConstraint::AutoIncrement => {
+                flags |= msql_srv::ColumnFlags::AUTO_INCREMENT_FLAG;
+            }
+            ColumnConstraint::NotNull => {
+                flags |= msql_srv::ColumnFlags::NOT_NULL_FLAG;
+            }
+            ColumnConstraint::PrimaryKey => {
+                flags |= msql_srv::ColumnFlags::PRI_KEY_FLAG;
+            }
+            ColumnConstraint::Unique => {
+                flags |= msql_srv::ColumnFlags::UNIQUE_KEY_FLAG;
+            }
+            _ => (),
+        }
+    }
+
     msql_srv::Column {
-        table: cs.column.table.clone().unwrap_or("".to_owned()),
+        table: cs.column.table.clone().unwrap_or_default(),
         column: cs.column.name.clone(),
-        coltype: match cs.sql_type {
-            SqlType::Mediumtext => msql_srv::ColumnType::MYSQL_TYPE_VAR_STRING,
-            SqlType::Longtext => msql_srv::ColumnType::MYSQL_TYPE_BLOB,
-            SqlType::Text => msql_srv::ColumnType::MYSQL_TYPE_STRING,
-            SqlType::Varchar(_) => msql_srv::ColumnType::MYSQL_TYPE_VAR_STRING,
-            SqlType::Int(_) => msql_srv::ColumnType::MYSQL_TYPE_LONG,
-            SqlType::UnsignedInt(_) => {
-                flags |= msql_srv::ColumnFlags::UNSIGNED_FLAG;
-                msql_srv::ColumnType::MYSQL_TYPE_LONG
-            },
-            SqlType::Bigint(_) => msql_srv::ColumnType::MYSQL_TYPE_LONGLONG,
-            SqlType::UnsignedBigint(_) => {
-                flags |= msql_srv::ColumnFlags::UNSIGNED_FLAG;
-                msql_srv::ColumnType::MYSQL_TYPE_LONGLONG
-            },
-            SqlType::Tinyint(_) => msql_srv::ColumnType::MYSQL_TYPE_TINY,
-            SqlType::UnsignedTinyint(_) => {
-                flags |= msql_srv::ColumnFlags::UNSIGNED_FLAG;
-                msql_srv::ColumnType::MYSQL_TYPE_TINY
-            },
-            SqlType::Bool => msql_srv::ColumnType::MYSQL_TYPE_BIT,
-            SqlType::DateTime(_) => msql_srv::ColumnType::MYSQL_TYPE_DATETIME,
-            SqlType::Float => msql_srv::ColumnType::MYSQL_TYPE_DOUBLE,
-            SqlType::Decimal(_, _) => msql_srv::ColumnType::MYSQL_TYPE_DECIMAL,
-            _ => unimplemented!(),
-        },
-        colflags: {
-            for c in &cs.constraints {
-                match *c {
-                    ColumnConstraint::AutoIncrement => {
-                        flags |= msql_srv::ColumnFlags::AUTO_INCREMENT_FLAG;
-                    }
-                    ColumnConstraint::NotNull => {
-                        flags |= msql_srv::ColumnFlags::NOT_NULL_FLAG;
-                    }
-                    ColumnConstraint::PrimaryKey => {
-                        flags |= msql_srv::ColumnFlags::PRI_KEY_FLAG;
-                    }
-                    ColumnConstraint::Unique => {
-                        flags |= msql_srv::ColumnFlags::UNIQUE_KEY_FLAG;
-                    }
-                    _ => (),
-                }
-            }
-            flags
-        },
+        coltype,
+        colflags: flags,
     }
 }
 
 pub(crate) fn convert_schema(schema: &Schema) -> Vec<msql_srv::Column> {
     match schema {
         Schema::Table(CreateTableStatement { ref fields, .. }) | Schema::View(ref fields) => {
-            fields.iter().map(|c| convert_column(c)).collect()
+            fields.iter().map(convert_column).collect()
         }
     }
 }
 
-pub(crate) fn schema_for_column(schema: &Schema, c: &nom_sql::Column) -> msql_srv::Column {
-    if let Some(ref table) = c.table {
-        match schema {
+pub(crate) fn schema_for_column(
+    schema: &Schema,
+    c: &nom_sql::Column,
+) -> Result<msql_srv::Column, Error> {
+    if c.table.is_some() {
+        let colspec = match schema {
             Schema::Table(CreateTableStatement { ref fields, .. }) => {
-                let colspec = fields
-                    .iter()
-                    .find(|cc| cc.column.name == c.name)
-                    .expect(&format!("column {} not found", c.name));
-
-                assert_eq!(colspec.column.name, c.name);
-
-                convert_column(colspec)
+                fields.iter().find(|cc| cc.column.name == c.name)
             }
-            Schema::View(ref fields) => convert_column(
-                fields
-                    .iter()
-                    .find(|cs| cs.column == *c)
-                    .expect(&format!("column {} not found in view {}", c.name, table)),
-            ),
-        }
+            Schema::View(ref fields) => fields.iter().find(|cs| cs.column == *c),
+        };
+        colspec
+            .map(convert_column)
+            .ok_or_else(|| Error::BadField(c.name.clone()))
     } else {
         // no table specified on column
-        msql_srv::Column {
+        Ok(msql_srv::Column {
             table: "".into(),
             column: c.name.clone(),
             coltype: msql_srv::ColumnType::MYSQL_TYPE_LONG,
             colflags: msql_srv::ColumnFlags::empty(),
-        }
+        })
     }
 }
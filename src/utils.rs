@@ -1,16 +1,24 @@
-use std::collections::HashSet;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 
-use crate::convert::ToDataType;
-use msql_srv::ParamParser;
+use crate::convert::{self, ToDataType};
+use crate::errors::Error;
+use msql_srv::{ColumnFlags, ColumnType, ParamParser};
 use nom_sql::{
     ArithmeticBase, ArithmeticExpression, ArithmeticOperator, Column, ColumnConstraint,
-    ConditionBase, ConditionExpression, ConditionTree, CreateTableStatement, FieldValueExpression,
-    Literal, LiteralExpression, Operator, SelectStatement, SqlQuery, TableKey, UpdateStatement,
+    ConditionBase, ConditionExpression, ConditionTree, CreateTableStatement,
+    FieldDefinitionExpression, FieldValueExpression, FunctionExpression, InsertStatement,
+    JoinRightSide, LimitClause, Literal, LiteralExpression, Operator, OrderType, SelectStatement,
+    SqlQuery, Table, TableKey, UpdateStatement,
 };
-use noria::{DataType, Modification, Operation};
+use noria::{DataType, Modification, Operation, TableOperation};
 use regex::Regex;
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::slice;
+
+use crate::rewrite;
+use crate::schema;
 
 lazy_static! {
     pub(crate) static ref HARD_CODED_REPLIES: Vec<(Regex, Vec<(&'static str, &'static str)>)> = vec![
@@ -52,6 +60,10 @@ lazy_static! {
     ];
     pub(crate) static ref COLLAPSE_SPACES: (Regex, &'static str) =
         (Regex::new(r" +").unwrap(), " ");
+    static ref INSERT_SELECT: Regex = Regex::new(
+        r"(?is)^\s*INSERT\s+(IGNORE\s+)?INTO\s+`?(\w+)`?\s*(?:\(([^)]*)\))?\s*(SELECT\s.*)$"
+    )
+    .unwrap();
 }
 
 pub(crate) fn hash_select_query(q: &SelectStatement) -> u64 {
@@ -63,6 +75,431 @@ pub(crate) fn hash_select_query(q: &SelectStatement) -> u64 {
     h.finish()
 }
 
+/// The tables a SELECT reads from, including the ones it joins with.
+pub(crate) fn select_tables(q: &SelectStatement) -> Vec<&Table> {
+    fn join_tables<'a>(right: &'a JoinRightSide, tables: &mut Vec<&'a Table>) {
+        match *right {
+            JoinRightSide::Table(ref t) => tables.push(t),
+            JoinRightSide::Tables(ref ts) => tables.extend(ts.iter()),
+            JoinRightSide::NestedSelect(ref q, _) => tables.extend(select_tables(q)),
+            JoinRightSide::NestedJoin(ref j) => join_tables(&j.right, tables),
+        }
+    }
+
+    let mut tables: Vec<_> = q.tables.iter().collect();
+    for j in &q.join {
+        join_tables(&j.right, &mut tables);
+    }
+    tables
+}
+
+/// Turns a flat list of parameter values into lookup keys.
+///
+/// For every WHERE IN list that was collapsed into a single parameter, `rewritten` holds the
+/// position of its first value and the number of values in the list, and the keys are the
+/// cartesian product of the lists' values. For example, the values `[a, b, c, d, e]` for
+/// `WHERE x = ? AND y IN (?, ?) AND z IN (?, ?)`, rewritten to `WHERE x = ? AND y = ? AND z = ?`,
+/// give the keys `[[a, b, d], [a, b, e], [a, c, d], [a, c, e]]`.
+pub(crate) fn expand_where_in(
+    params: Vec<DataType>,
+    rewritten: &[(usize, usize)],
+) -> Vec<Vec<DataType>> {
+    // the values that each parameter of the rewritten query can take
+    let mut choices: Vec<&[DataType]> = Vec::new();
+    let mut lists = rewritten.iter().peekable();
+    let mut i = 0;
+    while i < params.len() {
+        match lists.peek() {
+            Some(&&(first, n)) if first == i => {
+                choices.push(&params[i..(i + n).min(params.len())]);
+                lists.next();
+                i += n;
+            }
+            _ => {
+                choices.push(&params[i..=i]);
+                i += 1;
+            }
+        }
+    }
+    // a list can also be the last, and empty
+    if lists.any(|&(_, n)| n == 0) {
+        return vec![];
+    }
+
+    choices.into_iter().fold(vec![vec![]], |keys, values| {
+        keys.iter()
+            .flat_map(|key| {
+                values.iter().map(move |v| {
+                    let mut key: Vec<DataType> = key.clone();
+                    key.push(v.clone());
+                    key
+                })
+            })
+            .collect()
+    })
+}
+
+/// Turns the parameter values of a query whose WHERE IN lists were collapsed into lookup keys,
+/// without duplicates.
+///
+/// `lists` are the collapsed lists, as returned by `rewrite::collapse_where_in`, and `params` are
+/// the values for the query's other parameters and for the placeholders in the lists, in order.
+pub(crate) fn where_in_keys(
+    params: Vec<DataType>,
+    lists: &[(usize, Vec<Literal>)],
+) -> Result<Vec<Vec<DataType>>, Error> {
+    let mut params = params.into_iter();
+    let mut values = Vec::new();
+    let mut rewritten = Vec::with_capacity(lists.len());
+    for &(first, ref literals) in lists {
+        while values.len() < first {
+            values.push(params.next().ok_or(Error::WrongArguments)?);
+        }
+        rewritten.push((first, literals.len()));
+        for l in literals {
+            values.push(match *l {
+                Literal::Placeholder => params.next().ok_or(Error::WrongArguments)?,
+                ref l => l.clone().to_datatype()?,
+            });
+        }
+    }
+    values.extend(params);
+
+    // a value listed twice (e.g., `IN (1, 1)`) must not return its rows twice
+    let mut seen = HashSet::new();
+    Ok(expand_where_in(values, &rewritten)
+        .into_iter()
+        .filter(|key| seen.insert(key.clone()))
+        .collect())
+}
+
+/// How the values of an aggregate column of several lookups combine into one.
+#[derive(Clone, Debug, PartialEq)]
+enum Aggregate {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Average,
+    /// An aggregate whose per-key values cannot be combined (e.g., `COUNT(DISTINCT x)`).
+    Unsupported,
+}
+
+/// The clauses of a SELECT that apply to its whole result.
+///
+/// Noria applies them to the rows of each lookup key separately, so the adapter has to apply them
+/// again when it merges the results of several lookups (e.g., for a collapsed WHERE IN). The
+/// default merge leaves the rows of queries that Noria answers on its own as they are.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ResultMerge {
+    aggregates: Vec<(String, Aggregate)>,
+    group_by: Vec<String>,
+    distinct: bool,
+    order: Vec<(String, OrderType)>,
+    limit: Option<LimitClause>,
+}
+
+impl ResultMerge {
+    /// Note that `q` is the query before `rewrite::prepare_reaggregation`.
+    pub(crate) fn for_query(q: &SelectStatement) -> Self {
+        ResultMerge {
+            aggregates: q
+                .fields
+                .iter()
+                .filter_map(|f| match *f {
+                    FieldDefinitionExpression::Col(ref c) => c.function.as_ref().map(|f| {
+                        let aggregate = match **f {
+                            FunctionExpression::CountStar | FunctionExpression::Count(_, false) => {
+                                Aggregate::Count
+                            }
+                            FunctionExpression::Sum(_, false) => Aggregate::Sum,
+                            FunctionExpression::Min(_) => Aggregate::Min,
+                            FunctionExpression::Max(_) => Aggregate::Max,
+                            FunctionExpression::Avg(_, false) => Aggregate::Average,
+                            _ => Aggregate::Unsupported,
+                        };
+                        (c.name.clone(), aggregate)
+                    }),
+                    _ => None,
+                })
+                .collect(),
+            group_by: q
+                .group_by
+                .as_ref()
+                .map(|g| g.columns.iter().map(|c| c.name.clone()).collect())
+                .unwrap_or_default(),
+            distinct: q.distinct,
+            order: q
+                .order
+                .as_ref()
+                .map(|o| {
+                    o.columns
+                        .iter()
+                        .map(|(c, ot)| (c.name.clone(), ot.clone()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            limit: q.limit.clone(),
+        }
+    }
+
+    /// Combines the merged `rows`, whose columns are `columns`, into one row per group (or into a
+    /// single row if the query has no GROUP BY), like the query's aggregates would have.
+    ///
+    /// Averages are weighted by the counts that `rewrite::prepare_reaggregation` added.
+    pub(crate) fn aggregate(
+        &self,
+        rows: Vec<Vec<DataType>>,
+        columns: &[String],
+    ) -> Result<Vec<Vec<DataType>>, Error> {
+        if self.aggregates.is_empty() && self.group_by.is_empty() {
+            return Ok(rows);
+        }
+        let position = |name: &str| {
+            columns.iter().position(|c| c == name).ok_or_else(|| {
+                Error::NotSupported(format!(
+                    "aggregating unselected column '{}' across several lookups",
+                    name
+                ))
+            })
+        };
+
+        // how to combine each column, and which columns count the averages' values
+        let mut combine = Vec::new();
+        let mut averages = Vec::new();
+        let mut counts = Vec::new();
+        for (name, aggregate) in &self.aggregates {
+            let i = position(name)?;
+            match *aggregate {
+                Aggregate::Average => {
+                    let count = position(&rewrite::average_count_column(name))?;
+                    // the average is turned into a sum below
+                    combine.push((i, Aggregate::Sum));
+                    combine.push((count, Aggregate::Count));
+                    averages.push((i, count));
+                    counts.push(count);
+                }
+                Aggregate::Count => {
+                    combine.push((i, Aggregate::Count));
+                    counts.push(i);
+                }
+                Aggregate::Unsupported => {
+                    return Err(Error::NotSupported(format!(
+                        "{} across several lookups",
+                        name
+                    )))
+                }
+                ref aggregate => combine.push((i, aggregate.clone())),
+            }
+        }
+        let group_by: Vec<usize> = self
+            .group_by
+            .iter()
+            .map(|c| position(c))
+            .collect::<Result<_, _>>()?;
+
+        let mut groups: Vec<Vec<DataType>> = Vec::new();
+        let mut index = HashMap::new();
+        for mut row in rows {
+            for &(avg, count) in &averages {
+                row[avg] = match (real(&row[avg]), real(&row[count])) {
+                    (Some(avg), Some(count)) => DataType::from(avg * count),
+                    _ => DataType::None,
+                };
+            }
+
+            let key: Vec<DataType> = group_by.iter().map(|&i| row[i].clone()).collect();
+            match index.get(&key) {
+                None => {
+                    index.insert(key, groups.len());
+                    groups.push(row);
+                }
+                Some(&g) => {
+                    let group = &mut groups[g];
+                    for &(i, ref aggregate) in &combine {
+                        group[i] = combine_values(aggregate, &group[i], &row[i]);
+                    }
+                }
+            }
+        }
+
+        // like in MySQL, aggregating no rows at all still gives a row
+        if groups.is_empty() && group_by.is_empty() {
+            let mut row = vec![DataType::None; columns.len()];
+            for &i in &counts {
+                row[i] = DataType::from(0_i32);
+            }
+            groups.push(row);
+        }
+
+        for group in &mut groups {
+            for &(sum, count) in &averages {
+                group[sum] = match (real(&group[sum]), real(&group[count])) {
+                    (Some(sum), Some(count)) if count > 0.0 => DataType::from(sum / count),
+                    _ => DataType::None,
+                };
+            }
+        }
+        Ok(groups)
+    }
+
+    /// Sorts the merged `rows`, whose columns are `columns`, by the query's ORDER BY.
+    pub(crate) fn sort(
+        &self,
+        rows: &mut Vec<Vec<DataType>>,
+        columns: &[String],
+    ) -> Result<(), Error> {
+        if self.order.is_empty() {
+            return Ok(());
+        }
+        let by: Vec<_> = self
+            .order
+            .iter()
+            .map(|(name, ot)| {
+                columns
+                    .iter()
+                    .position(|c| c == name)
+                    .map(|i| (i, ot))
+                    .ok_or_else(|| {
+                        Error::NotSupported(format!(
+                            "ORDER BY unselected column '{}' across several lookups",
+                            name
+                        ))
+                    })
+            })
+            .collect::<Result<_, _>>()?;
+        rows.sort_by(|a, b| {
+            for &(i, ot) in &by {
+                let o = match (&a[i], &b[i]) {
+                    // like in MySQL, NULLs come first in ascending order
+                    (DataType::None, DataType::None) => Ordering::Equal,
+                    (DataType::None, _) => Ordering::Less,
+                    (_, DataType::None) => Ordering::Greater,
+                    (a, b) => order(a, b).unwrap_or(Ordering::Equal),
+                };
+                let o = match *ot {
+                    OrderType::OrderAscending => o,
+                    OrderType::OrderDescending => o.reverse(),
+                };
+                if o != Ordering::Equal {
+                    return o;
+                }
+            }
+            Ordering::Equal
+        });
+        Ok(())
+    }
+
+    /// Applies the query's DISTINCT, LIMIT and OFFSET to the merged and sorted result `rows`.
+    ///
+    /// `rewrite::prepare_reaggregation` leaves the OFFSET to the adapter, so it is only applied
+    /// here.
+    pub(crate) fn truncate(&self, rows: Vec<Vec<DataType>>) -> Vec<Vec<DataType>> {
+        let rows = if self.distinct {
+            let mut seen = HashSet::new();
+            rows.into_iter()
+                .filter(|r| seen.insert(r.clone()))
+                .collect()
+        } else {
+            rows
+        };
+        match self.limit {
+            Some(LimitClause { limit, offset }) => rows
+                .into_iter()
+                .skip(offset as usize)
+                .take(limit as usize)
+                .collect(),
+            None => rows,
+        }
+    }
+}
+
+/// The AUTO_INCREMENT id that `v` stands for, if it is a positive integer.
+pub(crate) fn auto_increment_id(v: &DataType) -> Option<u64> {
+    match *v {
+        DataType::Int(i) if i > 0 => Some(i as u64),
+        DataType::BigInt(i) if i > 0 => Some(i as u64),
+        DataType::UnsignedInt(i) => Some(u64::from(i)),
+        DataType::UnsignedBigInt(i) => Some(i),
+        _ => None,
+    }
+}
+
+/// Checks that `v` fits into `column` if it is an integer, rather than letting it wrap around.
+pub(crate) fn check_range(v: &DataType, column: &msql_srv::Column) -> Result<(), Error> {
+    let i = match integer(v) {
+        Some(i) => i,
+        None => return Ok(()),
+    };
+    let unsigned = column.colflags.contains(ColumnFlags::UNSIGNED_FLAG);
+    let (min, max) = match (column.coltype, unsigned) {
+        (ColumnType::MYSQL_TYPE_TINY, false) => {
+            (i128::from(i8::min_value()), i128::from(i8::max_value()))
+        }
+        (ColumnType::MYSQL_TYPE_TINY, true) => (0, i128::from(u8::max_value())),
+        (ColumnType::MYSQL_TYPE_SHORT, false) => {
+            (i128::from(i16::min_value()), i128::from(i16::max_value()))
+        }
+        (ColumnType::MYSQL_TYPE_SHORT, true) => (0, i128::from(u16::max_value())),
+        (ColumnType::MYSQL_TYPE_INT24, false) => (-(1 << 23), (1 << 23) - 1),
+        (ColumnType::MYSQL_TYPE_INT24, true) => (0, (1 << 24) - 1),
+        (ColumnType::MYSQL_TYPE_LONG, false) => {
+            (i128::from(i32::min_value()), i128::from(i32::max_value()))
+        }
+        (ColumnType::MYSQL_TYPE_LONG, true) => (0, i128::from(u32::max_value())),
+        (ColumnType::MYSQL_TYPE_LONGLONG, false) => {
+            (i128::from(i64::min_value()), i128::from(i64::max_value()))
+        }
+        (ColumnType::MYSQL_TYPE_LONGLONG, true) => (0, i128::from(u64::max_value())),
+        _ => return Ok(()),
+    };
+    if i < min || i > max {
+        return Err(Error::OutOfRange(column.column.clone()));
+    }
+    Ok(())
+}
+
+fn integer(v: &DataType) -> Option<i128> {
+    match *v {
+        DataType::Int(i) => Some(i128::from(i)),
+        DataType::BigInt(i) => Some(i128::from(i)),
+        DataType::UnsignedInt(i) => Some(i128::from(i)),
+        DataType::UnsignedBigInt(i) => Some(i128::from(i)),
+        _ => None,
+    }
+}
+
+fn real(v: &DataType) -> Option<f64> {
+    match *v {
+        DataType::Real(..) => Some(v.into()),
+        _ => integer(v).map(|i| i as f64),
+    }
+}
+
+/// Combines the values of an aggregate column of two rows.
+fn combine_values(aggregate: &Aggregate, a: &DataType, b: &DataType) -> DataType {
+    match (a, b) {
+        // aggregates skip NULLs
+        (DataType::None, v) | (v, DataType::None) => v.clone(),
+        _ => match *aggregate {
+            Aggregate::Min if b < a => b.clone(),
+            Aggregate::Max if b > a => b.clone(),
+            Aggregate::Min | Aggregate::Max => a.clone(),
+            _ => match (integer(a), integer(b)) {
+                (Some(a), Some(b)) => {
+                    let sum = a + b;
+                    i32::try_from(sum)
+                        .map(DataType::from)
+                        .or_else(|_| i64::try_from(sum).map(DataType::BigInt))
+                        .or_else(|_| u64::try_from(sum).map(DataType::UnsignedBigInt))
+                        .unwrap_or_else(|_| DataType::from(sum as f64))
+                }
+                _ => DataType::from(real(a).unwrap_or(0.0) + real(b).unwrap_or(0.0)),
+            },
+        },
+    }
+}
+
 pub(crate) fn sanitize_query(query: &str) -> String {
     let query = Cow::from(query);
     for &(ref pattern, replacement) in &*COMMENTS {
@@ -74,6 +511,48 @@ pub(crate) fn sanitize_query(query: &str) -> String {
     query.to_owned()
 }
 
+/// If `query` is a `REPLACE`, which nom-sql cannot parse, returns the `INSERT` with the same
+/// rows.
+pub(crate) fn replace_as_insert(query: &str) -> Option<String> {
+    let query = query.trim_start();
+    match (query.get(..7), query.get(7..)) {
+        (Some(verb), Some(rest))
+            if verb.eq_ignore_ascii_case("replace") && rest.starts_with(char::is_whitespace) =>
+        {
+            Some(format!("INSERT{}", rest))
+        }
+        _ => None,
+    }
+}
+
+/// Splits an `INSERT INTO t [(columns)] SELECT ...`, which nom-sql cannot parse, into the INSERT
+/// that the SELECT's rows feed (with no rows of its own) and the text of the SELECT.
+pub(crate) fn split_insert_select(query: &str) -> Option<(InsertStatement, &str)> {
+    let caps = INSERT_SELECT.captures(query)?;
+    let fields = caps.get(3).map(|cols| {
+        cols.as_str()
+            .split(',')
+            .map(|c| Column::from(c.trim().trim_matches('`')))
+            .collect()
+    });
+    let q = InsertStatement {
+        table: Table::from(&caps[2]),
+        fields,
+        data: vec![],
+        ignore: caps.get(1).is_some(),
+        on_duplicate: None,
+    };
+    Some((q, caps.get(4).unwrap().as_str()))
+}
+
+// The value of a literal in a WHERE-clause; placeholders take the next of `params`.
+fn literal_value(l: &Literal, params: &mut slice::Iter<DataType>) -> Result<DataType, Error> {
+    match *l {
+        Literal::Placeholder => params.next().cloned().ok_or(Error::WrongArguments),
+        ref l => l.clone().to_datatype(),
+    }
+}
+
 // Helper for flatten_conditional - returns true if the
 // expression is "valid" (i.e. not something like `a = 1 AND a = 2`.
 // Goes through the condition tree by gradually filling up primary key slots.
@@ -92,9 +571,10 @@ pub(crate) fn sanitize_query(query: &str) -> String {
 //    and we'll get {[(aid, 1), (uid, 2)]}.
 fn do_flatten_conditional(
     cond: &ConditionExpression,
-    pkey: &Vec<&Column>,
-    mut flattened: &mut HashSet<Vec<(String, DataType)>>,
-) -> bool {
+    pkey: &[&Column],
+    params: &mut slice::Iter<DataType>,
+    flattened: &mut HashSet<Vec<(String, DataType)>>,
+) -> Result<bool, Error> {
     match *cond {
         ConditionExpression::ComparisonOp(ConditionTree {
             left: box ConditionExpression::Base(ConditionBase::Literal(ref l)),
@@ -107,10 +587,12 @@ fn do_flatten_conditional(
             operator: Operator::Equal,
         }) => {
             if !pkey.contains(&c) {
-                panic!("UPDATE/DELETE only supports WHERE-clauses on primary keys");
+                return Err(Error::NotSupported(
+                    "UPDATE/DELETE with WHERE-clauses on non-key columns".into(),
+                ));
             }
 
-            let value = DataType::from(l);
+            let value = literal_value(l, params)?;
             // We want to look through our existing keys and see if any of them
             // are missing any columns. In that case we'll add the one we're looking
             // at now there.
@@ -122,7 +604,7 @@ fn do_flatten_conditional(
                 // Not a very happy clone, but using a HashSet here simplifies the AND
                 // logic by letting us ignore identical clauses (and we need the .clone()
                 // to be able to "mutate" key).
-                .and_then(|key| Some(key.clone()));
+                .cloned();
 
             if let Some(mut key) = with_space {
                 flattened.remove(&key);
@@ -133,13 +615,13 @@ fn do_flatten_conditional(
                 flattened.insert(vec![(c.name.clone(), value)]);
             }
 
-            true
+            Ok(true)
         }
         ConditionExpression::ComparisonOp(ConditionTree {
             left: box ConditionExpression::Base(ConditionBase::Literal(ref left)),
             right: box ConditionExpression::Base(ConditionBase::Literal(ref right)),
             operator: Operator::Equal,
-        }) if left == right => true,
+        }) => Ok(literal_value(left, params)? == literal_value(right, params)?),
         ConditionExpression::LogicalOp(ConditionTree {
             operator: Operator::And,
             ref left,
@@ -151,52 +633,61 @@ fn do_flatten_conditional(
             // WHERE A.a = AND a.b = 2
             // but also bogus stuff like `WHERE 1 = 1 AND 2 = 2`.
             let pre_count = flattened.len();
-            do_flatten_conditional(&*left, pkey, &mut flattened) && {
-                let count = flattened.len();
-                let valid = do_flatten_conditional(&*right, pkey, &mut flattened);
-                valid && (pre_count == flattened.len() || count == flattened.len())
+            if !do_flatten_conditional(&*left, pkey, params, flattened)? {
+                return Ok(false);
             }
+            let count = flattened.len();
+            let valid = do_flatten_conditional(&*right, pkey, params, flattened)?;
+            Ok(valid && (pre_count == flattened.len() || count == flattened.len()))
         }
         ConditionExpression::LogicalOp(ConditionTree {
             operator: Operator::Or,
             ref left,
             ref right,
-        }) => {
-            do_flatten_conditional(&*left, pkey, &mut flattened)
-                && do_flatten_conditional(&*right, pkey, &mut flattened)
+        }) => Ok(do_flatten_conditional(&*left, pkey, params, flattened)?
+            && do_flatten_conditional(&*right, pkey, params, flattened)?),
+        ConditionExpression::Bracketed(ref inner) => {
+            do_flatten_conditional(&*inner, pkey, params, flattened)
         }
-        _ => false,
+        _ => Err(Error::NotSupported(format!(
+            "UPDATE/DELETE with WHERE-clause '{}'",
+            cond
+        ))),
     }
 }
 
 // Takes a tree of conditional expressions for a DELETE/UPDATE statement and returns a list of all the
 // keys that should be mutated.
-// Panics if given a WHERE-clause containing other keys than the primary.
+// Fails if given a WHERE-clause containing other keys than the primary, or anything but
+// equality comparisons. Placeholders take their values from `params`, in order.
 // DELETE FROM a WHERE key = 1 OR key = 2 -> Some([[1], [2]])
 // DELETE FROM a WHERE key = 1 OR key = 2 AND key = 3 -> None // Bogus query
 // DELETE FROM a WHERE key = 1 AND key = 1 -> Some([[1]])
 pub(crate) fn flatten_conditional(
     cond: &ConditionExpression,
-    pkey: &Vec<&Column>,
-) -> Option<Vec<Vec<DataType>>> {
+    pkey: &[&Column],
+    params: &[DataType],
+) -> Result<Option<Vec<Vec<DataType>>>, Error> {
     let mut flattened = HashSet::new();
-    if do_flatten_conditional(cond, pkey, &mut flattened) {
+    if do_flatten_conditional(cond, pkey, &mut params.iter(), &mut flattened)? {
         let keys = flattened
             .into_iter()
             .map(|key| {
                 // This will be the case if we got a cond without any primary keys,
                 // or if we have a multi-column primary key and the cond only covers part of it.
                 if key.len() != pkey.len() {
-                    panic!("UPDATE/DELETE requires all columns of a compound key to be present");
+                    return Err(Error::NotSupported(
+                        "UPDATE/DELETE without all columns of a compound key".into(),
+                    ));
                 }
 
-                key.into_iter().map(|(_c, v)| v).collect()
+                Ok(key.into_iter().map(|(_c, v)| v).collect())
             })
-            .collect();
+            .collect::<Result<_, _>>()?;
 
-        Some(keys)
+        Ok(Some(keys))
     } else {
-        None
+        Ok(None)
     }
 }
 
@@ -207,7 +698,7 @@ pub(crate) fn get_primary_key(schema: &CreateTableStatement) -> Vec<(usize, &Col
         .fields
         .iter()
         .enumerate()
-        .filter(|&(_, ref cs)| {
+        .filter(|&(_, cs)| {
             cs.constraints.contains(&ColumnConstraint::PrimaryKey)
                 || match schema.keys {
                     // Try finding PRIMARY KEY constraints in keys as well:
@@ -222,26 +713,30 @@ pub(crate) fn get_primary_key(schema: &CreateTableStatement) -> Vec<(usize, &Col
         .collect()
 }
 
-fn get_parameter_columns_recurse(cond: &ConditionExpression) -> Vec<&Column> {
-    match *cond {
+fn get_parameter_columns_recurse(cond: &ConditionExpression) -> Result<Vec<&Column>, Error> {
+    Ok(match *cond {
+        // equalities, and ranges and LIKEs that the adapter filters by itself
         ConditionExpression::ComparisonOp(ConditionTree {
             left: box ConditionExpression::Base(ConditionBase::Field(ref c)),
             right: box ConditionExpression::Base(ConditionBase::Literal(Literal::Placeholder)),
-            operator: Operator::Equal,
+            operator: _,
         })
         | ConditionExpression::ComparisonOp(ConditionTree {
             left: box ConditionExpression::Base(ConditionBase::Literal(Literal::Placeholder)),
             right: box ConditionExpression::Base(ConditionBase::Field(ref c)),
-            operator: Operator::Equal,
+            operator: _,
         }) => vec![c],
+        // a list may mix placeholders with literals
         ConditionExpression::ComparisonOp(ConditionTree {
             left: box ConditionExpression::Base(ConditionBase::Field(ref c)),
             right: box ConditionExpression::Base(ConditionBase::LiteralList(ref literals)),
             operator: Operator::In,
-        }) if (|| literals.iter().all(|l| *l == Literal::Placeholder))() => {
-            // the weird extra closure above is due to
-            // https://github.com/rust-lang/rfcs/issues/1006
-            vec![c; literals.len()]
+        }) => {
+            let placeholders = literals
+                .iter()
+                .filter(|l| **l == Literal::Placeholder)
+                .count();
+            vec![c; placeholders]
         }
         ConditionExpression::ComparisonOp(ConditionTree {
             left: box ConditionExpression::Base(ConditionBase::Field(_)),
@@ -269,36 +764,42 @@ fn get_parameter_columns_recurse(cond: &ConditionExpression) -> Vec<&Column> {
             ref left,
             ref right,
         }) => {
-            let mut l = get_parameter_columns_recurse(left);
-            let mut r = get_parameter_columns_recurse(right);
+            let mut l = get_parameter_columns_recurse(left)?;
+            let mut r = get_parameter_columns_recurse(right)?;
             l.append(&mut r);
             l
         }
         ConditionExpression::NegationOp(ref expr) | ConditionExpression::Bracketed(ref expr) => {
-            get_parameter_columns_recurse(expr)
+            get_parameter_columns_recurse(expr)?
         }
-        _ => unimplemented!(),
-    }
+        _ => {
+            return Err(Error::NotSupported(format!(
+                "WHERE-clause condition '{}'",
+                cond
+            )))
+        }
+    })
 }
 
-pub(crate) fn get_parameter_columns(query: &SqlQuery) -> Vec<&Column> {
-    match *query {
+pub(crate) fn get_parameter_columns(query: &SqlQuery) -> Result<Vec<&Column>, Error> {
+    Ok(match *query {
         SqlQuery::Select(ref query) => {
             if let Some(ref wc) = query.where_clause {
-                get_parameter_columns_recurse(wc)
+                get_parameter_columns_recurse(wc)?
             } else {
                 vec![]
             }
         }
         SqlQuery::Insert(ref query) => {
-            assert_eq!(query.data.len(), 1);
-            // need to find for which fields we *actually* have a parameter
-            query.data[0]
+            // need to find for which fields we *actually* have a parameter, in every row
+            query
+                .data
                 .iter()
-                .enumerate()
-                .filter_map(|(i, v)| match *v {
-                    Literal::Placeholder => Some(&query.fields.as_ref().unwrap()[i]),
-                    _ => None,
+                .flat_map(|row| {
+                    row.iter().enumerate().filter_map(|(i, v)| match *v {
+                        Literal::Placeholder => query.fields.as_ref().unwrap().get(i),
+                        _ => None,
+                    })
                 })
                 .collect()
         }
@@ -316,58 +817,140 @@ pub(crate) fn get_parameter_columns(query: &SqlQuery) -> Vec<&Column> {
             });
 
             let where_params = if let Some(ref wc) = query.where_clause {
-                get_parameter_columns_recurse(wc)
+                get_parameter_columns_recurse(wc)?
             } else {
                 vec![]
             };
 
             field_params.chain(where_params.into_iter()).collect()
         }
-        _ => unimplemented!(),
+        SqlQuery::Delete(ref query) => {
+            if let Some(ref wc) = query.where_clause {
+                get_parameter_columns_recurse(wc)?
+            } else {
+                vec![]
+            }
+        }
+        _ => {
+            return Err(Error::NotSupported(
+                "prepared statements other than SELECT, INSERT, UPDATE and DELETE".into(),
+            ))
+        }
+    })
+}
+
+/// Whether `value <operator> operand` holds, for the operators of a `rewrite::PostFilter`.
+///
+/// Like in MySQL, comparisons with NULL never hold, and LIKE ignores ASCII case.
+pub(crate) fn compare(value: &DataType, operator: &Operator, operand: &DataType) -> bool {
+    if matches!(value, DataType::None) || matches!(operand, DataType::None) {
+        return false;
+    }
+    let order = || order(value, operand);
+    match *operator {
+        Operator::Equal => value == operand,
+        Operator::NotEqual => value != operand,
+        Operator::Greater => order() == Some(Ordering::Greater),
+        Operator::GreaterOrEqual => order().map_or(false, |o| o != Ordering::Less),
+        Operator::Less => order() == Some(Ordering::Less),
+        Operator::LessOrEqual => order().map_or(false, |o| o != Ordering::Greater),
+        Operator::Like => like(&text_of(value), &text_of(operand)),
+        Operator::NotLike => !like(&text_of(value), &text_of(operand)),
+        _ => false,
     }
 }
 
-fn walk_update_where(
-    col2v: &mut HashMap<String, DataType>,
-    params: &mut Option<<ParamParser as IntoIterator>::IntoIter>,
-    expr: ConditionExpression,
-) {
-    match expr {
-        ConditionExpression::ComparisonOp(ConditionTree {
-            operator: Operator::Equal,
-            left: box ConditionExpression::Base(ConditionBase::Field(c)),
-            right: box ConditionExpression::Base(ConditionBase::Literal(l)),
-        }) => {
-            let v = match l {
-                Literal::Placeholder => params
-                    .as_mut()
-                    .expect("Found placeholder in ad-hoc query")
-                    .next()
-                    .map(|pv| pv.value.to_datatype())
-                    .expect("Not enough parameter values given in EXECUTE"),
-                v => DataType::from(v),
-            };
-            let oldv = col2v.insert(c.name, v);
-            assert!(oldv.is_none());
+/// Orders two values, comparing texts by their bytes, like a binary collation would.
+fn order(a: &DataType, b: &DataType) -> Option<Ordering> {
+    match (convert::text_bytes(a), convert::text_bytes(b)) {
+        (Some(a), Some(b)) => Some(a.cmp(b)),
+        _ => a.partial_cmp(b),
+    }
+}
+
+fn text_of(v: &DataType) -> String {
+    match convert::text_bytes(v) {
+        Some(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+        None => v.to_string(),
+    }
+}
+
+// Matches `text` against a LIKE pattern, where `%` matches any run of characters, `_` matches
+// one character, and `\` escapes the character after it.
+fn like(text: &str, pattern: &str) -> bool {
+    let text: Vec<char> = text.to_ascii_lowercase().chars().collect();
+    let pattern: Vec<char> = pattern.to_ascii_lowercase().chars().collect();
+
+    // classic wildcard matching, backtracking to the last `%` on a mismatch
+    let (mut t, mut p) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p).cloned() {
+            Some('%') => {
+                backtrack = Some((t, p + 1));
+                p += 1;
+                continue;
+            }
+            Some('_') => {
+                t += 1;
+                p += 1;
+                continue;
+            }
+            Some('\\') if p + 1 < pattern.len() && pattern[p + 1] == text[t] => {
+                t += 1;
+                p += 2;
+                continue;
+            }
+            Some(c) if c != '\\' && c == text[t] => {
+                t += 1;
+                p += 1;
+                continue;
+            }
+            _ => {}
         }
-        ConditionExpression::LogicalOp(ConditionTree {
-            operator: Operator::And,
-            left,
-            right,
-        }) => {
-            // recurse
-            walk_update_where(col2v, params, *left);
-            walk_update_where(col2v, params, *right);
+        match backtrack {
+            Some((bt, bp)) => {
+                t = bt + 1;
+                p = bp;
+                backtrack = Some((bt + 1, bp));
+            }
+            None => return false,
         }
-        _ => unimplemented!("Fancy high-brow UPDATEs are not supported"),
     }
+    pattern[p..].iter().all(|&c| c == '%')
+}
+
+// Takes the value for the next placeholder from the parameters of an EXECUTE.
+fn next_param(
+    params: &mut Option<<ParamParser as IntoIterator>::IntoIter>,
+) -> Result<DataType, Error> {
+    params
+        .as_mut()
+        .ok_or_else(|| Error::ParseError("placeholder in a query that was not prepared".into()))?
+        .next()
+        .ok_or(Error::WrongArguments)?
+        .value
+        .to_datatype()
+}
+
+/// Takes the values for the placeholders in `cond` from the parameters of an EXECUTE, in order.
+///
+/// They are kept apart from the WHERE-clause, rather than filled into it as literals: nom-sql has
+/// no literals for some values (e.g., timestamps).
+pub(crate) fn where_params(
+    cond: &ConditionExpression,
+    params: &mut Option<<ParamParser as IntoIterator>::IntoIter>,
+) -> Result<Vec<DataType>, Error> {
+    (0..rewrite::count_placeholders(cond))
+        .map(|_| next_param(params))
+        .collect()
 }
 
 pub(crate) fn extract_update_params_and_fields(
     q: &mut UpdateStatement,
     params: &mut Option<<ParamParser as IntoIterator>::IntoIter>,
     schema: &CreateTableStatement,
-) -> Vec<(usize, Modification)> {
+) -> Result<Vec<(usize, Modification)>, Error> {
     let mut updates = Vec::new();
     for (i, field) in schema.fields.iter().enumerate() {
         if let Some(sets) = q
@@ -380,19 +963,17 @@ pub(crate) fn extract_update_params_and_fields(
                     value: Literal::Placeholder,
                     alias: None,
                 }) => {
-                    let v = params
-                        .as_mut()
-                        .expect("Found placeholder in ad-hoc query")
-                        .next()
-                        .map(|pv| pv.value.to_datatype())
-                        .expect("Not enough parameter values given in EXECUTE");
+                    let v = next_param(params)?;
+                    check_range(&v, &schema::convert_column(field))?;
                     updates.push((i, Modification::Set(v)));
                 }
                 FieldValueExpression::Literal(LiteralExpression {
                     value: ref v,
                     alias: None,
                 }) => {
-                    updates.push((i, Modification::Set(DataType::from(v))));
+                    let v = v.clone().to_datatype()?;
+                    check_range(&v, &schema::convert_column(field))?;
+                    updates.push((i, Modification::Set(v)));
                 }
                 FieldValueExpression::Arithmetic(ref ae) => {
                     // we only support "column = column +/- literal"
@@ -402,54 +983,174 @@ pub(crate) fn extract_update_params_and_fields(
                             left: ArithmeticBase::Column(ref c),
                             right: ArithmeticBase::Scalar(ref l),
                             alias: None,
-                        } => {
-                            assert_eq!(c, &field.column);
-                            match op {
-                                ArithmeticOperator::Add => {
-                                    updates.push((i, Modification::Apply(Operation::Add, l.into())))
-                                }
-                                ArithmeticOperator::Subtract => {
-                                    updates.push((i, Modification::Apply(Operation::Sub, l.into())))
-                                }
-                                _ => unimplemented!(),
+                        } if c.name == field.column.name => match op {
+                            ArithmeticOperator::Add => {
+                                updates.push((i, Modification::Apply(Operation::Add, l.into())))
                             }
+                            ArithmeticOperator::Subtract => {
+                                updates.push((i, Modification::Apply(Operation::Sub, l.into())))
+                            }
+                            _ => {
+                                return Err(Error::NotSupported(format!(
+                                    "UPDATE with SET {} = {}",
+                                    field.column.name, ae
+                                )))
+                            }
+                        },
+                        _ => {
+                            return Err(Error::NotSupported(format!(
+                                "UPDATE with SET {} = {}",
+                                field.column.name, ae
+                            )))
                         }
-                        _ => unreachable!(),
                     }
                 }
-                _ => unreachable!(),
+                _ => {
+                    return Err(Error::NotSupported(format!(
+                        "UPDATE with non-literal value for '{}'",
+                        field.column.name
+                    )))
+                }
             }
         }
     }
-    updates
+    // whatever is left does not name a column of the table
+    if let Some(&(ref f, _)) = q.fields.first() {
+        return Err(Error::BadField(f.name.clone()));
+    }
+    Ok(updates)
 }
 
+// Splits an UPDATE into the modifications it makes and the WHERE-clause that picks the rows to
+// modify, along with the parameters of an EXECUTE for the WHERE-clause's placeholders.
+#[allow(clippy::type_complexity)]
 pub(crate) fn extract_update(
     mut q: UpdateStatement,
     params: Option<ParamParser>,
     schema: &CreateTableStatement,
-) -> (Vec<DataType>, Vec<(usize, Modification)>) {
+) -> Result<
+    (
+        Option<ConditionExpression>,
+        Vec<DataType>,
+        Vec<(usize, Modification)>,
+    ),
+    Error,
+> {
     let mut params = params.map(|p| p.into_iter());
-    let updates = extract_update_params_and_fields(&mut q, &mut params, schema);
+    // SET comes before WHERE, so its placeholders take the first parameters
+    let updates = extract_update_params_and_fields(&mut q, &mut params, schema)?;
 
-    let pkey = get_primary_key(schema);
-    let where_clause = q
-        .where_clause
-        .expect("UPDATE without WHERE is not supported");
-    let mut col_to_val: HashMap<_, _> = HashMap::new();
-    walk_update_where(&mut col_to_val, &mut params, where_clause);
+    let where_params = match q.where_clause {
+        Some(ref cond) => where_params(cond, &mut params)?,
+        None => Vec::new(),
+    };
 
-    let key: Vec<_> = pkey
-        .iter()
-        .map(|&(_, c)| col_to_val.remove(&c.name).unwrap())
-        .collect();
+    Ok((q.where_clause, where_params, updates))
+}
+
+/// Expand sparse `(column index, modification)` pairs into one `Modification` per column, which
+/// is the form `TableOperation::Update` and `TableOperation::InsertOrUpdate` expect.
+pub(crate) fn dense_modifications(
+    ncols: usize,
+    updates: Vec<(usize, Modification)>,
+) -> Vec<Modification> {
+    let mut set: Vec<_> = (0..ncols).map(|_| Modification::None).collect();
+    for (i, m) in updates {
+        set[i] = m;
+    }
+    set
+}
+
+/// Whether applying the dense modifications `set` to `row` would change any of its values.
+pub(crate) fn changes_row(row: &[DataType], set: &[Modification]) -> bool {
+    row.iter().zip(set).any(|(v, m)| match *m {
+        Modification::None => false,
+        Modification::Set(ref new) => v != new,
+        Modification::Apply(_, ref by) => !matches!(
+            *by,
+            DataType::Int(0)
+                | DataType::BigInt(0)
+                | DataType::UnsignedInt(0)
+                | DataType::UnsignedBigInt(0)
+        ),
+    })
+}
+
+/// Applies the dense modifications `set` to `row`, like Noria would.
+pub(crate) fn apply_modifications(row: &mut [DataType], set: &[Modification]) {
+    for (v, m) in row.iter_mut().zip(set) {
+        match *m {
+            Modification::None => {}
+            Modification::Set(ref new) => *v = new.clone(),
+            Modification::Apply(Operation::Add, ref by) => *v = &*v + by,
+            Modification::Apply(Operation::Sub, ref by) => *v = &*v - by,
+        }
+    }
+}
+
+/// Applies the writes `ops`, which Noria has not seen yet, to the `rows` that a table with the
+/// primary key columns `pkey` currently has for `keys`.
+///
+/// Like the rows Noria returns, the result is in the order of `keys`, and keys that match no
+/// row are skipped.
+pub(crate) fn apply_pending_writes(
+    rows: Vec<Vec<DataType>>,
+    pkey: &[usize],
+    keys: &[Vec<DataType>],
+    ops: &[TableOperation],
+) -> Vec<Vec<DataType>> {
+    let key_of =
+        |row: &[DataType]| -> Vec<DataType> { pkey.iter().map(|&i| row[i].clone()).collect() };
+    let mut current: HashMap<Vec<DataType>, Vec<DataType>> =
+        rows.into_iter().map(|row| (key_of(&row), row)).collect();
+    for op in ops {
+        match *op {
+            TableOperation::Insert(ref row) => {
+                current.insert(key_of(row), row.clone());
+            }
+            TableOperation::Delete { ref key } => {
+                current.remove(key);
+            }
+            TableOperation::Update { ref key, ref set } => {
+                if let Some(row) = current.get_mut(key) {
+                    apply_modifications(row, set);
+                }
+            }
+            TableOperation::InsertOrUpdate {
+                ref row,
+                ref update,
+            } => match current.get_mut(&key_of(row)) {
+                Some(existing) => apply_modifications(existing, update),
+                None => {
+                    current.insert(key_of(row), row.clone());
+                }
+            },
+        }
+    }
+    keys.iter().filter_map(|key| current.remove(key)).collect()
+}
 
-    (key, updates)
+/// The primary keys of the rows that the writes `ops` to a table with the primary key columns
+/// `pkey` touch, in the order they are first touched.
+pub(crate) fn pending_keys(pkey: &[usize], ops: &[TableOperation]) -> Vec<Vec<DataType>> {
+    let mut seen = HashSet::new();
+    ops.iter()
+        .map(|op| match *op {
+            TableOperation::Insert(ref row) | TableOperation::InsertOrUpdate { ref row, .. } => {
+                pkey.iter().map(|&i| row[i].clone()).collect()
+            }
+            TableOperation::Delete { ref key } | TableOperation::Update { ref key, .. } => {
+                key.clone()
+            }
+        })
+        .filter(|key: &Vec<DataType>| seen.insert(key.clone()))
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::convert::ToLiteral;
     use nom_sql::{self, SqlQuery};
 
     fn compare_flatten<I>(cond_query: &str, key: Vec<&str>, expected: Option<Vec<Vec<I>>>)
@@ -472,8 +1173,8 @@ mod tests {
             })
             .collect();
 
-        let pkey_ref = pkey.iter().map(|c| c).collect();
-        if let Some(mut actual) = flatten_conditional(&cond, &pkey_ref) {
+        let pkey_ref: Vec<_> = pkey.iter().collect();
+        if let Some(mut actual) = flatten_conditional(&cond, &pkey_ref, &[]).unwrap() {
             let mut expected: Vec<Vec<DataType>> = expected
                 .unwrap()
                 .into_iter()
@@ -488,6 +1189,21 @@ mod tests {
         }
     }
 
+    fn assert_flatten_fails(cond_query: &str, key: Vec<&str>) {
+        let cond = match nom_sql::parse_query(cond_query).unwrap() {
+            SqlQuery::Update(u) => u.where_clause.unwrap(),
+            SqlQuery::Delete(d) => d.where_clause.unwrap(),
+            _ => unreachable!(),
+        };
+
+        let pkey: Vec<Column> = key
+            .into_iter()
+            .map(|k| Column::from(format!("T.{}", k).as_str()))
+            .collect();
+        let pkey_ref: Vec<_> = pkey.iter().collect();
+        assert!(flatten_conditional(&cond, &pkey_ref, &[]).is_err());
+    }
+
     fn get_schema(query: &str) -> CreateTableStatement {
         match nom_sql::parse_query(query).unwrap() {
             SqlQuery::CreateTable(c) => c,
@@ -663,45 +1379,416 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
     fn test_flatten_conditional_non_key_delete() {
-        compare_flatten(
-            "DELETE FROM T WHERE T.b = 1",
-            vec!["a"],
-            Some(vec![vec![1]]),
-        );
+        assert_flatten_fails("DELETE FROM T WHERE T.b = 1", vec!["a"]);
     }
 
     #[test]
-    #[should_panic]
     fn test_flatten_conditional_non_key_update() {
-        compare_flatten(
-            "UPDATE T SET T.b = 2 WHERE T.b = 1",
-            vec!["a"],
-            Some(vec![vec![1]]),
-        );
+        assert_flatten_fails("UPDATE T SET T.b = 2 WHERE T.b = 1", vec!["a"]);
     }
 
     #[test]
-    #[should_panic]
     fn test_flatten_conditional_partial_key_delete() {
-        compare_flatten(
-            "DELETE FROM T WHERE T.a = 1",
-            vec!["a", "b"],
-            Some(vec![vec![1]]),
-        );
+        assert_flatten_fails("DELETE FROM T WHERE T.a = 1", vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_flatten_conditional_range() {
+        assert_flatten_fails("DELETE FROM T WHERE T.a > 1", vec!["a"]);
+        assert_flatten_fails("UPDATE T SET T.b = 2 WHERE T.a = 1 OR T.a < 0", vec!["a"]);
     }
 
     #[test]
-    #[should_panic]
     fn test_flatten_conditional_partial_key_update() {
-        compare_flatten(
-            "UPDATE T SET T.b = 2 WHERE T.a = 1",
-            vec!["a", "b"],
-            Some(vec![vec![1]]),
+        assert_flatten_fails("UPDATE T SET T.b = 2 WHERE T.a = 1", vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_flatten_conditional_params() {
+        let cond = match nom_sql::parse_query(
+            "DELETE FROM T WHERE T.a = ? AND T.b = 2 OR T.a = ? AND T.b = ?",
+        )
+        .unwrap()
+        {
+            SqlQuery::Delete(d) => d.where_clause.unwrap(),
+            _ => unreachable!(),
+        };
+        let pkey = vec![Column::from("T.a"), Column::from("T.b")];
+        let pkey_ref: Vec<_> = pkey.iter().collect();
+
+        // the parameters are used as they are, timestamps included
+        let ts = DataType::Timestamp(chrono::NaiveDate::from_ymd(2020, 1, 2).and_hms(3, 4, 5));
+        let params = vec![ts.clone(), 1.into(), 3.into()];
+        let keys = flatten_conditional(&cond, &pkey_ref, &params)
+            .unwrap()
+            .unwrap();
+        // timestamps and integers do not have a total order, so compare without sorting
+        assert_eq!(keys.len(), 2);
+        assert!(keys.contains(&vec![ts, 2.into()]));
+        assert!(keys.contains(&vec![1.into(), 3.into()]));
+
+        assert!(flatten_conditional(&cond, &pkey_ref, &params[..2]).is_err());
+    }
+
+    #[test]
+    fn test_expand_where_in() {
+        let params: Vec<DataType> = vec![1.into(), 2.into(), 3.into(), 4.into()];
+        assert_eq!(expand_where_in(params.clone(), &[]), vec![params.clone()]);
+        let expected: Vec<Vec<DataType>> = vec![
+            vec![1.into(), 2.into(), 4.into()],
+            vec![1.into(), 3.into(), 4.into()],
+        ];
+        assert_eq!(expand_where_in(params.clone(), &[(1, 2)]), expected);
+        assert!(expand_where_in(params.clone(), &[(0, 0)]).is_empty());
+
+        let expected: Vec<Vec<DataType>> = vec![
+            vec![1.into(), 3.into()],
+            vec![1.into(), 4.into()],
+            vec![2.into(), 3.into()],
+            vec![2.into(), 4.into()],
+        ];
+        assert_eq!(expand_where_in(params.clone(), &[(0, 2), (2, 2)]), expected);
+        assert!(expand_where_in(params, &[(0, 2), (2, 2), (4, 0)]).is_empty());
+    }
+
+    #[test]
+    fn test_where_in_keys() {
+        // WHERE x = ? AND y IN (?, 5) AND z = ?
+        let params: Vec<DataType> = vec![1.into(), 4.into(), 9.into()];
+        let lists = vec![(1, vec![Literal::Placeholder, Literal::Integer(5)])];
+        let expected: Vec<Vec<DataType>> = vec![
+            vec![1.into(), 4.into(), 9.into()],
+            vec![
+                1.into(),
+                Literal::Integer(5).to_datatype().unwrap(),
+                9.into(),
+            ],
+        ];
+        assert_eq!(where_in_keys(params.clone(), &lists).unwrap(), expected);
+
+        assert!(where_in_keys(vec![1.into()], &lists).is_err());
+        assert_eq!(where_in_keys(params.clone(), &[]).unwrap(), vec![params]);
+
+        // WHERE y IN (?, ?, 5) with the values 4 and 4
+        let lists = vec![(
+            0,
+            vec![
+                Literal::Placeholder,
+                Literal::Placeholder,
+                Literal::Integer(5),
+            ],
+        )];
+        let expected: Vec<Vec<DataType>> = vec![
+            vec![4.into()],
+            vec![Literal::Integer(5).to_datatype().unwrap()],
+        ];
+        assert_eq!(
+            where_in_keys(vec![4.into(), 4.into()], &lists).unwrap(),
+            expected
         );
     }
 
+    #[test]
+    fn test_result_merge() {
+        let select = |q: &str| match nom_sql::parse_query(q).unwrap() {
+            SqlQuery::Select(q) => q,
+            _ => unreachable!(),
+        };
+        let columns = vec![String::from("id"), String::from("ts")];
+        let rows = || -> Vec<Vec<DataType>> {
+            vec![
+                vec![1.into(), 30.into()],
+                vec![1.into(), 10.into()],
+                vec![2.into(), 20.into()],
+                vec![2.into(), DataType::None],
+            ]
+        };
+
+        let merge = ResultMerge::for_query(&select(
+            "SELECT t.id, t.ts FROM t WHERE t.id = ? ORDER BY t.ts DESC LIMIT 2 OFFSET 1",
+        ));
+        let mut r = rows();
+        merge.sort(&mut r, &columns).unwrap();
+        let expected: Vec<Vec<DataType>> =
+            vec![vec![2.into(), 20.into()], vec![1.into(), 10.into()]];
+        assert_eq!(merge.truncate(r), expected);
+
+        let merge = ResultMerge::for_query(&select(
+            "SELECT DISTINCT t.id FROM t WHERE t.id = ? ORDER BY t.id",
+        ));
+        let mut r = rows();
+        merge.sort(&mut r, &columns).unwrap();
+        let r = r.into_iter().map(|r| vec![r[0].clone()]).collect();
+        let expected: Vec<Vec<DataType>> = vec![vec![1.into()], vec![2.into()]];
+        assert_eq!(merge.truncate(r), expected);
+
+        let merge = ResultMerge::for_query(&select("SELECT t.id FROM t ORDER BY t.other"));
+        assert!(merge.sort(&mut rows(), &columns).is_err());
+    }
+
+    #[test]
+    fn test_result_merge_aggregate() {
+        let select = |q: &str| match nom_sql::parse_query(q).unwrap() {
+            SqlQuery::Select(q) => q,
+            _ => unreachable!(),
+        };
+
+        // one row for each of three lookup keys
+        let merge = ResultMerge::for_query(&select(
+            "SELECT COUNT(*), SUM(t.x), MAX(t.x), AVG(t.x) FROM t WHERE t.k = ?",
+        ));
+        let columns: Vec<String> = vec![
+            "count(*)",
+            "sum(t.x)",
+            "max(t.x)",
+            "avg(t.x)",
+            "__count_avg_t_x_",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        let rows: Vec<Vec<DataType>> = vec![
+            vec![2.into(), 3.into(), 2.into(), DataType::from(1.5), 2.into()],
+            vec![1.into(), 5.into(), 5.into(), DataType::from(5.0), 1.into()],
+            vec![
+                1.into(),
+                DataType::None,
+                DataType::None,
+                DataType::None,
+                0.into(),
+            ],
+        ];
+        let expected: Vec<Vec<DataType>> = vec![vec![
+            4.into(),
+            8.into(),
+            5.into(),
+            DataType::from(8.0 / 3.0),
+            3.into(),
+        ]];
+        assert_eq!(merge.aggregate(rows, &columns).unwrap(), expected);
+
+        // no rows at all still give a row
+        let expected: Vec<Vec<DataType>> = vec![vec![
+            0.into(),
+            DataType::None,
+            DataType::None,
+            DataType::None,
+            0.into(),
+        ]];
+        assert_eq!(merge.aggregate(vec![], &columns).unwrap(), expected);
+
+        // one row per group
+        let merge = ResultMerge::for_query(&select(
+            "SELECT t.g, COUNT(*) FROM t WHERE t.k = ? GROUP BY t.g",
+        ));
+        let columns = vec![String::from("g"), String::from("count(*)")];
+        let rows: Vec<Vec<DataType>> = vec![
+            vec![1.into(), 2.into()],
+            vec![2.into(), 1.into()],
+            vec![1.into(), 3.into()],
+        ];
+        let expected: Vec<Vec<DataType>> = vec![vec![1.into(), 5.into()], vec![2.into(), 1.into()]];
+        assert_eq!(merge.aggregate(rows, &columns).unwrap(), expected);
+
+        let merge =
+            ResultMerge::for_query(&select("SELECT COUNT(DISTINCT t.x) FROM t WHERE t.k = ?"));
+        let columns = vec![String::from("count(distinct t.x)")];
+        assert!(merge.aggregate(vec![], &columns).is_err());
+    }
+
+    #[test]
+    fn test_check_range() {
+        let column = |coltype, colflags| msql_srv::Column {
+            table: String::from("t"),
+            column: String::from("c"),
+            coltype,
+            colflags,
+        };
+        let int = column(ColumnType::MYSQL_TYPE_LONG, ColumnFlags::empty());
+        let unsigned_bigint = column(ColumnType::MYSQL_TYPE_LONGLONG, ColumnFlags::UNSIGNED_FLAG);
+
+        assert!(check_range(&DataType::from(i32::min_value()), &int).is_ok());
+        assert!(check_range(&DataType::BigInt(1 << 31), &int).is_err());
+        assert!(check_range(
+            &DataType::UnsignedBigInt(u64::max_value()),
+            &unsigned_bigint
+        )
+        .is_ok());
+        assert!(check_range(&DataType::from(-1_i32), &unsigned_bigint).is_err());
+        // only integers are checked
+        assert!(check_range(&DataType::from("foo"), &int).is_ok());
+    }
+
+    #[test]
+    fn test_extract_update() {
+        let schema = get_schema("CREATE TABLE T (a int, b int, PRIMARY KEY(a))");
+        let update = |q: &str| match nom_sql::parse_query(q).unwrap() {
+            SqlQuery::Update(u) => extract_update(u, None, &schema),
+            _ => unreachable!(),
+        };
+
+        let (cond, params, updates) = update("UPDATE T SET T.b = 2 WHERE T.a IN (1, 2)").unwrap();
+        assert!(matches!(
+            cond,
+            Some(ConditionExpression::ComparisonOp(ConditionTree {
+                operator: Operator::In,
+                ..
+            }))
+        ));
+        assert!(params.is_empty());
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].0, 1);
+
+        let (cond, _, _) = update("UPDATE T SET T.b = 2").unwrap();
+        assert!(cond.is_none());
+
+        assert!(update("UPDATE T SET T.b = T.b * 2 WHERE T.a = 1").is_err());
+        assert!(matches!(
+            update("UPDATE T SET T.c = 2 WHERE T.a = 1"),
+            Err(Error::BadField(ref c)) if c == "c"
+        ));
+        // placeholders need the parameters of an EXECUTE
+        assert!(update("UPDATE T SET T.b = 2 WHERE T.a = ?").is_err());
+    }
+
+    #[test]
+    fn test_to_literal() {
+        let values = vec![DataType::from("Bob"), DataType::Real(-3, 500_000_000)];
+        for v in values {
+            assert_eq!(v.clone().to_literal().unwrap().to_datatype().unwrap(), v);
+        }
+        assert!(matches!(
+            DataType::Real(1 << 40, 0).to_literal(),
+            Err(Error::OutOfRange(_))
+        ));
+        // there are no timestamp literals
+        let ts = chrono::NaiveDate::from_ymd(2020, 1, 2).and_hms_milli(3, 4, 5, 6);
+        assert!(DataType::Timestamp(ts).to_literal().is_err());
+    }
+
+    #[test]
+    fn test_like() {
+        assert!(like("Rusty", "rusty"));
+        assert!(like("Rusty", "R%"));
+        assert!(like("Rusty", "%st%"));
+        assert!(like("Rusty", "R_st_"));
+        assert!(like("", "%"));
+        assert!(like("50%", "50\\%"));
+        assert!(!like("500", "50\\%"));
+        assert!(!like("Rusty", "R_st"));
+        assert!(!like("Rusty", "%x%"));
+        assert!(like("abcabd", "%abd"));
+    }
+
+    #[test]
+    fn test_compare() {
+        let five: DataType = 5.into();
+        assert!(compare(&five, &Operator::Greater, &4.into()));
+        assert!(!compare(&five, &Operator::Greater, &5.into()));
+        assert!(compare(&five, &Operator::LessOrEqual, &5.into()));
+        assert!(compare(&five, &Operator::NotEqual, &6.into()));
+        assert!(!compare(&DataType::None, &Operator::NotEqual, &6.into()));
+        assert!(compare(&"Bob".into(), &Operator::Like, &"b%".into()));
+        assert!(compare(&"Bob".into(), &Operator::NotLike, &"J%".into()));
+
+        // texts compare by their bytes, whichever way Noria stores them
+        let high = DataType::from("\u{ff}");
+        let low = DataType::from("a long text, so that it is not a TinyText");
+        assert!(compare(&high, &Operator::Greater, &low));
+        assert!(compare(&low, &Operator::LessOrEqual, &high));
+        assert!(compare(&high, &Operator::GreaterOrEqual, &high));
+    }
+
+    #[test]
+    fn test_bytes_to_datatype() {
+        // text is stored as it is, whatever its first byte
+        let v = convert::bytes_to_datatype(b"\x01ab").unwrap();
+        assert_eq!(convert::text_bytes(&v), Some(&b"\x01ab"[..]));
+        // Noria cannot store other byte strings
+        assert!(matches!(
+            convert::bytes_to_datatype(&[b'a', 0xff, 0x00]),
+            Err(Error::IncorrectString(ref s)) if s == "a\\xFF\\x00"
+        ));
+    }
+
+    #[test]
+    fn test_apply_pending_writes() {
+        let rows: Vec<Vec<DataType>> = vec![vec![1.into(), 10.into()], vec![2.into(), 20.into()]];
+        let keys: Vec<Vec<DataType>> = (1..=4).map(|k| vec![k.into()]).collect();
+        let set = |m| dense_modifications(2, vec![(1, m)]);
+        let ops = vec![
+            TableOperation::Delete {
+                key: vec![1.into()],
+            },
+            TableOperation::Update {
+                key: vec![2.into()],
+                set: set(Modification::Apply(Operation::Add, 5.into())),
+            },
+            TableOperation::Insert(vec![3.into(), 30.into()]),
+            TableOperation::InsertOrUpdate {
+                row: vec![3.into(), 0.into()],
+                update: set(Modification::Set(31.into())),
+            },
+            // an update of a row that does not exist does nothing
+            TableOperation::Update {
+                key: vec![4.into()],
+                set: set(Modification::Set(40.into())),
+            },
+        ];
+        let expected: Vec<Vec<DataType>> =
+            vec![vec![2.into(), 25.into()], vec![3.into(), 31.into()]];
+        assert_eq!(apply_pending_writes(rows, &[0], &keys, &ops), expected);
+    }
+
+    #[test]
+    fn test_pending_keys() {
+        let set = dense_modifications(2, vec![(1, Modification::Set(1.into()))]);
+        let ops = vec![
+            TableOperation::Insert(vec![3.into(), 30.into()]),
+            TableOperation::Update {
+                key: vec![1.into()],
+                set: set.clone(),
+            },
+            TableOperation::Delete {
+                key: vec![3.into()],
+            },
+            TableOperation::InsertOrUpdate {
+                row: vec![2.into(), 20.into()],
+                update: set,
+            },
+        ];
+        let expected: Vec<Vec<DataType>> = vec![vec![3.into()], vec![1.into()], vec![2.into()]];
+        assert_eq!(pending_keys(&[0], &ops), expected);
+    }
+
+    #[test]
+    fn test_changes_row() {
+        let row: Vec<DataType> = vec![1.into(), "Bob".into()];
+        let set = |m| dense_modifications(2, vec![(1, m)]);
+
+        assert!(!changes_row(&row, &set(Modification::Set("Bob".into()))));
+        assert!(changes_row(&row, &set(Modification::Set("Rusty".into()))));
+        assert!(!changes_row(
+            &row,
+            &set(Modification::Apply(Operation::Add, 0.into()))
+        ));
+        assert!(changes_row(
+            &row,
+            &set(Modification::Apply(Operation::Add, 1.into()))
+        ));
+        assert!(!changes_row(&row, &dense_modifications(2, vec![])));
+    }
+
+    #[test]
+    fn test_dense_modifications() {
+        let set = dense_modifications(3, vec![(2, Modification::Set(5.into()))]);
+        assert_eq!(set.len(), 3);
+        assert!(matches!(set[0], Modification::None));
+        assert!(matches!(set[1], Modification::None));
+        assert!(matches!(set[2], Modification::Set(_)));
+    }
+
     #[test]
     fn test_parameter_column_extraction() {
         let query = "SELECT  `votes`.* FROM `votes` WHERE `votes`.`user_id` = 1 \
@@ -709,8 +1796,64 @@ mod tests {
                      ORDER BY `votes`.`id` ASC LIMIT 1";
         let q = nom_sql::parse_query(query).unwrap();
 
-        let pc = get_parameter_columns(&q);
+        let pc = get_parameter_columns(&q).unwrap();
 
         assert_eq!(pc, vec![&Column::from("votes.story_id")]);
+
+        let q = nom_sql::parse_query("SELECT t.a FROM t WHERE t.id IN (1, ?, 3, ?)").unwrap();
+        let pc = get_parameter_columns(&q).unwrap();
+        assert_eq!(pc, vec![&Column::from("t.id"), &Column::from("t.id")]);
+    }
+
+    #[test]
+    fn test_replace_as_insert() {
+        assert_eq!(
+            replace_as_insert("REPLACE INTO cats (id) VALUES (1)"),
+            Some("INSERT INTO cats (id) VALUES (1)".to_owned())
+        );
+        assert_eq!(
+            replace_as_insert("replace\ninto cats VALUES (1)"),
+            Some("INSERT\ninto cats VALUES (1)".to_owned())
+        );
+        assert_eq!(replace_as_insert("INSERT INTO cats VALUES (1)"), None);
+        assert_eq!(replace_as_insert("replacements"), None);
+    }
+
+    #[test]
+    fn test_split_insert_select() {
+        let (q, select) = split_insert_select(
+            "INSERT INTO `archive` (id, `name`) SELECT cats.id, cats.name FROM cats WHERE cats.id > 2",
+        )
+        .unwrap();
+        assert_eq!(q.table, Table::from("archive"));
+        assert_eq!(
+            q.fields,
+            Some(vec![Column::from("id"), Column::from("name")])
+        );
+        assert!(!q.ignore);
+        assert_eq!(
+            select,
+            "SELECT cats.id, cats.name FROM cats WHERE cats.id > 2"
+        );
+
+        let (q, select) =
+            split_insert_select("insert ignore into archive select * from cats").unwrap();
+        assert_eq!(q.fields, None);
+        assert!(q.ignore);
+        assert_eq!(select, "select * from cats");
+
+        assert!(split_insert_select("INSERT INTO cats (id) VALUES (1)").is_none());
+    }
+
+    #[test]
+    fn test_parameter_column_extraction_multi_row_insert() {
+        let q = nom_sql::parse_query("INSERT INTO t (a, b) VALUES (?, 1), (?, ?)").unwrap();
+
+        let pc = get_parameter_columns(&q).unwrap();
+
+        assert_eq!(
+            pc,
+            vec![&Column::from("a"), &Column::from("a"), &Column::from("b")]
+        );
     }
 }
@@ -1,63 +1,25 @@
 #[macro_use]
 extern crate slog;
 
-use std::collections::HashMap;
 use std::env;
-use std::net::TcpListener;
 use std::sync::atomic::{AtomicBool, AtomicUsize};
-use std::sync::{Arc, Barrier, RwLock};
+use std::sync::{mpsc, Arc, Barrier};
 use std::thread;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::Duration;
 
 use msql_srv::MysqlIntermediary;
+use mysql::consts::{CapabilityFlags, ColumnFlags, ColumnType};
 use mysql::prelude::*;
-use nom_sql::SelectStatement;
-use noria_server::{Builder, ControllerHandle, ZookeeperAuthority};
-use zookeeper::{WatchedEvent, ZooKeeper, ZooKeeperExt};
+use noria::consensus::{Authority, LocalAuthority};
+use noria_server::{Builder, ControllerHandle};
 
-use noria_mysql::NoriaBackend;
-
-// Appends a unique ID to deployment strings, to avoid collisions between tests.
-struct Deployment {
-    name: String,
-}
-
-impl Deployment {
-    fn new(prefix: &str) -> Self {
-        let current_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-        let name = format!(
-            "{}.{}.{}",
-            prefix,
-            current_time.as_secs(),
-            current_time.subsec_nanos()
-        );
-
-        Self { name }
-    }
-}
-
-impl Drop for Deployment {
-    fn drop(&mut self) {
-        // Remove the ZK data if we created any:
-        let zk = ZooKeeper::connect(
-            "127.0.0.1:2181",
-            Duration::from_secs(3),
-            |_: WatchedEvent| {},
-        );
-
-        if let Ok(z) = zk {
-            let _ = z.delete_recursive(&format!("/{}", self.name));
-        }
-    }
-}
+use noria_mysql::{AutoIncrements, EndpointRegistry, NoriaBackend, QueryCache};
 
 fn sleep() {
     thread::sleep(Duration::from_millis(200));
 }
 
-// Initializes a Noria worker and starts processing MySQL queries against it.
-fn setup(deployment: &Deployment) -> mysql::Opts {
-    let zk_addr = "127.0.0.1:2181";
+fn logger() -> slog::Logger {
     // Run with VERBOSE=1 for log output.
     let verbose = match env::var("VERBOSE") {
         Ok(value) => {
@@ -67,68 +29,103 @@ fn setup(deployment: &Deployment) -> mysql::Opts {
         Err(_) => false,
     };
 
-    let logger = if verbose {
+    if verbose {
         noria_server::logger_pls()
     } else {
         slog::Logger::root(slog::Discard, o!())
-    };
+    }
+}
+
+// Starts an in-process Noria instance and an adapter that serves MySQL queries against it. The
+// Noria instance runs until the returned `Noria` is dropped.
+fn setup() -> (Noria, mysql::Opts) {
+    // each test gets its own Noria, found through an authority that lives only in this process
+    let authority = Arc::new(LocalAuthority::new());
+    let noria = start_noria(authority.clone());
+    (noria, start_adapter(authority))
+}
 
+// An in-process Noria instance, which is shut down when this is dropped.
+struct Noria {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for Noria {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+// Starts an in-process Noria instance that registers with `authority`.
+fn start_noria(authority: Arc<LocalAuthority>) -> Noria {
     let barrier = Arc::new(Barrier::new(2));
+    let (stop, stopped) = mpsc::channel::<()>();
 
-    let l = logger.clone();
-    let n = deployment.name.clone();
     let b = barrier.clone();
-    thread::spawn(move || {
-        let mut authority = ZookeeperAuthority::new(&format!("{}/{}", zk_addr, n)).unwrap();
+    let thread = thread::spawn(move || {
         let mut builder = Builder::default();
-        authority.log_with(l.clone());
-        builder.log_with(l);
+        builder.log_with(logger());
         let mut rt = tokio::runtime::Runtime::new().unwrap();
-        // NOTE(malte): important to assign to a variable here, since otherwise the handle gets
-        // dropped immediately and the Noria instance quits.
-        let _handle = rt.block_on(builder.start(Arc::new(authority))).unwrap();
+        let (mut handle, done) = rt.block_on(builder.start(authority)).unwrap();
         b.wait();
-        loop {
-            thread::sleep(Duration::from_millis(1000));
-        }
+        // the instance runs until the `Noria` is dropped, which closes the channel
+        let _ = stopped.recv();
+        rt.enter(|| handle.shutdown());
+        rt.block_on(done);
     });
 
     barrier.wait();
+    Noria {
+        stop: Some(stop),
+        thread: Some(thread),
+    }
+}
 
-    let auto_increments: Arc<RwLock<HashMap<String, AtomicUsize>>> = Arc::default();
-    let query_cache: Arc<RwLock<HashMap<SelectStatement, String>>> = Arc::default();
-    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
-    let addr = listener.local_addr().unwrap();
-
-    let mut zk_auth = ZookeeperAuthority::new(&format!("{}/{}", zk_addr, deployment.name)).unwrap();
-    zk_auth.log_with(logger.clone());
+// Starts an adapter for the Noria instance that `authority` points to, and returns the options
+// to connect to it with.
+fn start_adapter(authority: Arc<LocalAuthority>) -> mysql::Opts {
+    let logger = logger();
+    let endpoints: Arc<EndpointRegistry> = Arc::default();
+    let query_cache: Arc<QueryCache> = Arc::default();
+    let auto_increments = Arc::new(AutoIncrements::new(authority.clone()));
 
     debug!(logger, "Connecting to Noria...",);
     let mut rt = tokio::runtime::Runtime::new().unwrap();
-    let ch = rt.block_on(ControllerHandle::new(zk_auth)).unwrap();
+    let ch = rt.block_on(ControllerHandle::make(authority)).unwrap();
     debug!(logger, "Connected!");
 
+    let mut listener = rt
+        .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+
     // no need for a barrier here since accept() acts as one
     thread::spawn(move || {
-        let (s, _) = listener.accept().unwrap();
-
-        let stats = (Arc::new(AtomicUsize::new(0)), None);
-        let primed = Arc::new(AtomicBool::new(false));
-        let b = NoriaBackend::new(
-            rt.handle().clone(),
-            ch,
-            auto_increments,
-            query_cache,
-            stats,
-            primed,
-            false,
-            true,
-            true,
-        );
-        let mut b = rt.block_on(b);
-
-        MysqlIntermediary::run_on_tcp(&mut b, s).unwrap();
-        drop(rt);
+        rt.block_on(async move {
+            let (s, _) = listener.accept().await.unwrap();
+
+            let stats = (Arc::new(AtomicUsize::new(0)), None);
+            let primed = Arc::new(AtomicBool::new(false));
+            let b = NoriaBackend::new(
+                ch,
+                endpoints,
+                auto_increments,
+                query_cache,
+                None,
+                stats,
+                primed,
+                false,
+                true,
+                true,
+            )
+            .await;
+
+            MysqlIntermediary::run_on_tcp(b, s).await.unwrap();
+        });
     });
 
     mysql::OptsBuilder::default().tcp_port(addr.port()).into()
@@ -136,8 +133,7 @@ fn setup(deployment: &Deployment) -> mysql::Opts {
 
 #[test]
 fn delete_basic() {
-    let d = Deployment::new("delete_basic");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, PRIMARY KEY(id))")
         .unwrap();
@@ -167,8 +163,7 @@ fn delete_basic() {
 
 #[test]
 fn delete_only_constraint() {
-    let d = Deployment::new("delete_only_constraint");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     // Note that this doesn't have `id int PRIMARY KEY` like the other tests:
     conn.query_drop("CREATE TABLE Cats (id int, name VARCHAR(255), PRIMARY KEY(id))")
@@ -195,8 +190,7 @@ fn delete_only_constraint() {
 
 #[test]
 fn delete_multiple() {
-    let d = Deployment::new("delete_multiple");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, PRIMARY KEY(id))")
         .unwrap();
@@ -230,8 +224,7 @@ fn delete_multiple() {
 
 #[test]
 fn delete_bogus() {
-    let d = Deployment::new("delete_bogus");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, PRIMARY KEY(id))")
         .unwrap();
@@ -246,8 +239,7 @@ fn delete_bogus() {
 
 #[test]
 fn delete_bogus_valid_and() {
-    let d = Deployment::new("delete_bogus_valid_and");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, PRIMARY KEY(id))")
         .unwrap();
@@ -278,8 +270,7 @@ fn delete_bogus_valid_and() {
 
 #[test]
 fn delete_bogus_valid_or() {
-    let d = Deployment::new("delete_bogus_valid_or");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, PRIMARY KEY(id))")
         .unwrap();
@@ -310,33 +301,208 @@ fn delete_bogus_valid_or() {
 
 #[test]
 fn delete_other_column() {
-    let d = Deployment::new("delete_other_column");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
         .unwrap();
     sleep();
 
-    conn.query_drop("DELETE FROM Cats WHERE Cats.id = 1 OR Cats.name = \"Bob\"")
-        .unwrap_err();
+    conn.query_drop("INSERT INTO Cats (id, name) VALUES (1, \"Jane\"), (2, \"Bob\"), (3, \"Tom\")")
+        .unwrap();
+    sleep();
+
+    {
+        let deleted = conn
+            .query_iter("DELETE FROM Cats WHERE Cats.id = 1 OR Cats.name = \"Bob\"")
+            .unwrap();
+        assert_eq!(deleted.affected_rows(), 2);
+        sleep();
+    }
+
+    let mut rows: Vec<i32> = conn
+        .query("SELECT Cats.id FROM Cats WHERE Cats.id IN (1, 2, 3)")
+        .unwrap();
+    rows.sort_unstable();
+    assert_eq!(rows, vec![3]);
 }
 
 #[test]
 fn delete_no_keys() {
-    let d = Deployment::new("delete_no_keys");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    conn.query_drop("INSERT INTO Cats (id, name) VALUES (1, \"Bob\"), (2, \"Jane\")")
+        .unwrap();
+    sleep();
+
+    {
+        let deleted = conn.query_iter("DELETE FROM Cats WHERE 1 = 1").unwrap();
+        assert_eq!(deleted.affected_rows(), 2);
+        sleep();
+    }
+
+    let rows: Vec<i32> = conn
+        .query("SELECT Cats.id FROM Cats WHERE Cats.id IN (1, 2)")
+        .unwrap();
+    assert!(rows.is_empty());
+}
+
+#[test]
+fn delete_without_where() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    conn.query_drop("INSERT INTO Cats (id, name) VALUES (1, \"Bob\"), (2, \"Jane\")")
+        .unwrap();
+    sleep();
+
+    {
+        let deleted = conn.query_iter("DELETE FROM Cats").unwrap();
+        assert_eq!(deleted.affected_rows(), 2);
+        sleep();
+    }
+
+    let rows: Vec<i32> = conn
+        .query("SELECT Cats.id FROM Cats WHERE Cats.id IN (1, 2)")
+        .unwrap();
+    assert!(rows.is_empty());
+}
+
+#[test]
+fn delete_range() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Sessions (id int PRIMARY KEY, expires int, PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    conn.query_drop("INSERT INTO Sessions (id, expires) VALUES (1, 10), (2, 20), (3, 30)")
+        .unwrap();
+    sleep();
+
+    {
+        let deleted = conn
+            .query_iter("DELETE FROM Sessions WHERE Sessions.expires < 25")
+            .unwrap();
+        assert_eq!(deleted.affected_rows(), 2);
+        sleep();
+    }
+
+    let rows: Vec<i32> = conn
+        .query("SELECT Sessions.id FROM Sessions WHERE Sessions.id IN (1, 2, 3)")
+        .unwrap();
+    assert_eq!(rows, vec![3]);
+
+    // the bound is applied by the adapter, so other bounds work just the same
+    {
+        let deleted = conn
+            .exec_iter("DELETE FROM Sessions WHERE Sessions.expires < ?", (35,))
+            .unwrap();
+        assert_eq!(deleted.affected_rows(), 1);
+        sleep();
+    }
+
+    // a bound under OR is left to Noria
+    conn.query_drop("INSERT INTO Sessions (id, expires) VALUES (4, 10), (5, 40), (6, 50)")
+        .unwrap();
+    sleep();
+    {
+        let deleted = conn
+            .query_iter("DELETE FROM Sessions WHERE Sessions.id = 4 OR Sessions.expires > 45")
+            .unwrap();
+        assert_eq!(deleted.affected_rows(), 2);
+        sleep();
+    }
+
+    let rows: Vec<i32> = conn
+        .query("SELECT Sessions.id FROM Sessions WHERE Sessions.id IN (4, 5, 6)")
+        .unwrap();
+    assert_eq!(rows, vec![5]);
+}
+
+#[test]
+fn delete_prepared() {
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
         .unwrap();
     sleep();
 
-    conn.query_drop("DELETE FROM Cats WHERE 1 = 1").unwrap_err();
+    conn.query_drop("INSERT INTO Cats (id, name) VALUES (1, \"Bob\"), (2, \"Jane\")")
+        .unwrap();
+    sleep();
+
+    {
+        let deleted = conn
+            .exec_iter("DELETE FROM Cats WHERE Cats.id = ?", (1,))
+            .unwrap();
+        assert_eq!(deleted.affected_rows(), 1);
+        sleep();
+    }
+
+    {
+        let deleted = conn
+            .exec_iter("DELETE FROM Cats WHERE Cats.id = ?", (1,))
+            .unwrap();
+        assert_eq!(deleted.affected_rows(), 0);
+        sleep();
+    }
+
+    let rows: Vec<i32> = conn
+        .query("SELECT Cats.id FROM Cats WHERE Cats.id IN (1, 2)")
+        .unwrap();
+    assert_eq!(rows, vec![2]);
+}
+
+#[test]
+fn delete_prepared_timestamp() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Events (id int PRIMARY KEY, at DATETIME, PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    let at = |day| mysql::Value::Date(2020, 1, day, 12, 0, 0, 0);
+    conn.exec_batch(
+        "INSERT INTO Events (id, at) VALUES (?, ?)",
+        vec![(1, at(1)), (2, at(2)), (3, at(3))],
+    )
+    .unwrap();
+    sleep();
+
+    // the parameter stays a timestamp, both as a bound and as a key
+    {
+        let deleted = conn
+            .exec_iter("DELETE FROM Events WHERE Events.at < ?", (at(2),))
+            .unwrap();
+        assert_eq!(deleted.affected_rows(), 1);
+        sleep();
+    }
+    {
+        let deleted = conn
+            .exec_iter("DELETE FROM Events WHERE Events.at = ?", (at(3),))
+            .unwrap();
+        assert_eq!(deleted.affected_rows(), 1);
+        sleep();
+    }
+
+    // (Noria reuses the view that looked up Events.at for this query, and with it that column)
+    let rows: Vec<(i32, mysql::Value)> = conn
+        .query("SELECT Events.id, Events.at FROM Events WHERE Events.id IN (1, 2, 3)")
+        .unwrap();
+    let ids: Vec<i32> = rows.into_iter().map(|(id, _)| id).collect();
+    assert_eq!(ids, vec![2]);
 }
 
 #[test]
 fn delete_compound_primary_key() {
-    let d = Deployment::new("delete_compound_primary_key");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop(
         "CREATE TABLE Vote (aid int, uid int, reason VARCHAR(255), PRIMARY KEY(aid, uid))",
@@ -369,8 +535,7 @@ fn delete_compound_primary_key() {
 #[test]
 #[ignore]
 fn delete_multi_compound_primary_key() {
-    let d = Deployment::new("delete_multi_compound_primary_key");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop(
         "CREATE TABLE Vote (aid int, uid int, reason VARCHAR(255), PRIMARY KEY(aid, uid))",
@@ -400,8 +565,7 @@ fn delete_multi_compound_primary_key() {
 
 #[test]
 fn update_basic() {
-    let d = Deployment::new("update_basic");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
         .unwrap();
@@ -423,13 +587,12 @@ fn update_basic() {
         .query_first("SELECT Cats.name FROM Cats WHERE Cats.id = 1")
         .unwrap()
         .unwrap();
-    assert_eq!(name, String::from("\"Rusty\""));
+    assert_eq!(name, String::from("Rusty"));
 }
 
 #[test]
 fn update_basic_prepared() {
-    let d = Deployment::new("update_basic");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
         .unwrap();
@@ -454,7 +617,7 @@ fn update_basic_prepared() {
         .query_first("SELECT Cats.name FROM Cats WHERE Cats.id = 1")
         .unwrap()
         .unwrap();
-    assert_eq!(name, String::from("\"Rusty\""));
+    assert_eq!(name, String::from("Rusty"));
 
     {
         let updated = conn
@@ -476,8 +639,7 @@ fn update_basic_prepared() {
 
 #[test]
 fn update_compound_primary_key() {
-    let d = Deployment::new("update_compound_primary_key");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop(
         "CREATE TABLE Vote (aid int, uid int, reason VARCHAR(255), PRIMARY KEY(aid, uid))",
@@ -500,17 +662,16 @@ fn update_compound_primary_key() {
 
     let q = "SELECT Vote.reason FROM Vote WHERE Vote.aid = 1 AND Vote.uid = 2";
     let name: String = conn.query_first(q).unwrap().unwrap();
-    assert_eq!(name, String::from("\"better\""));
+    assert_eq!(name, String::from("better"));
 
     let q = "SELECT Vote.reason FROM Vote WHERE Vote.aid = 1 AND Vote.uid = 3";
     let name: String = conn.query_first(q).unwrap().unwrap();
-    assert_eq!(name, String::from("\"still okay\""));
+    assert_eq!(name, String::from("still okay"));
 }
 
 #[test]
 fn update_only_constraint() {
-    let d = Deployment::new("update_only_constraint");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     // Note that this doesn't have `id int PRIMARY KEY` like the other tests:
     conn.query_drop("CREATE TABLE Cats (id int, name VARCHAR(255), PRIMARY KEY(id))")
@@ -533,13 +694,12 @@ fn update_only_constraint() {
         .query_first("SELECT Cats.name FROM Cats WHERE Cats.id = 1")
         .unwrap()
         .unwrap();
-    assert_eq!(name, String::from("\"Rusty\""));
+    assert_eq!(name, String::from("Rusty"));
 }
 
 #[test]
 fn update_pkey() {
-    let d = Deployment::new("update_pkey");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
         .unwrap();
@@ -560,7 +720,7 @@ fn update_pkey() {
         .query_first("SELECT Cats.name FROM Cats WHERE Cats.id = 10")
         .unwrap()
         .unwrap();
-    assert_eq!(name, String::from("\"Rusty\""));
+    assert_eq!(name, String::from("Rusty"));
     let old_row = conn
         .query_first::<mysql::Row, _>("SELECT Cats.name FROM Cats WHERE Cats.id = 1")
         .unwrap();
@@ -569,8 +729,7 @@ fn update_pkey() {
 
 #[test]
 fn update_separate() {
-    let d = Deployment::new("update_separate");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
         .unwrap();
@@ -600,42 +759,137 @@ fn update_separate() {
         .query_first("SELECT Cats.name FROM Cats WHERE Cats.id = 1")
         .unwrap()
         .unwrap();
-    assert_eq!(name, String::from("\"Rusty II\""));
+    assert_eq!(name, String::from("Rusty II"));
 }
 
 #[test]
 fn update_no_keys() {
-    let d = Deployment::new("update_no_keys");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
         .unwrap();
     sleep();
 
-    let query = "UPDATE Cats SET Cats.name = \"Rusty\" WHERE 1 = 1";
-    conn.query_drop(query).unwrap_err();
+    conn.query_drop("INSERT INTO Cats (id, name) VALUES (1, \"Bob\"), (2, \"Jane\")")
+        .unwrap();
+    sleep();
+
+    {
+        let query = "UPDATE Cats SET Cats.name = \"Rusty\" WHERE 1 = 1";
+        let updated = conn.query_iter(query).unwrap();
+        assert_eq!(updated.affected_rows(), 2);
+        sleep();
+    }
+
+    let names: Vec<String> = conn
+        .query("SELECT Cats.name FROM Cats WHERE Cats.id IN (1, 2)")
+        .unwrap();
+    assert_eq!(names, vec!["Rusty", "Rusty"]);
 }
 
 #[test]
 fn update_other_column() {
-    let d = Deployment::new("update_no_keys");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
         .unwrap();
     sleep();
 
-    let query = "UPDATE Cats SET Cats.name = \"Rusty\" WHERE Cats.name = \"Bob\"";
-    conn.query_drop(query).unwrap_err();
+    conn.query_drop("INSERT INTO Cats (id, name) VALUES (1, \"Bob\"), (2, \"Jane\")")
+        .unwrap();
+    sleep();
+
+    {
+        let query = "UPDATE Cats SET Cats.name = \"Rusty\" WHERE Cats.name = \"Bob\"";
+        let updated = conn.query_iter(query).unwrap();
+        assert_eq!(updated.affected_rows(), 1);
+        sleep();
+    }
+
+    let name: Option<String> = conn
+        .query_first("SELECT Cats.name FROM Cats WHERE Cats.id = 1")
+        .unwrap();
+    assert_eq!(name, Some(String::from("Rusty")));
+    let name: Option<String> = conn
+        .query_first("SELECT Cats.name FROM Cats WHERE Cats.id = 2")
+        .unwrap();
+    assert_eq!(name, Some(String::from("Jane")));
 }
 
 #[test]
-#[ignore]
-fn update_no_changes() {
-    // ignored because we currently *always* return 1 row(s) affected.
+fn update_where_in() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    conn.query_drop("INSERT INTO Cats (id, name) VALUES (1, \"Bob\"), (2, \"Jane\"), (3, \"Tom\")")
+        .unwrap();
+    sleep();
+
+    {
+        let updated = conn
+            .query_iter("UPDATE Cats SET Cats.name = \"Rusty\" WHERE Cats.id IN (1, 3)")
+            .unwrap();
+        assert_eq!(updated.affected_rows(), 2);
+        sleep();
+    }
+
+    let mut rows: Vec<(i32, String)> = conn
+        .query("SELECT Cats.id, Cats.name FROM Cats WHERE Cats.id IN (1, 2, 3)")
+        .unwrap();
+    rows.sort();
+    assert_eq!(
+        rows,
+        vec![
+            (1, String::from("Rusty")),
+            (2, String::from("Jane")),
+            (3, String::from("Rusty")),
+        ]
+    );
+}
+
+#[test]
+fn update_negated_range() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Sessions (id int PRIMARY KEY, expires int, PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    conn.query_drop("INSERT INTO Sessions (id, expires) VALUES (1, 10), (2, 20), (3, 30)")
+        .unwrap();
+    sleep();
+
+    // the bound under NOT is turned around, and then applied by the adapter
+    {
+        let updated = conn
+            .query_iter(
+                "UPDATE Sessions SET Sessions.expires = 0 WHERE NOT (Sessions.expires < 25)",
+            )
+            .unwrap();
+        assert_eq!(updated.affected_rows(), 1);
+        sleep();
+    }
+
+    let mut rows: Vec<(i32, i32)> = conn
+        .query("SELECT Sessions.id, Sessions.expires FROM Sessions WHERE Sessions.id IN (1, 2, 3)")
+        .unwrap();
+    rows.sort_unstable();
+    assert_eq!(rows, vec![(1, 10), (2, 20), (3, 0)]);
+
+    // and so is a negated bound in a SELECT
+    let mut rows: Vec<i32> = conn
+        .query("SELECT Sessions.id FROM Sessions WHERE NOT (Sessions.expires > 15)")
+        .unwrap();
+    rows.sort_unstable();
+    assert_eq!(rows, vec![1, 3]);
+}
 
-    let d = Deployment::new("update_no_changes");
-    let opts = setup(&d);
+#[test]
+fn update_no_changes() {
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
         .unwrap();
@@ -651,10 +905,30 @@ fn update_no_changes() {
     assert_eq!(updated.affected_rows(), 0);
 }
 
+#[test]
+fn update_found_rows() {
+    let (_noria, opts) = setup();
+    let opts = mysql::OptsBuilder::from_opts(opts)
+        .additional_capabilities(CapabilityFlags::CLIENT_FOUND_ROWS);
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    conn.query_drop("INSERT INTO Cats (id, name) VALUES (1, \"Bob\"), (2, \"Jane\")")
+        .unwrap();
+    sleep();
+
+    // the client asked for the rows an UPDATE matches, whether or not it changes them
+    let updated = conn
+        .query_iter("UPDATE Cats SET Cats.name = \"Bob\" WHERE Cats.id IN (1, 2, 3)")
+        .unwrap();
+    assert_eq!(updated.affected_rows(), 2);
+}
+
 #[test]
 fn update_bogus() {
-    let d = Deployment::new("update_bogus");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
         .unwrap();
@@ -664,14 +938,23 @@ fn update_bogus() {
         .unwrap();
     sleep();
 
-    conn.query_drop("UPDATE Cats SET Cats.name = \"Rusty\" WHERE Cats.id = 1 AND Cats.id = 2")
-        .unwrap_err();
+    {
+        // `id` can't be both 1 and 2!
+        let query = "UPDATE Cats SET Cats.name = \"Rusty\" WHERE Cats.id = 1 AND Cats.id = 2";
+        let updated = conn.query_iter(query).unwrap();
+        assert_eq!(updated.affected_rows(), 0);
+        sleep();
+    }
+
+    let name: Option<String> = conn
+        .query_first("SELECT Cats.name FROM Cats WHERE Cats.id = 1")
+        .unwrap();
+    assert_eq!(name, Some(String::from("Bob")));
 }
 
 #[test]
 fn select_collapse_where_in() {
-    let d = Deployment::new("collapsed_where");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
         .unwrap();
@@ -690,8 +973,8 @@ fn select_collapse_where_in() {
         .map(|mut row: mysql::Row| row.take::<String, _>(0).unwrap())
         .collect();
     assert_eq!(names.len(), 2);
-    assert!(names.iter().any(|s| s == "\"Bob\""));
-    assert!(names.iter().any(|s| s == "\"Jane\""));
+    assert!(names.iter().any(|s| s == "Bob"));
+    assert!(names.iter().any(|s| s == "Jane"));
 
     let names: Vec<String> = conn
         .exec("SELECT Cats.name FROM Cats WHERE Cats.id IN (?, ?)", (1, 2))
@@ -700,8 +983,19 @@ fn select_collapse_where_in() {
         .map(|mut row: mysql::Row| row.take::<String, _>(0).unwrap())
         .collect();
     assert_eq!(names.len(), 2);
-    assert!(names.iter().any(|s| s == "\"Bob\""));
-    assert!(names.iter().any(|s| s == "\"Jane\""));
+    assert!(names.iter().any(|s| s == "Bob"));
+    assert!(names.iter().any(|s| s == "Jane"));
+
+    // a list can mix literals and placeholders
+    let names: Vec<String> = conn
+        .exec("SELECT Cats.name FROM Cats WHERE Cats.id IN (1, ?)", (2,))
+        .unwrap()
+        .into_iter()
+        .map(|mut row: mysql::Row| row.take::<String, _>(0).unwrap())
+        .collect();
+    assert_eq!(names.len(), 2);
+    assert!(names.iter().any(|s| s == "Bob"));
+    assert!(names.iter().any(|s| s == "Jane"));
 
     // some lookups give empty results
     let names: Vec<String> = conn
@@ -711,8 +1005,8 @@ fn select_collapse_where_in() {
         .map(|mut row: mysql::Row| row.take::<String, _>(0).unwrap())
         .collect();
     assert_eq!(names.len(), 2);
-    assert!(names.iter().any(|s| s == "\"Bob\""));
-    assert!(names.iter().any(|s| s == "\"Jane\""));
+    assert!(names.iter().any(|s| s == "Bob"));
+    assert!(names.iter().any(|s| s == "Jane"));
 
     let names: Vec<String> = conn
         .exec(
@@ -724,8 +1018,8 @@ fn select_collapse_where_in() {
         .map(|mut row: mysql::Row| row.take::<String, _>(0).unwrap())
         .collect();
     assert_eq!(names.len(), 2);
-    assert!(names.iter().any(|s| s == "\"Bob\""));
-    assert!(names.iter().any(|s| s == "\"Jane\""));
+    assert!(names.iter().any(|s| s == "Bob"));
+    assert!(names.iter().any(|s| s == "Jane"));
 
     // also track another parameter
     let names: Vec<String> = conn
@@ -735,7 +1029,7 @@ fn select_collapse_where_in() {
         .map(|mut row: mysql::Row| row.take::<String, _>(0).unwrap())
         .collect();
     assert_eq!(names.len(), 1);
-    assert!(names.iter().any(|s| s == "\"Bob\""));
+    assert!(names.iter().any(|s| s == "Bob"));
 
     let names: Vec<String> = conn
         .exec(
@@ -747,14 +1041,352 @@ fn select_collapse_where_in() {
         .map(|mut row: mysql::Row| row.take::<String, _>(0).unwrap())
         .collect();
     assert_eq!(names.len(), 1);
-    assert!(names.iter().any(|s| s == "\"Bob\""));
-}
+    assert!(names.iter().any(|s| s == "Bob"));
 
-#[test]
-fn basic_select() {
-    let d = Deployment::new("basic_select");
-    let opts = setup(&d);
-    let mut conn = mysql::Conn::new(opts).unwrap();
+    // listing a value twice does not return its rows twice
+    let names: Vec<String> = conn
+        .query("SELECT Cats.name FROM Cats WHERE Cats.id IN (1, 1)")
+        .unwrap();
+    assert_eq!(names.len(), 1);
+
+    // an empty list matches nothing
+    let names: Vec<String> = conn
+        .query("SELECT Cats.name FROM Cats WHERE Cats.id IN ()")
+        .unwrap();
+    assert!(names.is_empty());
+}
+
+#[test]
+fn select_collapse_multiple_where_in() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Vote (aid int, uid int, PRIMARY KEY(aid, uid))")
+        .unwrap();
+    sleep();
+
+    conn.query_drop("INSERT INTO Vote (aid, uid) VALUES (1, 1), (1, 2), (2, 1), (2, 3), (3, 1)")
+        .unwrap();
+    sleep();
+
+    let mut rows: Vec<(i32, i32)> = conn
+        .query(
+            "SELECT Vote.aid, Vote.uid FROM Vote WHERE Vote.aid IN (1, 2) AND Vote.uid IN (1, 3)",
+        )
+        .unwrap();
+    rows.sort_unstable();
+    assert_eq!(rows, vec![(1, 1), (2, 1), (2, 3)]);
+
+    let mut rows: Vec<(i32, i32)> = conn
+        .exec(
+            "SELECT Vote.aid, Vote.uid FROM Vote WHERE Vote.aid IN (?, ?) AND Vote.uid IN (?, ?)",
+            (1, 2, 1, 3),
+        )
+        .unwrap();
+    rows.sort_unstable();
+    assert_eq!(rows, vec![(1, 1), (2, 1), (2, 3)]);
+}
+
+#[test]
+fn select_or_equalities() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    conn.query_drop("INSERT INTO Cats (id, name) VALUES (1, \"Bob\"), (2, \"Jane\"), (3, \"Tom\")")
+        .unwrap();
+    sleep();
+
+    let mut ids: Vec<i32> = conn
+        .query("SELECT Cats.id FROM Cats WHERE Cats.id = 1 OR Cats.id = 3")
+        .unwrap();
+    ids.sort_unstable();
+    assert_eq!(ids, vec![1, 3]);
+
+    let mut ids: Vec<i32> = conn
+        .exec(
+            "SELECT Cats.id FROM Cats WHERE Cats.id = ? OR Cats.id = 2 OR Cats.id = ?",
+            (1, 3),
+        )
+        .unwrap();
+    ids.sort_unstable();
+    assert_eq!(ids, vec![1, 2, 3]);
+
+    // the same row is only returned once, however often it is asked for
+    let ids: Vec<i32> = conn
+        .exec(
+            "SELECT Cats.id FROM Cats WHERE Cats.id = ? OR Cats.id = ?",
+            (2, 2),
+        )
+        .unwrap();
+    assert_eq!(ids, vec![2]);
+    let ids: Vec<i32> = conn
+        .query("SELECT Cats.id FROM Cats WHERE Cats.id = 3 OR Cats.id = 3")
+        .unwrap();
+    assert_eq!(ids, vec![3]);
+}
+
+#[test]
+fn select_where_in_order_limit() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Post (id int, author int, ts int, PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    conn.query_drop(
+        "INSERT INTO Post (id, author, ts) VALUES (1, 1, 10), (2, 2, 40), (3, 1, 30), (4, 3, 20)",
+    )
+    .unwrap();
+    sleep();
+
+    let posts: Vec<(i32, i32)> = conn
+        .query(
+            "SELECT Post.id, Post.ts FROM Post WHERE Post.author IN (1, 2) \
+             ORDER BY Post.ts DESC LIMIT 2",
+        )
+        .unwrap();
+    assert_eq!(posts, vec![(2, 40), (3, 30)]);
+
+    let posts: Vec<(i32, i32)> = conn
+        .exec(
+            "SELECT Post.id, Post.ts FROM Post WHERE Post.author IN (?, ?, ?) \
+             ORDER BY Post.ts LIMIT 2 OFFSET 1",
+            (1, 2, 3),
+        )
+        .unwrap();
+    assert_eq!(posts, vec![(4, 20), (3, 30)]);
+
+    // the offset applies to the merged rows, not to the rows of each key
+    let posts: Vec<(i32, i32)> = conn
+        .query(
+            "SELECT Post.id, Post.ts FROM Post WHERE Post.author IN (1, 3) \
+             ORDER BY Post.ts DESC LIMIT 1 OFFSET 1",
+        )
+        .unwrap();
+    assert_eq!(posts, vec![(4, 20)]);
+
+    let posts: Vec<(i32, i32)> = conn
+        .exec(
+            "SELECT Post.id, Post.ts FROM Post WHERE Post.author IN (?, ?, ?) \
+             ORDER BY Post.ts LIMIT 2 OFFSET 1",
+            (1, 1, 1),
+        )
+        .unwrap();
+    assert_eq!(posts, vec![(3, 30)]);
+
+    let authors: Vec<i32> = conn
+        .query(
+            "SELECT DISTINCT Post.author FROM Post WHERE Post.author IN (1, 3) \
+             ORDER BY Post.author",
+        )
+        .unwrap();
+    assert_eq!(authors, vec![1, 3]);
+}
+
+#[test]
+fn select_where_in_aggregates() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Score (id int, team int, points int, PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    conn.query_drop(
+        "INSERT INTO Score (id, team, points) \
+         VALUES (1, 1, 10), (2, 1, 20), (3, 2, 60), (4, 3, 5), (5, 3, 10)",
+    )
+    .unwrap();
+    sleep();
+
+    let totals: Vec<(i32, i32, i32, i32)> = conn
+        .query(
+            "SELECT COUNT(*), SUM(Score.points), MIN(Score.points), MAX(Score.points) \
+             FROM Score WHERE Score.team IN (1, 2)",
+        )
+        .unwrap();
+    assert_eq!(totals, vec![(3, 90, 10, 60)]);
+
+    let averages: Vec<f64> = conn
+        .exec(
+            "SELECT AVG(Score.points) FROM Score WHERE Score.team IN (?, ?)",
+            (1, 2),
+        )
+        .unwrap();
+    assert_eq!(averages, vec![30.0]);
+
+    // queries of the same shape share a view, however many values they list
+    let averages: Vec<f64> = conn
+        .query("SELECT AVG(Score.points) FROM Score WHERE Score.team IN (3)")
+        .unwrap();
+    assert_eq!(averages, vec![7.5]);
+    let averages: Vec<f64> = conn
+        .query("SELECT AVG(Score.points) FROM Score WHERE Score.team IN (1, 2)")
+        .unwrap();
+    assert_eq!(averages, vec![30.0]);
+
+    // a value listed twice counts once
+    let totals: Vec<(i32, i32)> = conn
+        .query("SELECT COUNT(*), SUM(Score.points) FROM Score WHERE Score.team IN (1, 1)")
+        .unwrap();
+    assert_eq!(totals, vec![(2, 30)]);
+
+    let mut counts: Vec<(i32, i32)> = conn
+        .query(
+            "SELECT Score.points, COUNT(*) FROM Score WHERE Score.team IN (1, 3) \
+             GROUP BY Score.points",
+        )
+        .unwrap();
+    counts.sort_unstable();
+    assert_eq!(counts, vec![(5, 1), (10, 2), (20, 1)]);
+}
+
+#[test]
+fn select_column_types() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop(
+        "CREATE TABLE Types (id int, c CHAR(4), tt TINYTEXT, b BLOB, vb VARBINARY(16), \
+         d DATE, ts TIMESTAMP, dbl DOUBLE, r REAL, e ENUM('a', 'b'), ub BIGINT UNSIGNED, \
+         PRIMARY KEY(id))",
+    )
+    .unwrap();
+    sleep();
+
+    conn.query_drop("INSERT INTO Types (id, c, e) VALUES (1, 'abcd', 'b')")
+        .unwrap();
+    sleep();
+
+    let stmt = conn
+        .prep(
+            "SELECT Types.c, Types.tt, Types.b, Types.vb, Types.d, Types.ts, Types.dbl, Types.r, \
+             Types.e, Types.ub FROM Types WHERE Types.id = ?",
+        )
+        .unwrap();
+    let types: Vec<_> = stmt.columns().iter().map(|c| c.column_type()).collect();
+    assert_eq!(
+        types,
+        vec![
+            ColumnType::MYSQL_TYPE_STRING,
+            ColumnType::MYSQL_TYPE_VAR_STRING,
+            ColumnType::MYSQL_TYPE_BLOB,
+            ColumnType::MYSQL_TYPE_VAR_STRING,
+            ColumnType::MYSQL_TYPE_DATE,
+            ColumnType::MYSQL_TYPE_TIMESTAMP,
+            ColumnType::MYSQL_TYPE_DOUBLE,
+            ColumnType::MYSQL_TYPE_DOUBLE,
+            ColumnType::MYSQL_TYPE_STRING,
+            ColumnType::MYSQL_TYPE_LONGLONG,
+        ]
+    );
+    assert!(stmt.columns()[2].flags().contains(ColumnFlags::BLOB_FLAG));
+    assert!(stmt.columns()[8].flags().contains(ColumnFlags::ENUM_FLAG));
+    assert!(stmt.columns()[9]
+        .flags()
+        .contains(ColumnFlags::UNSIGNED_FLAG));
+
+    let rows: Vec<(String, String)> = conn
+        .exec(
+            "SELECT Types.c, Types.e FROM Types WHERE Types.id = ?",
+            (1,),
+        )
+        .unwrap();
+    assert_eq!(rows, vec![("abcd".into(), "b".into())]);
+}
+
+#[test]
+fn select_64_bit_integers() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Big (id int, u BIGINT UNSIGNED, s BIGINT, PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    conn.exec_drop(
+        "INSERT INTO Big (id, u, s) VALUES (?, ?, ?)",
+        (1, u64::max_value(), i64::min_value()),
+    )
+    .unwrap();
+    sleep();
+
+    // in the binary protocol
+    let rows: Vec<(u64, i64)> = conn
+        .exec("SELECT Big.u, Big.s FROM Big WHERE Big.id = ?", (1,))
+        .unwrap();
+    assert_eq!(rows, vec![(u64::max_value(), i64::min_value())]);
+
+    // and in the text protocol
+    let rows: Vec<(u64, i64)> = conn
+        .query("SELECT Big.u, Big.s FROM Big WHERE Big.id = 1")
+        .unwrap();
+    assert_eq!(rows, vec![(u64::max_value(), i64::min_value())]);
+}
+
+#[test]
+fn binary_values() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Thumb (hash VARBINARY(32), img BLOB, PRIMARY KEY(hash))")
+        .unwrap();
+    sleep();
+
+    let code = |e: mysql::Error| match e {
+        mysql::Error::MySqlError(e) => e.code,
+        e => panic!("unexpected error: {:?}", e),
+    };
+
+    // Noria only stores text, so byte strings that are not text are refused rather than mangled
+    let e = conn
+        .exec_drop(
+            "INSERT INTO Thumb (hash, img) VALUES (?, ?)",
+            (vec![0x00, 0xff, 0x01], b"img".to_vec()),
+        )
+        .unwrap_err();
+    assert_eq!(code(e), 1366);
+    let e = conn
+        .exec::<mysql::Row, _, _>(
+            "SELECT Thumb.img FROM Thumb WHERE Thumb.hash = ?",
+            (vec![0xff],),
+        )
+        .unwrap_err();
+    assert_eq!(code(e), 1366);
+
+    // any other byte string is stored and returned unchanged
+    conn.exec_drop(
+        "INSERT INTO Thumb (hash, img) VALUES (?, ?)",
+        (b"\x01ab".to_vec(), b"text".to_vec()),
+    )
+    .unwrap();
+    sleep();
+    let rows: Vec<(Vec<u8>, Vec<u8>)> = conn
+        .exec(
+            "SELECT Thumb.hash, Thumb.img FROM Thumb WHERE Thumb.hash = ?",
+            (b"\x01ab".to_vec(),),
+        )
+        .unwrap();
+    assert_eq!(rows, vec![(b"\x01ab".to_vec(), b"text".to_vec())]);
+
+    // the adapter compares byte strings by their bytes
+    conn.exec_drop(
+        "INSERT INTO Thumb (hash, img) VALUES (?, ?)",
+        ("\u{ff}".as_bytes().to_vec(), b"high".to_vec()),
+    )
+    .unwrap();
+    sleep();
+    let rows: Vec<Vec<u8>> = conn
+        .exec(
+            "SELECT Thumb.img FROM Thumb WHERE Thumb.hash > ?",
+            (b"a".to_vec(),),
+        )
+        .unwrap();
+    assert_eq!(rows, vec![b"high".to_vec()]);
+}
+
+#[test]
+fn basic_select() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop("CREATE TABLE test (x int, y int)").unwrap();
     sleep();
 
@@ -774,8 +1406,7 @@ fn basic_select() {
 
 #[test]
 fn prepared_select() {
-    let d = Deployment::new("prepared_select");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop("CREATE TABLE test (x int, y int)").unwrap();
     sleep();
@@ -793,12 +1424,280 @@ fn prepared_select() {
         rows.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
         vec![vec![4.into(), 2.into()]]
     );
+
+    // the same query text can be prepared and run ad hoc in either order
+    let q = "SELECT test.y FROM test WHERE test.x = 4";
+    assert_eq!(conn.exec::<i32, _, _>(q, ()).unwrap(), vec![2]);
+    assert_eq!(conn.query::<i32, _>(q).unwrap(), vec![2]);
+    let q = "SELECT test.x FROM test WHERE test.y = 2";
+    assert_eq!(conn.query::<i32, _>(q).unwrap(), vec![4]);
+    assert_eq!(conn.exec::<i32, _, _>(q, ()).unwrap(), vec![4]);
+}
+
+#[test]
+fn prepared_select_range() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE test (x int, y int)").unwrap();
+    sleep();
+
+    conn.query_drop("INSERT INTO test (x, y) VALUES (4, 1), (4, 2), (4, 3), (5, 3)")
+        .unwrap();
+    sleep();
+
+    let mut rows: Vec<(i32, i32)> = conn
+        .exec(
+            "SELECT test.x, test.y FROM test WHERE test.x = ? AND test.y > ?",
+            (4, 1),
+        )
+        .unwrap();
+    rows.sort_unstable();
+    assert_eq!(rows, vec![(4, 2), (4, 3)]);
+
+    let mut rows: Vec<(i32, i32)> = conn
+        .exec(
+            "SELECT test.x, test.y FROM test WHERE test.y >= ? AND test.y < ?",
+            (2, 3),
+        )
+        .unwrap();
+    rows.sort_unstable();
+    assert_eq!(rows, vec![(4, 2)]);
+
+    // the filter compares test.y, even though the query selects it under another name.
+    // (Noria looks up the wrong column if a lookup column is selected under the name of another
+    // column of the table, so this cannot select test.x AS y.)
+    let mut rows: Vec<i32> = conn
+        .exec(
+            "SELECT test.y AS z FROM test WHERE test.x = ? AND test.y > ?",
+            (4, 2),
+        )
+        .unwrap();
+    rows.sort_unstable();
+    assert_eq!(rows, vec![3]);
+
+    // Noria cannot look up by a range under OR
+    assert!(conn
+        .exec::<mysql::Row, _, _>(
+            "SELECT test.x FROM test WHERE test.x = ? OR test.y > ?",
+            (4, 2),
+        )
+        .is_err());
+}
+
+#[test]
+fn prepared_select_like() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    conn.query_drop(
+        "INSERT INTO Cats (id, name) VALUES (1, \"Bob\"), (2, \"Jane\"), (3, \"Bobby\")",
+    )
+    .unwrap();
+    sleep();
+
+    // the filtered column is not selected, and must not show up in the results
+    let mut rows: Vec<mysql::Row> = conn
+        .exec("SELECT Cats.id FROM Cats WHERE Cats.name LIKE ?", ("bob%",))
+        .unwrap();
+    assert!(rows.iter().all(|r| r.len() == 1));
+    let mut ids: Vec<i32> = rows.drain(..).map(|r| r.get(0).unwrap()).collect();
+    ids.sort_unstable();
+    assert_eq!(ids, vec![1, 3]);
+}
+
+#[test]
+fn insert_multiple_prepared() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    {
+        let inserted = conn
+            .exec_iter(
+                "INSERT INTO Cats (id, name) VALUES (?, ?), (?, \"Tom\"), (?, ?)",
+                (1, "Bob", 2, 3, "Jane"),
+            )
+            .unwrap();
+        assert_eq!(inserted.affected_rows(), 3);
+        sleep();
+    }
+
+    let mut rows: Vec<i32> = conn
+        .query("SELECT Cats.id FROM Cats WHERE Cats.id IN (1, 2, 3)")
+        .unwrap();
+    rows.sort_unstable();
+    assert_eq!(rows, vec![1, 2, 3]);
+}
+
+#[test]
+fn upsert_multiple() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Counts (id int PRIMARY KEY, n int, PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    conn.query_drop("INSERT INTO Counts (id, n) VALUES (1, 1)")
+        .unwrap();
+    sleep();
+
+    // like in MySQL, an updated row counts twice
+    {
+        let upserted = conn
+            .query_iter(
+                "INSERT INTO Counts (id, n) VALUES (1, 1), (2, 1) \
+                 ON DUPLICATE KEY UPDATE n = n + 1",
+            )
+            .unwrap();
+        assert_eq!(upserted.affected_rows(), 3);
+        sleep();
+    }
+    // and a row that the update leaves as it was does not count
+    {
+        let upserted = conn
+            .query_iter("INSERT INTO Counts (id, n) VALUES (2, 5) ON DUPLICATE KEY UPDATE n = 1")
+            .unwrap();
+        assert_eq!(upserted.affected_rows(), 0);
+        sleep();
+    }
+
+    let mut rows: Vec<(i32, i32)> = conn
+        .query("SELECT Counts.id, Counts.n FROM Counts WHERE Counts.id IN (1, 2)")
+        .unwrap();
+    rows.sort_unstable();
+    assert_eq!(rows, vec![(1, 2), (2, 1)]);
+}
+
+#[test]
+fn insert_select() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
+        .unwrap();
+    conn.query_drop(
+        "CREATE TABLE Pets (id int PRIMARY KEY AUTO_INCREMENT, name VARCHAR(255), \
+         kind VARCHAR(255) DEFAULT 'cat', PRIMARY KEY(id))",
+    )
+    .unwrap();
+    sleep();
+
+    conn.query_drop("INSERT INTO Cats (id, name) VALUES (1, \"Bob\"), (2, \"Jane\")")
+        .unwrap();
+    sleep();
+
+    {
+        let inserted = conn
+            .query_iter(
+                "INSERT INTO Pets (name) SELECT Cats.name FROM Cats WHERE Cats.id IN (1, 2)",
+            )
+            .unwrap();
+        assert_eq!(inserted.affected_rows(), 2);
+        sleep();
+    }
+
+    let mut rows: Vec<(i32, String)> = conn
+        .query("SELECT Pets.id, Pets.kind FROM Pets WHERE Pets.id IN (1, 2)")
+        .unwrap();
+    rows.sort();
+    assert_eq!(rows, vec![(1, "cat".into()), (2, "cat".into())]);
+}
+
+#[test]
+fn replace_into() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    conn.query_drop("INSERT INTO Cats (id, name) VALUES (1, \"Bob\")")
+        .unwrap();
+    sleep();
+
+    {
+        let replaced = conn
+            .query_iter("REPLACE INTO Cats (id, name) VALUES (1, \"Rusty\"), (2, \"Jane\")")
+            .unwrap();
+        // one row replaced (counts twice) and one row inserted
+        assert_eq!(replaced.affected_rows(), 3);
+        sleep();
+    }
+
+    let mut rows: Vec<(i32, String)> = conn
+        .query("SELECT Cats.id, Cats.name FROM Cats WHERE Cats.id IN (1, 2)")
+        .unwrap();
+    rows.sort();
+    assert_eq!(rows, vec![(1, "Rusty".into()), (2, "Jane".into())]);
+}
+
+#[test]
+fn insert_ignore() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    conn.query_drop("INSERT INTO Cats (id, name) VALUES (1, \"Bob\")")
+        .unwrap();
+    sleep();
+
+    {
+        let inserted = conn
+            .query_iter(
+                "INSERT IGNORE INTO Cats (id, name) VALUES (1, \"Rusty\"), (2, \"Jane\"), \
+                 (2, \"Tom\")",
+            )
+            .unwrap();
+        assert_eq!(inserted.affected_rows(), 1);
+        assert_eq!(inserted.warnings(), 2);
+        sleep();
+    }
+
+    // the skipped rows are listed until the next statement
+    let warnings: Vec<(String, u32, String)> = conn.query("SHOW WARNINGS").unwrap();
+    assert_eq!(
+        warnings,
+        vec![
+            (
+                "Warning".into(),
+                1062,
+                "Duplicate entry '1' for key 'PRIMARY'".into()
+            ),
+            (
+                "Warning".into(),
+                1062,
+                "Duplicate entry '2' for key 'PRIMARY'".into()
+            ),
+        ]
+    );
+
+    let mut rows: Vec<(i32, String)> = conn
+        .query("SELECT Cats.id, Cats.name FROM Cats WHERE Cats.id IN (1, 2)")
+        .unwrap();
+    rows.sort();
+    assert_eq!(rows, vec![(1, "Bob".into()), (2, "Jane".into())]);
+    let warnings: Vec<(String, u32, String)> = conn.query("SHOW WARNINGS").unwrap();
+    assert!(warnings.is_empty());
+
+    // prepared statements report them too
+    conn.exec_drop(
+        "INSERT IGNORE INTO Cats (id, name) VALUES (?, ?)",
+        (1, "Rusty"),
+    )
+    .unwrap();
+    assert_eq!(conn.affected_rows(), 0);
+    assert_eq!(conn.warnings(), 1);
 }
 
 #[test]
 fn create_view() {
-    let d = Deployment::new("create_view");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop("CREATE TABLE test (x int, y int)").unwrap();
     sleep();
@@ -833,8 +1732,7 @@ fn create_view() {
 #[test]
 #[ignore]
 fn create_view_rev() {
-    let d = Deployment::new("create_view_rev");
-    let opts = setup(&d);
+    let (_noria, opts) = setup();
     let mut conn = mysql::Conn::new(opts).unwrap();
     conn.query_drop("CREATE TABLE test (x int, y int)").unwrap();
     sleep();
@@ -853,3 +1751,378 @@ fn create_view_rev() {
         1
     );
 }
+
+#[test]
+fn transaction_commit() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    conn.query_drop("BEGIN").unwrap();
+    conn.query_drop("INSERT INTO Cats (id) VALUES (1)").unwrap();
+    conn.query_drop("INSERT INTO Cats (id) VALUES (2)").unwrap();
+    sleep();
+
+    // writes are held back until COMMIT, but the transaction reads them back
+    let mut rows: Vec<i32> = conn
+        .query("SELECT Cats.id FROM Cats WHERE Cats.id IN (1, 2)")
+        .unwrap();
+    rows.sort_unstable();
+    assert_eq!(rows, vec![1, 2]);
+
+    // only COMMIT itself commits
+    assert!(conn.query_drop("COMMITTED").is_err());
+    conn.query_drop("commit  work;").unwrap();
+    sleep();
+
+    let mut rows: Vec<i32> = conn
+        .query("SELECT Cats.id FROM Cats WHERE Cats.id IN (1, 2)")
+        .unwrap();
+    rows.sort_unstable();
+    assert_eq!(rows, vec![1, 2]);
+}
+
+#[test]
+fn transaction_writes_by_key() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    conn.query_drop("BEGIN").unwrap();
+    conn.query_drop("INSERT INTO Cats (id, name) VALUES (1, \"Bob\"), (2, \"Jane\")")
+        .unwrap();
+
+    // writes by key see the writes that the transaction made before them
+    {
+        let updated = conn
+            .query_iter("UPDATE Cats SET Cats.name = \"Rusty\" WHERE Cats.id = 1")
+            .unwrap();
+        assert_eq!(updated.affected_rows(), 1);
+    }
+    {
+        let deleted = conn
+            .query_iter("DELETE FROM Cats WHERE Cats.id = 2")
+            .unwrap();
+        assert_eq!(deleted.affected_rows(), 1);
+    }
+    assert!(conn
+        .query_drop("INSERT INTO Cats (id, name) VALUES (1, \"Tom\")")
+        .is_err());
+    conn.query_drop("INSERT INTO Cats (id, name) VALUES (2, \"Tom\")")
+        .unwrap();
+
+    conn.query_drop("COMMIT").unwrap();
+    sleep();
+
+    let mut rows: Vec<(i32, String)> = conn
+        .query("SELECT Cats.id, Cats.name FROM Cats WHERE Cats.id IN (1, 2)")
+        .unwrap();
+    rows.sort();
+    assert_eq!(
+        rows,
+        vec![(1, String::from("Rusty")), (2, String::from("Tom"))]
+    );
+}
+
+#[test]
+fn transaction_read_after_write() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
+        .unwrap();
+    conn.query_drop("CREATE TABLE Dogs (id int PRIMARY KEY, PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    conn.query_drop("INSERT INTO Cats (id, name) VALUES (1, \"Bob\"), (3, \"Tom\")")
+        .unwrap();
+    conn.query_drop("INSERT INTO Dogs (id) VALUES (1)").unwrap();
+    sleep();
+
+    conn.query_drop("BEGIN").unwrap();
+    conn.query_drop("INSERT INTO Cats (id, name) VALUES (2, \"Jane\")")
+        .unwrap();
+    conn.query_drop("UPDATE Cats SET Cats.name = \"Rusty\" WHERE Cats.id = 1")
+        .unwrap();
+    conn.query_drop("DELETE FROM Cats WHERE Cats.id = 3")
+        .unwrap();
+
+    // reads of the table see the buffered writes, by key or not, and prepared or not
+    let mut rows: Vec<(i32, String)> = conn
+        .query("SELECT Cats.id, Cats.name FROM Cats WHERE Cats.id IN (1, 2, 3)")
+        .unwrap();
+    rows.sort();
+    assert_eq!(
+        rows,
+        vec![(1, String::from("Rusty")), (2, String::from("Jane"))]
+    );
+    let rows: Vec<String> = conn
+        .exec("SELECT Cats.name FROM Cats WHERE Cats.id = ?", (1,))
+        .unwrap();
+    assert_eq!(rows, vec![String::from("Rusty")]);
+    let mut rows: Vec<i32> = conn.query("SELECT Cats.id FROM Cats").unwrap();
+    rows.sort_unstable();
+    assert_eq!(rows, vec![1, 2]);
+    let rows: Vec<i32> = conn
+        .exec("SELECT Cats.id FROM Cats WHERE Cats.name LIKE ?", ("r%",))
+        .unwrap();
+    assert_eq!(rows, vec![1]);
+
+    // and so do writes that have to find their rows first
+    {
+        let updated = conn
+            .query_iter("UPDATE Cats SET Cats.name = \"Jim\" WHERE Cats.name = \"Jane\"")
+            .unwrap();
+        assert_eq!(updated.affected_rows(), 1);
+    }
+    {
+        let deleted = conn
+            .query_iter("DELETE FROM Cats WHERE Cats.name = \"Tom\"")
+            .unwrap();
+        assert_eq!(deleted.affected_rows(), 0);
+    }
+
+    // there is no telling how the writes change aggregates, so those are refused
+    let e = conn
+        .query::<i32, _>("SELECT COUNT(Cats.id) FROM Cats")
+        .unwrap_err();
+    match e {
+        mysql::Error::MySqlError(e) => assert_eq!(e.code, 1235),
+        e => panic!("unexpected error: {:?}", e),
+    }
+
+    // tables that the transaction has not written to are read as usual
+    let rows: Vec<i32> = conn
+        .query("SELECT Dogs.id FROM Dogs WHERE Dogs.id = 1")
+        .unwrap();
+    assert_eq!(rows, vec![1]);
+
+    conn.query_drop("COMMIT").unwrap();
+    sleep();
+
+    let mut rows: Vec<(i32, String)> = conn
+        .query("SELECT Cats.id, Cats.name FROM Cats WHERE Cats.id IN (1, 2, 3)")
+        .unwrap();
+    rows.sort();
+    assert_eq!(
+        rows,
+        vec![(1, String::from("Rusty")), (2, String::from("Jim"))]
+    );
+}
+
+#[test]
+fn transaction_rollback() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    conn.query_drop("INSERT INTO Cats (id) VALUES (1)").unwrap();
+    sleep();
+
+    conn.query_drop("START TRANSACTION").unwrap();
+    conn.query_drop("DELETE FROM Cats WHERE Cats.id = 1")
+        .unwrap();
+    conn.query_drop("INSERT INTO Cats (id) VALUES (2)").unwrap();
+    // a partial rollback must not throw away the whole transaction
+    assert!(conn.query_drop("ROLLBACK TO SAVEPOINT s").is_err());
+    conn.query_drop("ROLLBACK").unwrap();
+    sleep();
+
+    let rows: Vec<i32> = conn
+        .query("SELECT Cats.id FROM Cats WHERE Cats.id IN (1, 2)")
+        .unwrap();
+    assert_eq!(rows, vec![1]);
+}
+
+#[test]
+fn transaction_autocommit_off() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    conn.query_drop("SET autocommit = 0").unwrap();
+    conn.query_drop("INSERT INTO Cats (id) VALUES (1)").unwrap();
+    sleep();
+
+    // the writes are held back, but the connection reads them back
+    let rows: Vec<i32> = conn
+        .query("SELECT Cats.id FROM Cats WHERE Cats.id > 0")
+        .unwrap();
+    assert_eq!(rows, vec![1]);
+
+    // turning autocommit back on commits the open transaction
+    conn.query_drop("SET autocommit = 1").unwrap();
+    sleep();
+
+    let row = conn
+        .query_first::<mysql::Row, _>("SELECT Cats.id FROM Cats WHERE Cats.id = 1")
+        .unwrap();
+    assert!(row.is_some());
+}
+
+#[test]
+fn auto_increment_starts_after_explicit_id() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop(
+        "CREATE TABLE Cats (id int PRIMARY KEY AUTO_INCREMENT, name VARCHAR(255), PRIMARY KEY(id))",
+    )
+    .unwrap();
+    sleep();
+
+    conn.query_drop("INSERT INTO Cats (id, name) VALUES (10, \"Bob\")")
+        .unwrap();
+    sleep();
+
+    // ids continue after an explicit one, even if none were generated before
+    let inserted = conn
+        .query_iter("INSERT INTO Cats (name) VALUES (\"Jane\"), (\"Tom\")")
+        .unwrap();
+    assert_eq!(inserted.last_insert_id(), Some(11));
+    drop(inserted);
+    sleep();
+
+    let mut rows: Vec<i32> = conn
+        .query("SELECT Cats.id FROM Cats WHERE Cats.id IN (10, 11, 12)")
+        .unwrap();
+    rows.sort_unstable();
+    assert_eq!(rows, vec![10, 11, 12]);
+}
+
+#[test]
+fn auto_increment_after_explicit_id() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop(
+        "CREATE TABLE Cats (id int PRIMARY KEY AUTO_INCREMENT, name VARCHAR(255), PRIMARY KEY(id))",
+    )
+    .unwrap();
+    sleep();
+
+    let inserted = conn
+        .query_iter("INSERT INTO Cats (name) VALUES (\"Jane\")")
+        .unwrap();
+    assert_eq!(inserted.last_insert_id(), Some(1));
+    drop(inserted);
+    conn.query_drop("INSERT INTO Cats (id, name) VALUES (10, \"Bob\")")
+        .unwrap();
+
+    // the next generated id follows the explicit one
+    let inserted = conn
+        .query_iter("INSERT INTO Cats (name) VALUES (\"Tom\")")
+        .unwrap();
+    assert_eq!(inserted.last_insert_id(), Some(11));
+}
+
+#[test]
+fn auto_increment_after_restart() {
+    let authority = Arc::new(LocalAuthority::new());
+    let _noria = start_noria(authority.clone());
+    let mut conn = mysql::Conn::new(start_adapter(authority.clone())).unwrap();
+    conn.query_drop(
+        "CREATE TABLE Cats (id int PRIMARY KEY AUTO_INCREMENT, name VARCHAR(255), PRIMARY KEY(id))",
+    )
+    .unwrap();
+    sleep();
+
+    conn.query_drop("INSERT INTO Cats (name) VALUES (\"Jane\"), (\"Tom\")")
+        .unwrap();
+    conn.query_drop("INSERT INTO Cats (id, name) VALUES (10, \"Bob\")")
+        .unwrap();
+    sleep();
+
+    // an adapter restarted with --controller only knows where the controller is, and has to
+    // find the ids that are taken in the table itself
+    let restarted = Arc::new(LocalAuthority::new());
+    let (_, leader) = authority.get_leader().unwrap();
+    restarted.become_leader(leader).unwrap();
+    let mut conn = mysql::Conn::new(start_adapter(restarted)).unwrap();
+
+    let inserted = conn
+        .query_iter("INSERT INTO Cats (name) VALUES (\"Alice\")")
+        .unwrap();
+    assert_eq!(inserted.last_insert_id(), Some(11));
+}
+
+#[test]
+fn error_codes() {
+    let (_noria, opts) = setup();
+    let mut conn = mysql::Conn::new(opts).unwrap();
+    conn.query_drop("CREATE TABLE Cats (id int PRIMARY KEY, name VARCHAR(255), PRIMARY KEY(id))")
+        .unwrap();
+    sleep();
+
+    let code = |e: mysql::Error| match e {
+        mysql::Error::MySqlError(e) => e.code,
+        e => panic!("unexpected error: {:?}", e),
+    };
+
+    // ER_NO_SUCH_TABLE
+    let e = conn
+        .query_drop("SELECT Dogs.id FROM Dogs WHERE Dogs.id = 1")
+        .unwrap_err();
+    assert_eq!(code(e), 1146);
+
+    // ER_DUP_ENTRY
+    let e = conn
+        .query_drop("INSERT INTO Cats (id, name) VALUES (1, \"Bob\"), (1, \"Jane\")")
+        .unwrap_err();
+    assert_eq!(code(e), 1062);
+
+    // ER_BAD_FIELD_ERROR
+    let e = conn
+        .query_drop("UPDATE Cats SET Cats.age = 3 WHERE Cats.id = 1")
+        .unwrap_err();
+    assert_eq!(code(e), 1054);
+
+    // ER_WRONG_VALUE_COUNT_ON_ROW
+    let e = conn
+        .query_drop("INSERT INTO Cats (id, name) VALUES (1)")
+        .unwrap_err();
+    assert_eq!(code(e), 1136);
+
+    // ER_WARN_DATA_OUT_OF_RANGE
+    let e = conn
+        .query_drop("INSERT INTO Cats (id, name) VALUES (4294967296, \"Bob\")")
+        .unwrap_err();
+    assert_eq!(code(e), 1264);
+
+    // ER_PARSE_ERROR for a placeholder outside of a prepared statement
+    let e = conn
+        .query_drop("INSERT INTO Cats (id, name) VALUES (?, \"Bob\")")
+        .unwrap_err();
+    assert_eq!(code(e), 1064);
+
+    // ER_NOT_SUPPORTED_YET for a NOT that cannot be turned into the opposite comparison
+    let e = conn
+        .query_drop("DELETE FROM Cats WHERE NOT (Cats.id IN (1, 2))")
+        .unwrap_err();
+    assert_eq!(code(e), 1235);
+
+    // ER_NOT_SUPPORTED_YET for a statement that can only be sent as a plain query
+    let e = conn.prep("DROP TABLE Cats").unwrap_err();
+    assert_eq!(code(e), 1235);
+
+    // the connection is still usable after all of those
+    conn.query_drop("INSERT INTO Cats (id, name) VALUES (1, \"Bob\")")
+        .unwrap();
+    sleep();
+    let row = conn
+        .query_first::<mysql::Row, _>("SELECT Cats.id FROM Cats WHERE Cats.id = 1")
+        .unwrap();
+    assert!(row.is_some());
+
+    // ER_DUP_ENTRY for a row that is already in the table
+    let e = conn
+        .query_drop("INSERT INTO Cats (id, name) VALUES (1, \"Jane\")")
+        .unwrap_err();
+    assert_eq!(code(e), 1062);
+}